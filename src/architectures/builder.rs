@@ -0,0 +1,881 @@
+use crate::{
+    iterators::SpanGenerator,
+    lut_bank_creators::generate_luts,
+    netcore::*,
+    settings::{Configuration, Data, Network, initialize_app_config_with},
+    utils::new_rng,
+    validation::{span_output_dims, window_output_dims},
+};
+use rand::prelude::*;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::Deserialize;
+
+// Shape-tracking network builder. Layers are added as groups of nodes, and the builder keeps the dims of every
+// group's output, so offsets, layer sizes and layer_edges are worked out instead of written by hand.
+// Nodes only read the layer right before theirs: a group reads the whole previous layer, or one of its groups
+// (reads_group), optionally reshaped. The groups of a layer are concatenated in the order they were given.
+//
+// let mut net = NetBuilder::new(input_shape(&cfg.data));
+// net.span((6, 1, 1), (1, 1, 1))?;
+// net.layer(vec![
+//     GroupSpec::span((1, 3, 2), (1, 3, 2)),
+//     GroupSpec::span((1, 6, 1), (1, 6, 1)).reshaped((9, 12, 7)),
+// ])?;
+// let ltnet = net.build(cfg);
+
+pub type Shape = (usize, usize, usize); // dim1 varies fastest, like SpanGenerator
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct GroupSpec {
+    pub op: GroupOp,
+    #[serde(default)]
+    pub from: Option<usize>, // Group of the previous layer to read, the whole previous layer when unset
+    #[serde(default)]
+    pub reshape: Option<Shape>, // Reads the input as these dims, same number of bits unless wrap is set
+    #[serde(default)]
+    pub wrap: bool, // reshape may be larger than the input, bits past its end wrap around to its start
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub enum GroupOp {
+    // One node per 6 bit window, like a convolution
+    Span {
+        len: Shape,
+        hop: Shape,
+    },
+    // Every node reads 6 bits anywhere in the input
+    Random {
+        nodes: usize,
+    },
+    // per_window nodes reading 6 random bits of each window
+    Window {
+        window: Shape,
+        hop: Shape,
+        per_window: usize,
+    },
+    // One node per non-overlapping window of up to 6 bits
+    Pool {
+        window: Shape,
+        #[serde(default)]
+        function: PoolFunction,
+    },
+    // per_position nodes every hop, each reading 6 random bits within radius of its position along every dim.
+    // distinct: no bit read twice by a node. cover: every input bit read by some node, the node whose hop cell
+    // holds it. mix_channels: read every channel (all of dim1) instead of the ones near the position.
+    Local(LocalSpec),
+    // Flattens the input and mixes it down to nodes bits, every node reading fan_in bits spread across all of it
+    Mix {
+        nodes: usize,
+        fan_in: usize,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct LocalSpec {
+    pub radius: Shape,
+    pub hop: Shape,
+    #[serde(default = "default_per_position")]
+    pub per_position: usize,
+    #[serde(default)]
+    pub distinct: bool,
+    #[serde(default)]
+    pub cover: bool,
+    #[serde(default)]
+    pub mix_channels: bool,
+}
+
+fn default_per_position() -> usize {
+    1
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct HeadsSpec {
+    // count copies of the same layers side by side, each its own sub-network with its own random wiring.
+    // Their last layers are concatenated into the output, head after head, so every head owns a contiguous
+    // block of output bits: one per class for one-vs-rest heads, or one per codeword bit.
+    // The first layer of a head reads the layer before the heads like any layer (from is a group of that
+    // layer), later ones read the head's own previous layer (from is a group of the head, and can be left out
+    // when the head's previous layer has a single group).
+    pub count: usize,
+    pub layers: Vec<Vec<GroupSpec>>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum PoolFunction {
+    // Random LUTs the trainers learn
+    #[default]
+    Learned,
+    // Fixed LUTs, frozen so the trainers leave them alone.
+    // 1 when any bit of the window is 1, max-pooling for bits
+    Or,
+    // 1 when more than half of the window is 1
+    Majority,
+    // 1 when at least k bits of the window are 1, k = 1 is Or
+    AnyOf {
+        k: usize,
+    },
+}
+
+impl PoolFunction {
+    pub fn lut(&self, window_bits: usize) -> Option<u64> {
+        // Truth table over a window of window_bits bits, repeated to fill the 6 LUT inputs like Pool wires them.
+        // Only the first window_bits address bits are distinct inputs. None for Learned.
+        let threshold = match *self {
+            PoolFunction::Learned => return None,
+            PoolFunction::Or => 1,
+            PoolFunction::Majority => window_bits / 2 + 1,
+            PoolFunction::AnyOf { k } => k,
+        };
+        let mask = (1u32 << window_bits) - 1;
+        Some(
+            (0..64u32)
+                .filter(|address| (address & mask).count_ones() as usize >= threshold)
+                .fold(0, |lut, address| lut | 1 << address),
+        )
+    }
+}
+
+impl GroupSpec {
+    pub fn new(op: GroupOp) -> Self {
+        GroupSpec {
+            op,
+            from: None,
+            reshape: None,
+            wrap: false,
+        }
+    }
+
+    pub fn span(len: Shape, hop: Shape) -> Self {
+        GroupSpec::new(GroupOp::Span { len, hop })
+    }
+
+    pub fn random(nodes: usize) -> Self {
+        GroupSpec::new(GroupOp::Random { nodes })
+    }
+
+    pub fn reads_group(mut self, group: usize) -> Self {
+        self.from = Some(group);
+        self
+    }
+
+    pub fn reshaped(mut self, shape: Shape) -> Self {
+        self.reshape = Some(shape);
+        self
+    }
+
+    pub fn wrapped(mut self) -> Self {
+        self.wrap = true;
+        self
+    }
+}
+
+impl HeadsSpec {
+    pub fn expand(&self) -> Result<Vec<Vec<GroupSpec>>, String> {
+        // The heads as plain layers, every layer holding the groups of head 0, then those of head 1, ...
+        if self.count == 0 || self.layers.is_empty() {
+            return Err("Heads need a non-zero count and at least one layer".to_string());
+        }
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for (l, groups) in self.layers.iter().enumerate() {
+            let prev_groups = if l == 0 { 0 } else { self.layers[l - 1].len() };
+            let mut layer = Vec::with_capacity(self.count * groups.len());
+            for head in 0..self.count {
+                for (g, group) in groups.iter().enumerate() {
+                    let mut group = group.clone();
+                    if l > 0 {
+                        let from = match group.from {
+                            Some(from) if from < prev_groups => from,
+                            None if prev_groups == 1 => 0,
+                            _ => {
+                                return Err(format!(
+                                    "Head layer {}, group {}: from has to name one of the {} groups of the head's previous layer",
+                                    l, g, prev_groups
+                                ));
+                            }
+                        };
+                        group.from = Some(head * prev_groups + from);
+                    }
+                    layer.push(group);
+                }
+            }
+            layers.push(layer);
+        }
+        Ok(layers)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupPlan {
+    pub start: usize, // First node of the group, counted from the start of its layer
+    pub shape: Shape,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerPlan {
+    pub groups: Vec<GroupPlan>,
+    pub size: usize,
+}
+
+impl LayerPlan {
+    fn input(shape: Shape) -> Self {
+        LayerPlan {
+            groups: vec![GroupPlan { start: 0, shape }],
+            size: volume(shape),
+        }
+    }
+
+    pub fn shape(&self) -> Shape {
+        // A single group keeps its dims, groups that agree on dim1 and dim2 stack along dim3,
+        // anything else is read as one row
+        let (dim1, dim2, _) = self.groups[0].shape;
+        if self
+            .groups
+            .iter()
+            .all(|g| (g.shape.0, g.shape.1) == (dim1, dim2))
+        {
+            (dim1, dim2, self.size / (dim1 * dim2))
+        } else {
+            (1, self.size, 1)
+        }
+    }
+}
+
+struct Source {
+    start: usize, // Bits start..start + len of the previous layer
+    len: usize,
+    shape: Shape, // How the group reads them
+}
+
+impl GroupSpec {
+    fn source(&self, prev: &LayerPlan) -> Result<Source, String> {
+        let (start, len, shape) = match self.from {
+            None => (0, prev.size, prev.shape()),
+            Some(i) => {
+                let group = prev.groups.get(i).ok_or_else(|| {
+                    format!(
+                        "from = {}, but the previous layer has {} groups",
+                        i,
+                        prev.groups.len()
+                    )
+                })?;
+                (group.start, volume(group.shape), group.shape)
+            }
+        };
+        let shape = match self.reshape {
+            None => shape,
+            Some(reshape) if volume(reshape) == len || (self.wrap && volume(reshape) > 0) => {
+                reshape
+            }
+            Some(reshape) => {
+                return Err(format!(
+                    "reshape {:?} holds {} bits but the input has {}, set wrap = true to read bits more than once",
+                    reshape,
+                    volume(reshape),
+                    len
+                ));
+            }
+        };
+        Ok(Source { start, len, shape })
+    }
+}
+
+impl LocalSpec {
+    pub fn output_shape(&self, input: Shape) -> Result<Shape, String> {
+        let LocalSpec {
+            per_position,
+            distinct,
+            cover,
+            ..
+        } = *self;
+        let (radius, hop) = self.reach(input);
+        if per_position == 0 {
+            return Err("Local needs at least one node per position".to_string());
+        }
+        if hop.0 == 0 || hop.1 == 0 || hop.2 == 0 {
+            return Err(format!("Local hop {:?} has to be at least 1", hop));
+        }
+        // The smallest neighbourhood is at a corner, where radius is cut by the edges
+        let corner = (
+            (radius.0 + 1).min(input.0),
+            (radius.1 + 1).min(input.1),
+            (radius.2 + 1).min(input.2),
+        );
+        if distinct && volume(corner) < 6 {
+            return Err(format!(
+                "Local radius {:?} leaves {} bits around a corner of {:?}, distinct needs 6",
+                radius,
+                volume(corner),
+                input
+            ));
+        }
+        if cover && (hop.0 > radius.0 + 1 || hop.1 > radius.1 + 1 || hop.2 > radius.2 + 1) {
+            return Err(format!(
+                "Local hop {:?} skips bits outside radius {:?}, cover needs hop <= radius + 1",
+                hop, radius
+            ));
+        }
+        if cover && volume(hop) > 6 * per_position {
+            return Err(format!(
+                "Local hop cell {:?} has {} bits, more than the {} inputs of its nodes, so cover can't read them all",
+                hop,
+                volume(hop),
+                6 * per_position
+            ));
+        }
+        let positions = |dim: usize, hop: usize| dim.div_ceil(hop);
+        Ok((
+            per_position * positions(input.0, hop.0),
+            positions(input.1, hop.1),
+            positions(input.2, hop.2),
+        ))
+    }
+
+    fn reach(&self, input: Shape) -> (Shape, Shape) {
+        // radius and hop, with mix_channels reaching over all of dim1 from a single position
+        if self.mix_channels {
+            (
+                (input.0, self.radius.1, self.radius.2),
+                (input.0, self.hop.1, self.hop.2),
+            )
+        } else {
+            (self.radius, self.hop)
+        }
+    }
+
+    fn wire(&self, shape: Shape, rng: &mut Xoshiro256PlusPlus) -> Vec<[usize; 6]> {
+        let LocalSpec {
+            per_position,
+            distinct,
+            cover,
+            ..
+        } = *self;
+        let (radius, hop) = self.reach(shape);
+        let range = |center: usize, r: usize, dim: usize| {
+            center.saturating_sub(r)..(center + r + 1).min(dim)
+        };
+        let mut wiring = Vec::new();
+        for z in (0..shape.2).step_by(hop.2) {
+            for y in (0..shape.1).step_by(hop.1) {
+                for x in (0..shape.0).step_by(hop.0) {
+                    let mut near = Vec::new();
+                    for k in range(z, radius.2, shape.2) {
+                        for j in range(y, radius.1, shape.1) {
+                            for i in range(x, radius.0, shape.0) {
+                                near.push(shape.0 * shape.1 * k + shape.0 * j + i);
+                            }
+                        }
+                    }
+                    // The bits of this position's hop cell, dealt out to its nodes when covering
+                    let mut owned = Vec::new();
+                    if cover {
+                        for k in z..(z + hop.2).min(shape.2) {
+                            for j in y..(y + hop.1).min(shape.1) {
+                                for i in x..(x + hop.0).min(shape.0) {
+                                    owned.push(shape.0 * shape.1 * k + shape.0 * j + i);
+                                }
+                            }
+                        }
+                    }
+                    for n in 0..per_position {
+                        let mut inputs: Vec<usize> = owned
+                            .iter()
+                            .skip(n)
+                            .step_by(per_position)
+                            .copied()
+                            .collect();
+                        if distinct {
+                            let rest: Vec<usize> = near
+                                .iter()
+                                .filter(|b| !inputs.contains(b))
+                                .copied()
+                                .collect();
+                            inputs.extend(rest.choose_multiple(rng, 6 - inputs.len()));
+                        } else {
+                            while inputs.len() < 6 {
+                                inputs.push(near[rng.random_range(0..near.len())]);
+                            }
+                        }
+                        wiring.push(std::array::from_fn(|i| inputs[i]));
+                    }
+                }
+            }
+        }
+        wiring
+    }
+}
+
+impl GroupOp {
+    pub fn output_shape(&self, input: Shape) -> Result<Shape, String> {
+        match *self {
+            GroupOp::Span { len, hop } => span_output_dims(input, len, hop),
+            GroupOp::Random { nodes: 0 } => Err("Random needs at least one node".to_string()),
+            GroupOp::Random { nodes } => Ok((1, nodes, 1)),
+            GroupOp::Window { per_window: 0, .. } => {
+                Err("Window needs at least one node per window".to_string())
+            }
+            GroupOp::Window {
+                window,
+                hop,
+                per_window,
+            } => {
+                let (dim1, dim2, dim3) = window_output_dims(input, window, hop)?;
+                Ok((per_window * dim1, dim2, dim3))
+            }
+            GroupOp::Pool { window, .. } if volume(window) > 6 => Err(format!(
+                "pool window {:?} has more than the 6 bits a LUT reads",
+                window
+            )),
+            GroupOp::Pool {
+                window,
+                function: PoolFunction::AnyOf { k },
+            } if k == 0 || k > volume(window) => Err(format!(
+                "AnyOf k = {} needs to be between 1 and the {} bits of the pool window",
+                k,
+                volume(window)
+            )),
+            GroupOp::Pool { window, .. } => window_output_dims(input, window, window),
+            GroupOp::Local(local) => local.output_shape(input),
+            GroupOp::Mix { nodes: 0, .. } => Err("Mix needs at least one node".to_string()),
+            GroupOp::Mix { fan_in, .. } if fan_in == 0 || fan_in > 6 => Err(format!(
+                "Mix fan_in = {} needs to be between 1 and the 6 bits a LUT reads",
+                fan_in
+            )),
+            GroupOp::Mix { nodes, .. } => Ok((1, nodes, 1)),
+        }
+    }
+
+    fn wire(&self, shape: Shape, rng: &mut Xoshiro256PlusPlus) -> Vec<[usize; 6]> {
+        // Inputs of every node, as positions in shape
+        match *self {
+            GroupOp::Span { len, hop } => SpanGenerator::new(
+                0, shape.0, shape.1, shape.2, len.0, len.1, len.2, hop.0, hop.1, hop.2,
+            )
+            .collect(),
+            GroupOp::Random { nodes } => (0..nodes)
+                .map(|_| std::array::from_fn(|_| rng.random_range(0..volume(shape))))
+                .collect(),
+            GroupOp::Window {
+                window,
+                hop,
+                per_window,
+            } => windows(shape, window, hop)
+                .iter()
+                .flat_map(|bits| {
+                    (0..per_window)
+                        .map(|_| std::array::from_fn(|_| bits[rng.random_range(0..bits.len())]))
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+            // Windows smaller than 6 bits repeat their bits
+            GroupOp::Pool { window, .. } => windows(shape, window, window)
+                .iter()
+                .map(|bits| std::array::from_fn(|i| bits[i % bits.len()]))
+                .collect(),
+            GroupOp::Local(local) => local.wire(shape, rng),
+            // Input t of node j reads slot t * nodes + j of nodes * fan_in slots spread evenly over the input,
+            // so each node sees the whole map and every bit is read once there are at least as many slots as bits.
+            // Inputs past fan_in repeat the first ones.
+            GroupOp::Mix { nodes, fan_in } => (0..nodes)
+                .map(|j| {
+                    std::array::from_fn(|i| {
+                        (i % fan_in * nodes + j) * volume(shape) / (nodes * fan_in)
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    fn fixed_lut(&self) -> Option<u64> {
+        // The LUT every node of the group gets when it isn't trained
+        match *self {
+            GroupOp::Pool { window, function } => function.lut(volume(window)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NetBuilder {
+    input: LayerPlan,
+    layers: Vec<(Vec<GroupSpec>, LayerPlan)>,
+}
+
+impl NetBuilder {
+    pub fn new(input: Shape) -> Self {
+        NetBuilder {
+            input: LayerPlan::input(input),
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn with_layers(input: Shape, layers: &[Vec<GroupSpec>]) -> Result<Self, String> {
+        let mut net = NetBuilder::new(input);
+        for groups in layers {
+            net.layer(groups.clone())?;
+        }
+        if net.layers.is_empty() {
+            return Err("the network has no layers".to_string());
+        }
+        Ok(net)
+    }
+
+    pub fn layer(&mut self, groups: Vec<GroupSpec>) -> Result<&LayerPlan, String> {
+        // Adds a layer of concatenated groups. Errors name the layer and group that doesn't fit, and leave
+        // the builder as it was.
+        let l = self.layers.len();
+        if groups.is_empty() {
+            return Err(format!("Layer {} has no groups", l));
+        }
+        let prev = self.last();
+        let mut layer = LayerPlan {
+            groups: Vec::with_capacity(groups.len()),
+            size: 0,
+        };
+        for (g, group) in groups.iter().enumerate() {
+            let shape = group
+                .source(prev)
+                .and_then(|source| group.op.output_shape(source.shape))
+                .map_err(|e| format!("Layer {}, group {}: {}", l, g, e))?;
+            layer.groups.push(GroupPlan {
+                start: layer.size,
+                shape,
+            });
+            layer.size += volume(shape);
+        }
+        self.layers.push((groups, layer));
+        Ok(&self.layers[l].1)
+    }
+
+    pub fn heads(&mut self, heads: &HeadsSpec) -> Result<Shape, String> {
+        // Adds the layers of the heads, returns the dims of the concatenated output
+        for groups in heads.expand()? {
+            self.layer(groups)?;
+        }
+        Ok(self.shape())
+    }
+
+    pub fn span(&mut self, len: Shape, hop: Shape) -> Result<Shape, String> {
+        // A layer with a single span group over the whole previous layer, returns its dims
+        Ok(self.layer(vec![GroupSpec::span(len, hop)])?.shape())
+    }
+
+    pub fn last(&self) -> &LayerPlan {
+        // The last layer added, or the input
+        self.layers.last().map_or(&self.input, |(_, plan)| plan)
+    }
+
+    pub fn shape(&self) -> Shape {
+        self.last().shape()
+    }
+
+    pub fn layer_sizes(&self) -> Vec<usize> {
+        self.layers.iter().map(|(_, plan)| plan.size).collect()
+    }
+
+    pub fn layer_edges(&self) -> Vec<usize> {
+        // Same layout as DerivedValues::layer_edges: 0, then where every layer ends
+        let mut edges = vec![0];
+        for (_, plan) in &self.layers {
+            edges.push(edges.last().unwrap() + plan.size);
+        }
+        edges
+    }
+
+    pub fn build_nodes(&self, lut_bank: &Option<Vec<u64>>) -> Vec<Node> {
+        self.build_nodes_and_frozen(lut_bank).0
+    }
+
+    pub fn build_nodes_and_frozen(&self, lut_bank: &Option<Vec<u64>>) -> (Vec<Node>, Vec<usize>) {
+        // The nodes, and the nodes with a fixed function the trainers leave alone
+        let mut frozen = Vec::new();
+        let mut nodes: Vec<Node> = Vec::with_capacity(self.layer_sizes().iter().sum());
+        let mut rng_luts = new_rng();
+        let mut rng = new_rng();
+        let mut base = 0; // Where the previous layer starts, input bits first
+        let mut prev = &self.input;
+        for (groups, plan) in &self.layers {
+            for group in groups {
+                // Checked when the layer was added
+                let source = group.source(prev).unwrap();
+                let fixed_lut = group.op.fixed_lut();
+                let wired = group.op.wire(source.shape, &mut rng);
+                if fixed_lut.is_some() {
+                    frozen.extend(nodes.len()..nodes.len() + wired.len());
+                }
+                nodes.extend(wired.into_iter().map(|indices| Node {
+                    lut: if let Some(lut) = fixed_lut {
+                        lut
+                    } else if let Some(lut_bank) = lut_bank {
+                        lut_bank[rng_luts.random_range(0..lut_bank.len())]
+                    } else {
+                        rng_luts.next_u64()
+                    },
+                    indices: indices.map(|i| base + source.start + i % source.len),
+                }));
+            }
+            base += prev.size;
+            prev = plan;
+        }
+        (nodes, frozen)
+    }
+
+    pub fn build(&self, cfg: &Configuration) -> LUTNet {
+        let lut_bank = if cfg.network.lut_bank_size > 0 {
+            generate_luts(cfg)
+        } else {
+            None
+        };
+        let (nodes, frozen) = self.build_nodes_and_frozen(&lut_bank);
+        LUTNet::new(
+            nodes,
+            self.input.size,
+            self.layer_edges(),
+            lut_bank,
+            cfg.network.output_embedding.clone(),
+        )
+        .with_frozen(frozen)
+    }
+}
+
+pub fn input_shape(data: &Data) -> Shape {
+    (data.dim1, data.dim2, data.dim3)
+}
+
+pub fn aligned_cfg(
+    builder: impl FnOnce(Shape) -> Result<NetBuilder, String>,
+    network: impl FnOnce(Vec<usize>) -> Network,
+) -> Result<Configuration, String> {
+    // Main settings with the network built for their input dims. network gets the layer sizes the builder
+    // produced. Fails when the layers don't fit the input.
+    let mut error = None;
+    let cfg = initialize_app_config_with(|data| match builder(input_shape(data)) {
+        Ok(net) => Some(network(net.layer_sizes())),
+        Err(e) => {
+            error = Some(e);
+            None
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(cfg),
+    }
+}
+
+pub(crate) fn volume(shape: Shape) -> usize {
+    shape.0 * shape.1 * shape.2
+}
+
+fn windows(shape: Shape, window: Shape, hop: Shape) -> Vec<Vec<usize>> {
+    // Positions of the bits inside every window, windows and bits in SpanGenerator order (dim1 fastest).
+    // The last window along a dim is moved back to end at the edge, as SpanGenerator does.
+    let starts = |dim: usize, len: usize, hop: usize| -> Vec<usize> {
+        (0..(dim - len + 2 * hop - 1) / hop)
+            .map(|i| (i * hop).min(dim - len))
+            .collect()
+    };
+    let mut out = Vec::new();
+    for &z in &starts(shape.2, window.2, hop.2) {
+        for &y in &starts(shape.1, window.1, hop.1) {
+            for &x in &starts(shape.0, window.0, hop.0) {
+                let mut bits = Vec::with_capacity(volume(window));
+                for k in z..z + window.2 {
+                    for j in y..y + window.1 {
+                        for i in x..x + window.0 {
+                            bits.push(shape.0 * shape.1 * k + shape.0 * j + i);
+                        }
+                    }
+                }
+                out.push(bits);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_tracks_shapes_and_concatenates_groups() {
+        let mut net = NetBuilder::new((4, 4, 1));
+        // 4 pools and 4 windows of 3 nodes, (2, 2, 1) and (3, 4, 1) don't stack so the layer is one row
+        let layer = net
+            .layer(vec![
+                GroupSpec::new(GroupOp::Pool {
+                    window: (2, 2, 1),
+                    function: PoolFunction::Learned,
+                }),
+                GroupSpec::new(GroupOp::Window {
+                    window: (4, 1, 1),
+                    hop: (4, 1, 1),
+                    per_window: 3,
+                }),
+            ])
+            .unwrap();
+        assert_eq!(layer.groups[1].start, 4);
+        assert_eq!(layer.shape(), (1, 16, 1));
+        net.layer(vec![GroupSpec::random(5).reads_group(1)])
+            .unwrap();
+        assert_eq!(net.layer_sizes(), vec![16, 5]);
+        assert_eq!(net.layer_edges(), vec![0, 16, 21]);
+
+        let nodes = net.build_nodes(&None);
+        assert_eq!(nodes[0].indices, [0, 1, 4, 5, 0, 1]);
+        assert!(
+            nodes[4..16]
+                .iter()
+                .all(|n| n.indices.iter().all(|&i| i < 16))
+        );
+        // The random layer only reads the window group, bits 16 + 4..16 + 16
+        assert!(
+            nodes[16..]
+                .iter()
+                .all(|n| n.indices.iter().all(|&i| (20..32).contains(&i)))
+        );
+
+        // A failed layer names itself and leaves the builder as it was
+        let error = net
+            .layer(vec![GroupSpec::random(1).reads_group(1)])
+            .unwrap_err();
+        assert!(error.starts_with("Layer 2, group 0"), "{}", error);
+        assert!(
+            net.layer(vec![GroupSpec::random(2).reshaped((1, 6, 1))])
+                .is_err()
+        );
+        assert_eq!(net.layer_sizes(), vec![16, 5]);
+        assert_eq!(
+            net.span((6, 1, 1), (3, 1, 1)),
+            Err("Layer 2, group 0: span (6, 1, 1) doesn't fit in dims (1, 5, 1)".to_string())
+        );
+        let wrapped = GroupSpec::span((6, 1, 1), (3, 1, 1))
+            .reshaped((9, 1, 1))
+            .wrapped();
+        assert_eq!(net.layer(vec![wrapped]).unwrap().size, 2);
+        assert_eq!(net.build_nodes(&None)[22].indices, [35, 36, 32, 33, 34, 35]);
+        assert!(NetBuilder::with_layers((4, 4, 1), &[]).is_err());
+    }
+
+    #[test]
+    fn heads_read_only_their_own_layers() {
+        // Two heads of two groups, then one group reading the second group of its head
+        let heads = HeadsSpec {
+            count: 2,
+            layers: vec![
+                vec![GroupSpec::random(3), GroupSpec::random(6)],
+                vec![GroupSpec::random(1).reads_group(1)],
+            ],
+        };
+        let layers = heads.expand().unwrap();
+        assert_eq!(layers[0].len(), 4);
+        assert_eq!(layers[1][1].from, Some(3));
+        let mut net = NetBuilder::new((6, 1, 1));
+        assert_eq!(net.heads(&heads), Ok((1, 1, 2)));
+        let nodes = net.build_nodes(&None);
+        // The last node is head 1, reading its group of 6 at addresses 18..24
+        assert!(nodes[19].indices.iter().all(|&i| (18..24).contains(&i)));
+
+        let ambiguous = HeadsSpec {
+            layers: vec![heads.layers[0].clone(), vec![GroupSpec::random(1)]],
+            ..heads
+        };
+        assert!(ambiguous.expand().is_err());
+    }
+
+    #[test]
+    fn pools_are_fixed_and_mix_reads_the_whole_map() {
+        // Over 2 bits, address bits 0 and 1 are the inputs and the other 4 repeat them
+        assert_eq!(PoolFunction::Or.lut(2).unwrap() & 0b1111, 0b1110);
+        assert_eq!(PoolFunction::Majority.lut(2).unwrap() & 0b1111, 0b1000);
+        assert_eq!(
+            PoolFunction::AnyOf { k: 2 }.lut(3),
+            PoolFunction::Majority.lut(3)
+        );
+        assert_eq!(PoolFunction::Or.lut(6), Some(u64::MAX - 1));
+        assert_eq!(PoolFunction::Learned.lut(4), None);
+
+        let pool = |function| {
+            GroupSpec::new(GroupOp::Pool {
+                window: (2, 1, 1),
+                function,
+            })
+        };
+        let mut net = NetBuilder::new((4, 3, 1));
+        assert!(net.layer(vec![pool(PoolFunction::AnyOf { k: 3 })]).is_err());
+        net.layer(vec![pool(PoolFunction::Learned), pool(PoolFunction::Or)])
+            .unwrap();
+        assert!(
+            net.layer(vec![GroupSpec::new(GroupOp::Mix {
+                nodes: 2,
+                fan_in: 7
+            })])
+            .is_err()
+        );
+        net.layer(vec![GroupSpec::new(GroupOp::Mix {
+            nodes: 3,
+            fan_in: 4,
+        })])
+        .unwrap();
+        assert_eq!(net.layer_sizes(), vec![12, 3]);
+
+        let (nodes, frozen) = net.build_nodes_and_frozen(&None);
+        assert_eq!(frozen, (6..12).collect::<Vec<_>>());
+        assert_eq!(nodes[6].lut, PoolFunction::Or.lut(2).unwrap());
+        assert_eq!(nodes[6].indices, [0, 1, 0, 1, 0, 1]);
+        // 12 slots over the 12 pooled bits: node j reads j, j + 3, j + 6, j + 9, then repeats
+        assert_eq!(nodes[13].indices, [13, 16, 19, 22, 13, 16]);
+        let mut read: Vec<usize> = nodes[12..].iter().flat_map(|n| n.indices).collect();
+        read.sort();
+        read.dedup();
+        assert_eq!(read, (12..24).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn local_nodes_read_distinct_bits_near_their_position() {
+        let local = |radius, hop, mix_channels| {
+            GroupSpec::new(GroupOp::Local(LocalSpec {
+                radius,
+                hop,
+                per_position: 1,
+                distinct: true,
+                cover: true,
+                mix_channels,
+            }))
+        };
+        let mut net = NetBuilder::new((1, 6, 6));
+        // 4 bits around a corner, and bits 2 away from any position
+        assert!(net.layer(vec![local((0, 1, 1), (1, 2, 2), false)]).is_err());
+        assert!(net.layer(vec![local((0, 2, 2), (1, 4, 4), false)]).is_err());
+        assert_eq!(
+            net.layer(vec![local((0, 2, 2), (1, 2, 2), false)])
+                .unwrap()
+                .shape(),
+            (1, 3, 3)
+        );
+        let nodes = net.build_nodes(&None);
+        for (n, node) in nodes.iter().enumerate() {
+            let (y, z) = (n % 3 * 2, n / 3 * 2);
+            let mut inputs = node.indices.to_vec();
+            assert!(inputs.iter().all(|&b| b % 6 <= y + 2 && y <= b % 6 + 2));
+            assert!(inputs.iter().all(|&b| b / 6 <= z + 2 && z <= b / 6 + 2));
+            inputs.sort();
+            inputs.dedup();
+            assert_eq!(inputs.len(), 6);
+        }
+        let mut read: Vec<usize> = nodes.iter().flat_map(|n| n.indices).collect();
+        read.sort();
+        read.dedup();
+        assert_eq!(read.len(), 36);
+
+        // Mixing channels reads all 3 of them from a single position along dim1
+        let mut net = NetBuilder::new((3, 4, 4));
+        net.layer(vec![local((0, 1, 1), (0, 1, 1), true)]).unwrap();
+        assert_eq!(net.shape(), (1, 4, 4));
+        assert!(
+            net.build_nodes(&None)[5]
+                .indices
+                .iter()
+                .all(|&b| (b / 3 % 4).abs_diff(1) <= 1 && (b / 12).abs_diff(1) <= 1)
+        );
+    }
+}
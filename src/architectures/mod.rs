@@ -1,82 +1,135 @@
-pub mod cnn_iv0 {
-    pub mod netimpl;
-    pub mod settings;
-}
-pub mod cnn_iv1 {
-    pub mod netimpl;
-    pub mod settings;
-}
-pub mod cnn_iv2 {
+pub mod builder;
+pub mod registry;
+pub mod spec {
     pub mod netimpl;
     pub mod settings;
 }
 
-use crate::{lut_bank_creators::*, netcore::LUTNet, settings::Configuration};
-use std::str::FromStr;
+use crate::{
+    netcore::LUTNet,
+    settings::{Configuration, initialize_app_config_with_network},
+    validation::ValidationReport,
+};
+use builder::{GroupSpec, NetBuilder, Shape, input_shape};
+use registry::{Registration, find_architecture};
+use spec::settings::SpecSettings;
+use std::{error::Error, fs, path::Path, str::FromStr};
 
 pub trait LUTNetBuilder {
-    fn build_net(&self) -> (&'static Configuration, LUTNet);
+    // The layers over input dims input, Err when they don't fit it
+    fn net_builder(&self, input: Shape) -> Result<NetBuilder, String>;
+    // The configuration build_net would use, Err when the settings can't make one (validate then says why)
+    fn aligned_cfg(&self) -> Result<Configuration, String> {
+        Ok(initialize_app_config_with_network(None))
+    }
+    fn build_net(&self) -> (Configuration, LUTNet) {
+        let cfg = self
+            .aligned_cfg()
+            .unwrap_or_else(|e| panic!("Invalid architecture settings: {}", e));
+        let net = self
+            .net_builder(input_shape(&cfg.data))
+            .unwrap_or_else(|e| panic!("Invalid architecture settings: {}", e));
+        let ltnet = net.build(&cfg);
+        (cfg, ltnet)
+    }
+    // cfg is the configuration build_net would use. Includes the checks of Configuration::validate.
+    fn validate(&self, cfg: &Configuration) -> ValidationReport {
+        let mut report = cfg.validate();
+        match self.net_builder(input_shape(&cfg.data)) {
+            Ok(net) => report.check_layer_sizes(cfg, Some(net.layer_sizes())),
+            Err(e) => report.error(e),
+        }
+        report
+    }
 }
 
-// In src/architectures/mod.rs
-
-// ... existing code ...
-
 pub enum Architecture {
-    CnnIv0,
-    CnnIv1,
-    CnnIv2,
-    Random,
+    Registered(Registration),              // Found by name in the registry
+    Spec { name: String, source: String }, // A spec file, name is its file stem
 }
 
 impl FromStr for Architecture {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "cnn_iv0" => Ok(Architecture::CnnIv0),
-            "cnn_iv1" => Ok(Architecture::CnnIv1),
-            "cnn_iv2" => Ok(Architecture::CnnIv2),
-            "random" => Ok(Architecture::Random),
-            _ => Err(format!("'{}' is not a valid architecture.", s)),
+        if s.ends_with(".toml") {
+            // Relative to the working directory, or else to the crate directory like the files in architectures/
+            let path = Path::new(s);
+            let path = if path.exists() {
+                path.to_path_buf()
+            } else {
+                Path::new(crate::HOME_DIR).join(path)
+            };
+            let source = fs::read_to_string(&path)
+                .map_err(|e| format!("Can't read architecture spec {}: {}", path.display(), e))?;
+            let name = path.file_stem().unwrap().to_string_lossy().to_lowercase();
+            return Ok(Architecture::Spec { name, source });
         }
+        find_architecture(s).map(Architecture::Registered).ok_or_else(|| {
+            format!(
+                "'{}' is not a valid architecture, --list-architectures shows the registered ones.",
+                s
+            )
+        })
     }
 }
 
 impl Architecture {
-    pub fn build(&self) -> (&'static Configuration, LUTNet) {
+    pub fn name(&self) -> &str {
+        // Key of the architecture's settings under architectures.<name>
         match self {
-            Architecture::CnnIv0 => {
-                let arch_settings = crate::architectures::cnn_iv0::settings::Ci0Settings::new()
-                    .expect("Failed to load cnn_iv0/Settings.toml");
-                arch_settings.build_net()
-            }
-            Architecture::CnnIv1 => {
-                let arch_settings = crate::architectures::cnn_iv1::settings::Ci1Settings::new()
-                    .expect("Failed to load cnn_iv1/Settings.toml");
-                arch_settings.build_net()
-            }
-            Architecture::CnnIv2 => {
-                let arch_settings = crate::architectures::cnn_iv2::settings::Ci2Settings::new()
-                    .expect("Failed to load cnn_iv2/Settings.toml");
-                arch_settings.build_net()
-            }
-            Architecture::Random => {
-                let cfg = crate::settings::get_cfg(None);
+            Architecture::Registered(registration) => &registration.name,
+            Architecture::Spec { name, .. } => name,
+        }
+    }
+
+    pub fn default_settings(&self) -> Option<&str> {
+        // The architecture's own Settings.toml, None when it only uses the main settings
+        match self {
+            Architecture::Registered(registration) => registration.default_settings,
+            Architecture::Spec { source, .. } => Some(source),
+        }
+    }
 
-                let lut_bank = if cfg.network.lut_bank_size > 0 {
-                    generate_luts(cfg)
-                } else {
-                    None
-                };
-                let ltnet = LUTNet::init_random(
-                    cfg.derived.img_bitcount,
-                    &cfg.derived.layer_edges,
-                    lut_bank,
-                    &cfg.network.output_embedding,
-                );
-                (cfg, ltnet)
+    pub fn builder(&self) -> Result<Box<dyn LUTNetBuilder>, Box<dyn Error>> {
+        // Loads the architecture's settings, layered like the main settings
+        match self {
+            Architecture::Registered(registration) => {
+                (registration.load)(&registration.name, registration.default_settings)
             }
+            Architecture::Spec { name, source } => Ok(Box::new(SpecSettings::new(name, source)?)),
         }
     }
+
+    pub fn validate(&self) -> Result<ValidationReport, Box<dyn Error>> {
+        // Same settings as build, but nothing is created and the global configuration is left alone.
+        // Layers that don't fit the input have no layer sizes, the main settings stand in for the report.
+        let builder = self.builder()?;
+        let cfg = builder
+            .aligned_cfg()
+            .unwrap_or_else(|_| initialize_app_config_with_network(None));
+        Ok(builder.validate(&cfg))
+    }
+
+    pub fn build(&self) -> (Configuration, LUTNet) {
+        self.builder()
+            .unwrap_or_else(|e| panic!("Failed to load the settings of {}: {}", self.name(), e))
+            .build_net()
+    }
+}
+
+// Every node reads 6 random bits of the layer before it, layers sized by the main settings
+pub struct RandomNet;
+
+impl LUTNetBuilder for RandomNet {
+    fn net_builder(&self, input: Shape) -> Result<NetBuilder, String> {
+        let cfg = initialize_app_config_with_network(None);
+        let layers: Vec<Vec<GroupSpec>> = cfg
+            .network
+            .layer_sizes
+            .iter()
+            .map(|&nodes| vec![GroupSpec::random(nodes)])
+            .collect();
+        NetBuilder::with_layers(input, &layers)
+    }
 }
@@ -0,0 +1,214 @@
+use crate::architectures::{LUTNetBuilder, RandomNet, spec::settings::SpecSettings};
+use crate::layered_config::load_architecture_settings;
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::sync::{OnceLock, RwLock};
+
+// Architectures by name. The built-in ones are registered on first use, and code outside the crate adds its own
+// before parsing any architecture name, so a private architecture needs no fork:
+//
+// register_settings::<MyNetSettings>("my_net", "What it is", include_str!("my_net/Settings.toml"))?;
+// let arch = Architecture::from_str("my_net")?;
+//
+// MyNetSettings implements LUTNetBuilder and Deserialize. It is read from the [network] table of its Settings.toml,
+// layered with architectures.my_net like the built-in architectures.
+
+pub type LoadBuilder = fn(
+    name: &str,
+    default_settings: Option<&'static str>,
+) -> Result<Box<dyn LUTNetBuilder>, Box<dyn Error>>;
+
+#[derive(Clone)]
+pub struct Registration {
+    pub name: String,    // Lowercase, architecture names are matched case-insensitively
+    pub summary: String, // One line for --list-architectures
+    // The architecture's own Settings.toml, None when it only uses the main settings
+    pub default_settings: Option<&'static str>,
+    pub load: LoadBuilder,
+}
+
+fn registry() -> &'static RwLock<Vec<Registration>> {
+    static REGISTRY: OnceLock<RwLock<Vec<Registration>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        // The cnn_iv* architectures are the spec files of the same name, compiled in so installed binaries
+        // don't need the source tree
+        let builtin = vec![
+            settings_registration::<SpecSettings>(
+                "cnn_iv0",
+                "Spans over the image all the way down to 8 output bits",
+                include_str!("../../architectures/cnn_iv0.toml"),
+            ),
+            settings_registration::<SpecSettings>(
+                "cnn_iv1",
+                "Two span layers, then fully random layers",
+                include_str!("../../architectures/cnn_iv1.toml"),
+            ),
+            settings_registration::<SpecSettings>(
+                "cnn_iv2",
+                "Span groups reading the previous layer in several shapes",
+                include_str!("../../architectures/cnn_iv2.toml"),
+            ),
+            Registration {
+                name: "random".to_string(),
+                summary: "Every node reads 6 random bits of the previous layer, sized by [network] layer_sizes"
+                    .to_string(),
+                default_settings: None,
+                load: |_, _| Ok(Box::new(RandomNet)),
+            },
+        ];
+        RwLock::new(builtin)
+    })
+}
+
+pub fn register_architecture(registration: Registration) -> Result<(), String> {
+    let mut registry = registry().write().unwrap();
+    let name = registration.name.to_lowercase();
+    if name.ends_with(".toml") {
+        return Err(format!("'{}' would be read as a spec file", name));
+    }
+    if registry.iter().any(|r| r.name == name) {
+        return Err(format!(
+            "An architecture named '{}' is already registered",
+            name
+        ));
+    }
+    registry.push(Registration {
+        name,
+        ..registration
+    });
+    Ok(())
+}
+
+pub fn register_settings<T: LUTNetBuilder + DeserializeOwned + 'static>(
+    name: &str,
+    summary: &str,
+    default_settings: &'static str,
+) -> Result<(), String> {
+    // An architecture whose LUTNetBuilder is its own settings type, like SpecSettings for the built-in cnn_iv*
+    register_architecture(settings_registration::<T>(name, summary, default_settings))
+}
+
+pub fn find_architecture(name: &str) -> Option<Registration> {
+    let name = name.to_lowercase();
+    registry()
+        .read()
+        .unwrap()
+        .iter()
+        .find(|r| r.name == name)
+        .cloned()
+}
+
+pub fn registered_architectures() -> Vec<Registration> {
+    let mut all = registry().read().unwrap().clone();
+    all.sort_by(|a, b| a.name.cmp(&b.name));
+    all
+}
+
+pub fn architecture_list() -> String {
+    // What --list-architectures prints
+    let all = registered_architectures();
+    let width = all.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    let mut out = String::from("Registered architectures:\n");
+    for r in &all {
+        out.push_str(&format!(
+            "  {:width$}  {}\n",
+            r.name,
+            r.summary,
+            width = width
+        ));
+    }
+    out.push_str("Spec files are passed as a path ending in .toml, see architectures/\n");
+    out
+}
+
+fn settings_registration<T: LUTNetBuilder + DeserializeOwned + 'static>(
+    name: &str,
+    summary: &str,
+    default_settings: &'static str,
+) -> Registration {
+    Registration {
+        name: name.to_lowercase(),
+        summary: summary.to_string(),
+        default_settings: Some(default_settings),
+        load: load_settings::<T>,
+    }
+}
+
+fn load_settings<T: LUTNetBuilder + DeserializeOwned + 'static>(
+    name: &str,
+    default_settings: Option<&'static str>,
+) -> Result<Box<dyn LUTNetBuilder>, Box<dyn Error>> {
+    let defaults = default_settings.ok_or_else(|| format!("{} has no default settings", name))?;
+    Ok(Box::new(load_architecture_settings::<T>(name, defaults)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::architectures::{
+        Architecture,
+        builder::{GroupSpec, NetBuilder, Shape, aligned_cfg},
+    };
+    use crate::settings::{Configuration, Network};
+    use serde::Deserialize;
+    use std::str::FromStr;
+
+    #[derive(Deserialize)]
+    struct FunnelSettings {
+        widths: Vec<usize>,
+    }
+
+    impl LUTNetBuilder for FunnelSettings {
+        fn net_builder(&self, input: Shape) -> Result<NetBuilder, String> {
+            let layers: Vec<Vec<GroupSpec>> = self
+                .widths
+                .iter()
+                .map(|&width| vec![GroupSpec::random(width)])
+                .collect();
+            NetBuilder::with_layers(input, &layers)
+        }
+
+        fn aligned_cfg(&self) -> Result<Configuration, String> {
+            aligned_cfg(
+                |input| self.net_builder(input),
+                |layer_sizes| Network {
+                    output_embedding: Vec::new(),
+                    layer_sizes,
+                    lut_bank_size: 0,
+                    output_code: None,
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn external_architectures_can_be_registered() {
+        register_settings::<FunnelSettings>(
+            "Test_Funnel",
+            "Random layers of the given widths",
+            "[network]\nwidths = [64, 0]",
+        )
+        .unwrap();
+        assert!(register_settings::<FunnelSettings>("test_funnel", "Again", "").is_err());
+        assert!(architecture_list().contains("test_funnel  Random layers"));
+        assert!(architecture_list().contains("cnn_iv2"));
+
+        let arch = Architecture::from_str("TEST_FUNNEL").unwrap();
+        assert_eq!(arch.name(), "test_funnel");
+        assert_eq!(arch.default_settings(), Some("[network]\nwidths = [64, 0]"));
+        let report = arch.validate().unwrap();
+        assert_eq!(report.errors.len(), 1, "{}", report);
+        assert!(report.errors[0].contains("Random needs at least one node"));
+        assert!(Architecture::from_str("no_such_net").is_err());
+
+        let (cfg, ltnet) = FunnelSettings {
+            widths: vec![64, 8],
+        }
+        .build_net();
+        assert_eq!(cfg.network.layer_sizes, vec![64, 8]);
+        assert_eq!(ltnet.nodes.len(), 72);
+        assert_eq!(ltnet.layer_edges, cfg.derived.layer_edges);
+        let builtin = find_architecture("random").unwrap();
+        assert!((builtin.load)("random", None).is_ok());
+    }
+}
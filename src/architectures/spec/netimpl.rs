@@ -0,0 +1,219 @@
+use crate::{
+    architectures::{
+        LUTNetBuilder,
+        builder::{NetBuilder, Shape},
+        spec::settings::*,
+    },
+    settings::Configuration,
+};
+
+impl LUTNetBuilder for SpecSettings {
+    fn net_builder(&self, input: Shape) -> Result<NetBuilder, String> {
+        let mut layers = self.layers.clone();
+        if let Some(heads) = &self.heads {
+            layers.extend(heads.expand()?);
+        }
+        NetBuilder::with_layers(input, &layers)
+    }
+
+    fn aligned_cfg(&self) -> Result<Configuration, String> {
+        get_aligned_cfg(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::architectures::{Architecture, builder::GroupSpec};
+    use crate::iterators::SpanGenerator;
+    use crate::netcore::LUTNet;
+    use std::str::FromStr;
+
+    fn load(name: &str, source: &str) -> SpecSettings {
+        SpecSettings::new(name, source).expect("Failed to parse the spec")
+    }
+
+    // The absolute offsets and dims cnn_iv2 used to list for every span
+    const CNN_IV2_ROWS: [[usize; 10]; 16] = [
+        [0, 8, 28, 28, 6, 1, 1, 1, 1, 1],
+        [6272, 3, 28, 28, 3, 2, 1, 1, 1, 1],
+        [8624, 1, 27, 28, 1, 3, 2, 1, 3, 2],
+        [8624, 9, 12, 7, 1, 6, 1, 1, 6, 1],
+        [8624, 7, 9, 12, 1, 3, 2, 1, 3, 2],
+        [9380, 1, 9, 14, 1, 2, 3, 1, 1, 2],
+        [9506, 9, 2, 7, 3, 2, 1, 3, 2, 1],
+        [9632, 7, 3, 6, 1, 3, 2, 1, 3, 2],
+        [9758, 1, 98, 1, 1, 6, 1, 1, 6, 1],
+        [9758, 2, 7, 7, 1, 3, 2, 1, 2, 2],
+        [9758, 7, 14, 1, 1, 6, 1, 1, 4, 1],
+        [9856, 1, 62, 1, 1, 6, 1, 1, 6, 1],
+        [9856, 31, 2, 1, 3, 2, 1, 3, 2, 1],
+        [9918, 1, 22, 1, 1, 6, 1, 1, 3, 1],
+        [9918, 11, 2, 1, 3, 2, 1, 2, 1, 1],
+        [9918, 2, 11, 1, 1, 6, 1, 1, 2, 1],
+    ];
+
+    fn cnn_iv0_in_code(input: Shape) -> NetBuilder {
+        // How cnn_iv0 was built before it was a spec file
+        let mut net = NetBuilder::new(input);
+        for (len, hop) in [
+            ((6, 1, 1), (1, 1, 1)),
+            ((3, 2, 1), (1, 1, 1)),
+            ((1, 3, 2), (1, 2, 1)),
+            ((1, 2, 3), (1, 1, 2)),
+            ((1, 2, 3), (1, 1, 2)),
+            ((1, 3, 2), (1, 2, 1)),
+        ] {
+            net.span(len, hop).unwrap();
+        }
+        net.layer(vec![
+            GroupSpec::span((6, 1, 1), (3, 1, 1))
+                .reshaped((27, 1, 1))
+                .wrapped(),
+        ])
+        .unwrap();
+        net
+    }
+
+    fn cnn_iv1_in_code(input: Shape) -> NetBuilder {
+        // How cnn_iv1 was built before it was a spec file
+        let mut net = NetBuilder::new(input);
+        net.span((6, 1, 1), (1, 1, 1)).unwrap();
+        net.span((3, 2, 1), (1, 1, 1)).unwrap();
+        for nodes in [8192, 4096, 2048, 1024, 512, 256, 128, 64, 32, 16, 8] {
+            net.layer(vec![GroupSpec::random(nodes)]).unwrap();
+        }
+        net
+    }
+
+    #[test]
+    fn specs_reproduce_the_cnn_architectures() {
+        let mnist = (8, 28, 28);
+        let layer_sizes =
+            |spec: &SpecSettings| spec.net_builder(mnist).map(|net| net.layer_sizes());
+        // A single LUT in the bank makes the LUTs as reproducible as the wiring
+        let lut_bank = Some(vec![0x6996_9669_9669_6996]);
+        let nodes = |net: &NetBuilder| -> Vec<(u64, [usize; 6])> {
+            net.build_nodes(&lut_bank)
+                .iter()
+                .map(|n| (n.lut, n.indices))
+                .collect()
+        };
+
+        let cnn_iv0 = load(
+            "cnn_iv0",
+            include_str!("../../../architectures/cnn_iv0.toml"),
+        );
+        let spec = cnn_iv0.net_builder(mnist).unwrap();
+        assert_eq!(spec, cnn_iv0_in_code(mnist));
+        assert_eq!(spec.layer_sizes(), vec![2352, 756, 351, 156, 66, 25, 8]);
+        assert_eq!(nodes(&spec), nodes(&cnn_iv0_in_code(mnist)));
+
+        // The random layers draw their wiring, so only the span layers can be compared node by node
+        let cnn_iv1 = load(
+            "cnn_iv1",
+            include_str!("../../../architectures/cnn_iv1.toml"),
+        );
+        let spec = cnn_iv1.net_builder(mnist).unwrap();
+        assert_eq!(spec, cnn_iv1_in_code(mnist));
+        let spans = spec.layer_edges()[2];
+        assert_eq!(
+            nodes(&spec)[..spans],
+            nodes(&cnn_iv1_in_code(mnist))[..spans]
+        );
+        assert_eq!(nodes(&spec).len(), 2352 + 756 + 16376);
+
+        let cnn_iv2 = load(
+            "cnn_iv2",
+            include_str!("../../../architectures/cnn_iv2.toml"),
+        );
+        let expected: Vec<(u64, [usize; 6])> = CNN_IV2_ROWS
+            .iter()
+            .flat_map(|&[offset, d1, d2, d3, l1, l2, l3, h1, h2, h3]| {
+                SpanGenerator::new(offset, d1, d2, d3, l1, l2, l3, h1, h2, h3)
+            })
+            .map(|indices| (lut_bank.as_ref().unwrap()[0], indices))
+            .collect();
+        let spec = cnn_iv2.net_builder(mnist).unwrap();
+        assert_eq!(spec.layer_sizes(), vec![2352, 756, 378, 98, 62, 22, 20]);
+        assert_eq!(nodes(&spec), expected);
+
+        let local_iv0 = load(
+            "local_iv0",
+            include_str!("../../../architectures/local_iv0.toml"),
+        );
+        assert_eq!(layer_sizes(&local_iv0), Ok(vec![1176, 196, 48, 8]));
+
+        // The heads of heads_iv0 only read their own previous layer, so only trunk nodes can be shared
+        let heads_iv0 = load(
+            "heads_iv0",
+            include_str!("../../../architectures/heads_iv0.toml"),
+        );
+        assert_eq!(
+            layer_sizes(&heads_iv0),
+            Ok(vec![2352, 756, 351, 288, 48, 8])
+        );
+        let net = heads_iv0.net_builder(mnist).unwrap();
+        let ltnet = LUTNet::new(
+            net.build_nodes(&None),
+            8 * 28 * 28,
+            net.layer_edges(),
+            None,
+            vec![],
+        );
+        let (trunk, total) = (2352 + 756 + 351, ltnet.nodes.len());
+        let split = ltnet.head_nodes(8).unwrap();
+        for (head, nodes) in split.heads.iter().enumerate() {
+            // Trunk nodes read by a single head belong to it too
+            let own = |node: usize| {
+                (trunk + 36 * head..trunk + 36 * (head + 1)).contains(&node)
+                    || (trunk + 288 + 6 * head..trunk + 288 + 6 * (head + 1)).contains(&node)
+                    || node == total - 8 + head
+            };
+            assert!(nodes.iter().all(|&node| node < trunk || own(node)));
+            assert_eq!(nodes.last(), Some(&(total - 8 + head)));
+        }
+        assert!(split.shared.iter().all(|&node| node < trunk));
+
+        let empty = load("empty_spec", "[network]\nlayers = []");
+        assert!(empty.net_builder(mnist).is_err());
+        // Keys of the cnn_iv* settings from before they were spec files
+        assert!(SpecSettings::new("old_keys", "[network]\nrandom_layer_sizes = [8]").is_err());
+    }
+
+    #[test]
+    fn registered_cnn_architectures_are_their_spec_files() {
+        for name in ["cnn_iv0", "cnn_iv1", "cnn_iv2"] {
+            let architecture = Architecture::from_str(name).unwrap();
+            let path = format!("{}/architectures/{}.toml", crate::HOME_DIR, name);
+            assert_eq!(
+                architecture.default_settings(),
+                Some(std::fs::read_to_string(path).unwrap().as_str())
+            );
+            // Every layer reads only the layer before it, and all of it
+            let (cfg, ltnet) = architecture.build();
+            assert_eq!(cfg.derived.network_size, ltnet.nodes.len());
+            let edges = &cfg.derived.layer_edges;
+            for layer in 0..cfg.derived.num_layers {
+                let layer_nodes = &ltnet.nodes[edges[layer]..edges[layer + 1]];
+                let reads = match layer {
+                    0 => 0..cfg.derived.img_bitcount,
+                    l => {
+                        cfg.derived.img_bitcount + edges[l - 1]..cfg.derived.img_bitcount + edges[l]
+                    }
+                };
+                let indices = || layer_nodes.iter().flat_map(|node| node.indices);
+                assert!(
+                    indices().all(|i| reads.contains(&i)),
+                    "{} layer {}",
+                    name,
+                    layer
+                );
+                if name != "cnn_iv1" || layer < 2 {
+                    assert_eq!(indices().min(), Some(reads.start));
+                    assert_eq!(indices().max(), Some(reads.end - 1));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use config::ConfigError;
+use serde::Deserialize;
+
+use crate::architectures::LUTNetBuilder;
+use crate::architectures::builder::{GroupSpec, HeadsSpec, aligned_cfg};
+use crate::layered_config::load_architecture_settings;
+use crate::settings::{Configuration, Network, OutputCode};
+
+// An architecture described in a TOML file instead of code. The file is an architecture Settings.toml whose
+// [network] table lists the layers, each layer a list of groups (see architectures/builder.rs). The groups of
+// a layer are concatenated, and layer_sizes follow from the input dims, so they are never written down.
+//
+// [network]
+// output_embedding = [51, 15, 77, 85, 240, 170, 153, 204, 102, 210]
+// layers = [
+//     [{ op = { Span = { len = [6, 1, 1], hop = [1, 1, 1] } } }],
+//     [{ op = { Random = { nodes = 512 } } }, { op = { Pool = { window = [2, 3, 1] } }, reshape = [4, 6, 32] }],
+//     [{ op = { Pool = { window = [1, 2, 2], function = "Or" } }, from = 1 }],
+//     [{ op = { Mix = { nodes = 8, fan_in = 6 } } }],
+// ]
+//
+// An optional heads table adds separate sub-networks after the layers, their outputs concatenated:
+// heads = { count = 10, layers = [[{ op = { Random = { nodes = 64 } } }], [{ op = { Mix = { nodes = 1, fan_in = 6 } } }]] }
+//
+// The registered cnn_iv0, cnn_iv1 and cnn_iv2 architectures are the files of that name in architectures/, see
+// heads_iv0 there for heads.
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)] // Settings of the cnn_iv* architectures from before they were spec files fail loudly
+pub struct SpecSettings {
+    #[serde(default)]
+    pub output_embedding: Vec<usize>,
+    #[serde(default)]
+    pub lut_bank_size: usize,
+    #[serde(default)]
+    pub output_code: Option<OutputCode>,
+    #[serde(default)]
+    pub layers: Vec<Vec<GroupSpec>>,
+    #[serde(default)]
+    pub heads: Option<HeadsSpec>,
+}
+
+impl SpecSettings {
+    pub fn new(name: &str, source: &str) -> Result<Self, ConfigError> {
+        // Layered like the main settings, overrides go under architectures.<name>
+        load_architecture_settings(name, source)
+    }
+}
+
+pub fn get_aligned_cfg(spec: &SpecSettings) -> Result<Configuration, String> {
+    // Fails when the layers don't fit the input, the error names the layer and group
+    aligned_cfg(
+        |input| spec.net_builder(input),
+        |layer_sizes| Network {
+            output_embedding: spec.output_embedding.clone(),
+            layer_sizes,
+            lut_bank_size: spec.lut_bank_size,
+            output_code: spec.output_code.clone(),
+        },
+    )
+}
@@ -0,0 +1,298 @@
+use crate::{binarization::PixelEncoder, sampler::*, settings::*};
+use bitvec::prelude::*;
+use rand::prelude::*;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+// Random distortions of decoded images, applied before binarization. Every (epoch, row) pair gets
+// its own seeded rng, so a run is reproducible but each epoch shows the trainers slightly different images.
+
+pub struct Augmenter {
+    pub params: Augmentation,
+    pub width: usize,  // dim2
+    pub height: usize, // dim3
+}
+
+impl Augmenter {
+    pub fn new(params: Augmentation, width: usize, height: usize) -> Self {
+        Augmenter {
+            params,
+            width,
+            height,
+        }
+    }
+
+    fn rng_for(&self, epoch: usize, row: usize) -> Xoshiro256PlusPlus {
+        let key = self.params.seed
+            ^ (epoch as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            ^ (row as u64).wrapping_mul(0xD1B5_4A32_D192_ED03);
+        Xoshiro256PlusPlus::seed_from_u64(key)
+    }
+
+    pub fn augment(&self, image: &[u8], epoch: usize, row: usize) -> Vec<u8> {
+        let mut rng = self.rng_for(epoch, row);
+        let p = &self.params;
+        let mut out = if p.max_shift > 0 || p.max_rotation_deg > 0.0 || p.elastic_alpha > 0.0 {
+            self.warp(image, &mut rng)
+        } else {
+            image.to_vec()
+        };
+        if p.erase_prob > 0.0 && p.erase_max_size > 0 && rng.random::<f32>() < p.erase_prob {
+            self.erase(&mut out, &mut rng);
+        }
+        if p.noise_prob > 0.0 {
+            for pixel in out.iter_mut() {
+                if rng.random::<f32>() < p.noise_prob {
+                    *pixel = rng.random();
+                }
+            }
+        }
+        out
+    }
+
+    fn warp(&self, image: &[u8], rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+        // Inverse mapping: for every output pixel find where it comes from in the source and sample bilinearly
+        let p = &self.params;
+        let (w, h) = (self.width, self.height);
+        let shift = p.max_shift as i64;
+        let (dx, dy) = (
+            rng.random_range(-shift..=shift) as f32,
+            rng.random_range(-shift..=shift) as f32,
+        );
+        let angle = if p.max_rotation_deg > 0.0 {
+            rng.random_range(-p.max_rotation_deg..=p.max_rotation_deg)
+                .to_radians()
+        } else {
+            0.0
+        };
+        let (sin, cos) = angle.sin_cos();
+        let (cx, cy) = ((w as f32 - 1.0) / 2.0, (h as f32 - 1.0) / 2.0);
+        let displacement = if p.elastic_alpha > 0.0 {
+            Some((self.displacement_field(rng), self.displacement_field(rng)))
+        } else {
+            None
+        };
+
+        let mut out = vec![0u8; w * h];
+        for y in 0..h {
+            for x in 0..w {
+                let (mut xf, mut yf) = (x as f32 - dx - cx, y as f32 - dy - cy);
+                (xf, yf) = (cos * xf + sin * yf + cx, -sin * xf + cos * yf + cy);
+                if let Some((field_x, field_y)) = &displacement {
+                    xf += field_x[y * w + x];
+                    yf += field_y[y * w + x];
+                }
+                out[y * w + x] = bilinear(image, w, h, xf, yf);
+            }
+        }
+        out
+    }
+
+    fn displacement_field(&self, rng: &mut Xoshiro256PlusPlus) -> Vec<f32> {
+        // Uniform noise smoothed with a separable gaussian, then normalised to elastic_alpha pixels at most
+        let (w, h) = (self.width, self.height);
+        let sigma = self.params.elastic_sigma.max(0.1);
+        let radius = (3.0 * sigma).ceil() as i64;
+        let kernel: Vec<f32> = (-radius..=radius)
+            .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+            .collect();
+        let mut field: Vec<f32> = (0..w * h).map(|_| rng.random_range(-1.0..=1.0)).collect();
+        for horizontal in [true, false] {
+            let mut smoothed = vec![0.0; w * h];
+            for y in 0..h {
+                for x in 0..w {
+                    let mut acc = 0.0;
+                    for (k, weight) in kernel.iter().enumerate() {
+                        let offset = k as i64 - radius;
+                        let (sx, sy) = if horizontal {
+                            (x as i64 + offset, y as i64)
+                        } else {
+                            (x as i64, y as i64 + offset)
+                        };
+                        if sx >= 0 && sy >= 0 && (sx as usize) < w && (sy as usize) < h {
+                            acc += weight * field[sy as usize * w + sx as usize];
+                        }
+                    }
+                    smoothed[y * w + x] = acc;
+                }
+            }
+            field = smoothed;
+        }
+        let max = field
+            .iter()
+            .fold(0.0f32, |m, v| m.max(v.abs()))
+            .max(f32::EPSILON);
+        field
+            .iter()
+            .map(|v| v / max * self.params.elastic_alpha)
+            .collect()
+    }
+
+    fn erase(&self, image: &mut [u8], rng: &mut Xoshiro256PlusPlus) {
+        let (w, h) = (self.width, self.height);
+        let max_size = self.params.erase_max_size;
+        let (ew, eh) = (
+            rng.random_range(1..=max_size.min(w)),
+            rng.random_range(1..=max_size.min(h)),
+        );
+        let (x0, y0) = (rng.random_range(0..=w - ew), rng.random_range(0..=h - eh));
+        for y in y0..y0 + eh {
+            image[y * w + x0..y * w + x0 + ew].fill(0);
+        }
+    }
+}
+
+fn bilinear(image: &[u8], w: usize, h: usize, x: f32, y: f32) -> u8 {
+    // Pixels outside the image are treated as background (0)
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let pixel = |xi: f32, yi: f32| -> f32 {
+        if xi < 0.0 || yi < 0.0 || xi >= w as f32 || yi >= h as f32 {
+            0.0
+        } else {
+            image[yi as usize * w + xi as usize] as f32
+        }
+    };
+    let value = pixel(x0, y0) * (1.0 - fx) * (1.0 - fy)
+        + pixel(x0 + 1.0, y0) * fx * (1.0 - fy)
+        + pixel(x0, y0 + 1.0) * (1.0 - fx) * fy
+        + pixel(x0 + 1.0, y0 + 1.0) * fx * fy;
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+pub struct AugmentedBatches {
+    cfg: Configuration,
+    pixels: Vec<u8>,
+    labels: Vec<usize>,
+    encoder: PixelEncoder,
+    augmenter: Augmenter,
+    sampler: BatchSampler,
+    epoch: usize,
+    batches: Vec<Vec<usize>>,
+}
+
+impl AugmentedBatches {
+    pub fn new(
+        cfg: &Configuration,
+        pixels: Vec<u8>,
+        labels: Vec<usize>,
+        encoder: PixelEncoder,
+        params: Augmentation,
+    ) -> Self {
+        let sampler = BatchSampler::new(cfg, &labels);
+        AugmentedBatches {
+            cfg: cfg.clone(),
+            pixels,
+            labels,
+            encoder,
+            augmenter: Augmenter::new(params, cfg.data.dim2, cfg.data.dim3),
+            sampler,
+            epoch: 0,
+            batches: Vec::new(),
+        }
+    }
+}
+
+impl BatchSource for AugmentedBatches {
+    fn num_rows(&self) -> usize {
+        self.labels.len()
+    }
+
+    fn num_batches(&self) -> usize {
+        self.sampler.num_batches()
+    }
+
+    fn varies_per_epoch(&self) -> bool {
+        true
+    }
+
+    fn start_epoch(&mut self, epoch: usize) {
+        self.epoch = epoch;
+        self.batches = self.sampler.epoch_batches(epoch);
+    }
+
+    fn load_batch(&mut self, batch_num: usize, dbv: &mut BitVec<u8, Msb0>) -> Vec<usize> {
+        let cols = self.cfg.derived.cols;
+        let mut batch_bits = BitVec::<u8, Msb0>::with_capacity(self.cfg.derived.batch_bitcount);
+        for &row in &self.batches[batch_num] {
+            let image =
+                self.augmenter
+                    .augment(&self.pixels[row * cols..(row + 1) * cols], self.epoch, row);
+            self.encoder.encode_image(&image, &mut batch_bits);
+        }
+        dbv[..batch_bits.len()].copy_from_bitslice(&batch_bits);
+        self.batches[batch_num]
+            .iter()
+            .map(|&row| self.labels[row])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square_image() -> Vec<u8> {
+        // 8x8 image with a 2x2 bright square at (3, 3)
+        let mut image = vec![0u8; 64];
+        for (x, y) in [(3, 3), (4, 3), (3, 4), (4, 4)] {
+            image[y * 8 + x] = 200;
+        }
+        image
+    }
+
+    #[test]
+    fn no_op_augmentation_returns_image_unchanged() {
+        let augmenter = Augmenter::new(Augmentation::default(), 8, 8);
+        assert_eq!(augmenter.augment(&square_image(), 3, 17), square_image());
+    }
+
+    #[test]
+    fn shifts_move_the_square_and_are_reproducible() {
+        let params = Augmentation {
+            max_shift: 2,
+            ..Default::default()
+        };
+        let augmenter = Augmenter::new(params, 8, 8);
+        let image = square_image();
+        let shifted = augmenter.augment(&image, 1, 5);
+        assert_eq!(shifted, augmenter.augment(&image, 1, 5));
+        // A pure translation by whole pixels keeps the square intact
+        assert_eq!(shifted.iter().filter(|&&v| v == 200).count(), 4);
+        let differs = (0..20).any(|epoch| augmenter.augment(&image, epoch, 5) != image);
+        assert!(differs);
+    }
+
+    #[test]
+    fn erasing_and_noise_only_change_pixels() {
+        let params = Augmentation {
+            erase_prob: 1.0,
+            erase_max_size: 8,
+            ..Default::default()
+        };
+        let augmenter = Augmenter::new(params, 8, 8);
+        let erased = augmenter.augment(&[255u8; 64], 0, 0);
+        assert!(erased.contains(&0));
+
+        let params = Augmentation {
+            noise_prob: 1.0,
+            ..Default::default()
+        };
+        let augmenter = Augmenter::new(params, 8, 8);
+        assert_eq!(augmenter.augment(&square_image(), 0, 0).len(), 64);
+    }
+
+    #[test]
+    fn rotation_and_elastic_keep_most_of_the_mass() {
+        let params = Augmentation {
+            max_rotation_deg: 15.0,
+            elastic_alpha: 1.0,
+            elastic_sigma: 2.0,
+            ..Default::default()
+        };
+        let augmenter = Augmenter::new(params, 8, 8);
+        let image = square_image();
+        let mass = |img: &[u8]| img.iter().map(|&v| v as u32).sum::<u32>();
+        let warped = augmenter.augment(&image, 2, 9);
+        assert!(mass(&warped) > mass(&image) / 2);
+    }
+}
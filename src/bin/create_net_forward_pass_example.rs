@@ -1,15 +1,27 @@
 use bitvec::prelude::*;
+use clap::Parser;
+use lutnets::layered_config::ConfigArgs;
 use lutnets::{architectures::*, processing::*, utils::calculate_accuracy};
 use std::{error::Error, str::FromStr, time::Instant};
 
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     // This file simply shows creating a new network, and manually running a forward pass on a single batch
+    Args::parse().config.install()?;
     let start_time = Instant::now();
     println!("LUTNets Reference Example");
     // let cfg = get_cfg(None);
     let arch_name = "random";
     let architecture = Architecture::from_str(arch_name)?;
+    architecture.validate()?.ensure_valid()?;
     let (cfg, ltnet) = architecture.build();
+    let cfg = &cfg;
 
     println!(
         "Network initialized. Nodes: {}, Wall time: {:?}",
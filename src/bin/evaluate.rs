@@ -0,0 +1,62 @@
+use clap::Parser;
+use lutnets::layered_config::ConfigArgs;
+use lutnets::{
+    evaluation::{evaluate, evaluation_cfg},
+    modelloader::load_model_from_file,
+    settings::*,
+};
+use std::{error::Error, time::Instant};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(short, long)]
+    f: String, // Model filename, without the .ltnet extension
+    #[arg(short, long)]
+    s: Option<String>, // Split to evaluate: train, val or test. Defaults to the validation split.
+    #[arg(long, default_value_t = 1000)]
+    chunk: usize, // Samples per forward pass
+    #[arg(long, default_value_t = 20)]
+    worst: usize, // Number of worst misclassified samples to list
+    #[arg(long)]
+    out: Option<String>, // Also write <out>.csv and <out>.json
+    #[command(flatten)]
+    config: ConfigArgs, // --config and --set, shared by all binaries
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // Full evaluation report of a model on one split
+    let start_time = Instant::now();
+    let args = Args::parse();
+    args.config.install()?;
+    let model_filename = format!("{}.ltnet", args.f);
+    if !std::path::Path::new(&model_filename).exists() {
+        return Err(format!("File {} does not exist.", model_filename).into());
+    }
+    let (cfg, ltnet) = load_model_from_file(&model_filename);
+    let val_split = || cfg.data.val_split.clone().unwrap_or(DataSplit::Val(10000));
+    let split = match args.s.as_deref() {
+        Some("train") => DataSplit::Train(50000),
+        Some("val") | None => val_split(),
+        Some("test") => DataSplit::Test(10000),
+        Some(other) => {
+            return Err(format!("Unknown split {}, use train, val or test", other).into());
+        }
+    };
+    let split_cfg = evaluation_cfg(cfg, &split, args.chunk);
+    split_cfg.validate().ensure_valid()?;
+
+    let mut report = evaluate(&ltnet, &split_cfg)?;
+    report.worst = args.worst;
+    print!("{}", report);
+    if let Some(prefix) = &args.out {
+        let (csv_path, json_path) = report.write_files(prefix)?;
+        println!(
+            "Report written to {} and {}",
+            csv_path.display(),
+            json_path.display()
+        );
+    }
+    println!("Total time: {:?}", start_time.elapsed());
+    Ok(())
+}
@@ -1,7 +1,8 @@
 use bitvec::prelude::*;
 use clap::Parser;
+use lutnets::layered_config::ConfigArgs;
+use lutnets::{decoding::REJECTED, processing::*, utils::*};
 use lutnets::{modelloader::load_model_from_file, netcore::*, settings::*};
-use lutnets::{processing::*, utils::*};
 use std::{error::Error, time::Instant};
 
 #[derive(Parser, Debug)]
@@ -11,15 +12,16 @@ struct Args {
     s: Option<String>, // arg for passing split: Train, Val, Test
     #[arg(short, long)]
     f: Option<String>, // arg for passing model filename to test
+    #[command(flatten)]
+    config: ConfigArgs, // --config and --set, shared by all binaries
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let start_time = Instant::now();
     let args = Args::parse();
+    args.config.install()?;
     // let model_filename;
     let (cfg_default, ltnet): (&'static Configuration, LUTNet);
-    let mut cfg: Configuration;
-    let batch_size: u32;
     // Parse the arguments and load the mode. This code is exposed here because various algoritms may need additional arguments.
 
     match args.f {
@@ -32,42 +34,36 @@ fn main() -> Result<(), Box<dyn Error>> {
                 );
             }
             (cfg_default, ltnet) = load_model_from_file(&model_filename);
-            cfg = cfg_default.clone();
         }
         None => {
             panic!("Must specify a file with option -- -f <filename>");
         }
     }
 
-    match args.s {
-        Some(s_str) => match s_str.as_str() {
-            "train" => {
-                batch_size = 50000;
-                cfg.data.datasplit = DataSplit::Train(batch_size);
-            }
-            "val" => {
-                batch_size = 10000;
-                cfg.data.datasplit = DataSplit::Val(batch_size);
-            }
-            "test" => {
-                batch_size = 10000;
-                cfg.data.datasplit = DataSplit::Test(batch_size);
-            }
-            _ => {
-                panic!("Provide a valid split");
-            }
-        },
+    // Evaluate the whole split in one batch
+    let split = match args.s.as_deref() {
+        Some("train") => DataSplit::Train(50000),
+        Some("val") => cfg_default
+            .data
+            .val_split
+            .clone()
+            .unwrap_or(DataSplit::Val(10000)),
+        Some("test") => DataSplit::Test(10000),
+        Some(_) => panic!("Provide a valid split"),
         None => {
             println!("No split provided, assuming Validation split.");
-            batch_size = 10000;
-            cfg.data.datasplit = DataSplit::Val(batch_size);
+            cfg_default
+                .data
+                .val_split
+                .clone()
+                .unwrap_or(DataSplit::Val(10000))
         }
-    }
+    };
+    let rows = split.num_rows(&cfg_default.data);
+    let cfg = cfg_default.for_split(split, Some(rows));
+    cfg.validate().ensure_valid()?;
     println!("Getting {:?} accuracy", cfg.data.datasplit);
 
-    cfg.data.batch_size = batch_size as usize;
-    cfg.derived = DerivedValues::new(&cfg.data, &cfg.network);
-
     // let model_filename = &args[1][..];
     let (databits, labels) = lutnets::dataloader::csv_to_bitvec(&cfg)?;
 
@@ -84,10 +80,17 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let (predicted_labels, loss) = (get_labels(&cfg, &dbv), get_loss(&cfg, &dbv, y));
     let accuracy = calculate_accuracy(y, &predicted_labels);
+    let rejected = predicted_labels
+        .iter()
+        .filter(|&&label| label == REJECTED)
+        .count();
+    let top_3_accuracy = calculate_top_k_accuracy(y, &get_ranked_predictions(&cfg, &dbv, 3), 3);
     println!(
-        " Loss: {:?}\n Accuracy: {:.1}%\n Total time: {:?}",
+        " Loss: {:?}\n Accuracy: {:.1}%\n Top-3 accuracy: {:.1}%\n Rejected: {}\n Total time: {:?}",
         loss,
         accuracy * 100.0,
+        top_3_accuracy * 100.0,
+        rejected,
         start_time.elapsed()
     );
     Ok(())
@@ -0,0 +1,64 @@
+use clap::Parser;
+use lutnets::layered_config::ConfigArgs;
+use lutnets::{
+    architectures::{builder::input_shape, *},
+    inspection::inspect,
+    modelloader::load_model_from_file,
+};
+use std::{error::Error, str::FromStr};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(short, long)]
+    a: Option<String>, // Architecture to build and inspect, or a spec file
+    #[arg(short, long, conflicts_with = "a")]
+    f: Option<String>, // Model to inspect instead, without the .ltnet extension
+    #[arg(long)]
+    fields: bool, // Also print the receptive field of every output bit
+    #[arg(long)]
+    out: Option<String>, // Also write <out>_coverage.pgm and <out>_output_<i>.pgm heatmaps
+    #[arg(long)]
+    list_architectures: bool, // Print the registered architectures and exit
+    #[command(flatten)]
+    config: ConfigArgs, // --config and --set, shared by all binaries
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // Wiring report of a network: layer sizes, fan-out, dead nodes and receptive fields. Needs no data.
+    let args = Args::parse();
+    if args.list_architectures {
+        print!("{}", registry::architecture_list());
+        return Ok(());
+    }
+    args.config.install()?;
+    let (cfg, ltnet) = match (args.a, args.f) {
+        (Some(arch_name), None) => {
+            let arch = Architecture::from_str(&arch_name)?;
+            arch.validate()?.ensure_valid()?;
+            arch.build()
+        }
+        (None, Some(model_file_base)) => {
+            let model_filename = format!("{}.ltnet", model_file_base);
+            if !std::path::Path::new(&model_filename).exists() {
+                return Err(format!("File {} does not exist.", model_filename).into());
+            }
+            let (cfg, ltnet) = load_model_from_file(&model_filename);
+            (cfg.clone(), ltnet)
+        }
+        _ => return Err("Pass an architecture with -a or a model file with -f.".into()),
+    };
+
+    let mut report = inspect(&ltnet, input_shape(&cfg.data))?;
+    report.show_fields = args.fields;
+    print!("{}", report);
+    if let Some(prefix) = &args.out {
+        let paths = report.write_files(prefix)?;
+        println!(
+            "Heatmaps written to {} and {} more",
+            paths[0].display(),
+            paths.len() - 1
+        );
+    }
+    Ok(())
+}
@@ -1,7 +1,11 @@
 use clap::Parser;
+use lutnets::layered_config::ConfigArgs;
 use lutnets::{
-    architectures::*, modelloader::load_model_from_file, netcore::*, settings::*,
-    training_algos::naive_evolutionary::trainer::*,
+    architectures::*,
+    modelloader::load_model_from_file,
+    netcore::*,
+    settings::*,
+    training_algos::{TrainParams, heads::check_heads, naive_evolutionary::trainer::*},
 };
 use std::{error::Error, str::FromStr, time::Instant};
 
@@ -12,19 +16,35 @@ struct Args {
     a: Option<String>, // arg for passing architecture, requires an output filename to save
     #[arg(short, long)]
     f: Option<String>, // arg for passing output filename along with architecture, or input model if passed alone
+    #[arg(long)]
+    list_architectures: bool, // Print the registered architectures and exit
+    #[arg(long, default_value_t = 0.0)]
+    rewire_probability: f32, // Chance a mutated node gets a new input instead of a new LUT
+    #[arg(long, default_value_t = 0)]
+    heads: usize, // Mutate one of this many output heads at a time, 0 for the whole network
+    #[command(flatten)]
+    config: ConfigArgs, // --config and --set, shared by all binaries
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let start_time = Instant::now();
     let args = Args::parse();
+    if args.list_architectures {
+        print!("{}", registry::architecture_list());
+        return Ok(());
+    }
+    args.config.install()?;
     let model_filename;
-    let (cfg, mut ltnet): (&'static Configuration, LUTNet);
+    let built_cfg: Configuration; // Owns the configuration of a newly built network, cfg borrows it
+    let (cfg, mut ltnet): (&Configuration, LUTNet);
 
     // Parse the arguments and load the mode. This code is exposed here because various algoritms may need additional arguments.
     match (args.a, args.f) {
         (Some(arch_name), Some(model_file_base)) => {
             let arch = Architecture::from_str(&arch_name)?;
-            (cfg, ltnet) = arch.build();
+            arch.validate()?.ensure_valid()?; // Report every settings problem before building anything
+            (built_cfg, ltnet) = arch.build();
+            cfg = &built_cfg;
             model_filename = format!("{}.ltnet", model_file_base);
             if std::path::Path::new(&model_filename).exists()
                 | std::path::Path::new(&model_file_base).exists()
@@ -38,6 +58,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         (None, Some(model_file_base)) => {
             model_filename = format!("{}.ltnet", model_file_base);
             (cfg, ltnet) = load_model_from_file(&model_filename);
+            cfg.validate().ensure_valid()?;
         }
         (Some(_), None) => unreachable!(),
         (None, None) => {
@@ -47,23 +68,29 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    check_heads(cfg, args.heads)?; // Before loading any data
+
     println!("Running Naive Evolutionary Algorithm Example");
     println!("Data split and size: {:?}", cfg.data.datasplit);
-    let (databits, labels) = lutnets::dataloader::csv_to_bitvec(cfg)?;
+    let mut batches = lutnets::dataloader::training_batches(cfg)?;
+    lutnets::tabular::write_encoder_sidecar(cfg, &model_filename)?;
     println!(
         "Read CSV rows: {}, Wall time: {:?}",
-        labels.len(),
+        batches.num_rows(),
         start_time.elapsed()
     );
     ltnet.verify_lut_bank_integrity();
     train(
         &mut ltnet,
         cfg,
-        &databits,
-        &labels,
-        0.001,
-        200,
-        100000,
+        batches.as_mut(),
+        TrainParams {
+            corruption_ratio: 0.001,
+            lut_sampling_depth: 200,
+            rewire_probability: args.rewire_probability,
+            heads: args.heads,
+            epochs: 100000,
+        },
         &model_filename,
     );
     println!("Total time: {:?}", start_time.elapsed());
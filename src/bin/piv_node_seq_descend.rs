@@ -1,4 +1,5 @@
 use clap::Parser;
+use lutnets::layered_config::ConfigArgs;
 use lutnets::{
     architectures::*, modelloader::load_model_from_file, netcore::*, settings::*,
     training_algos::piv_node_flipper::trainer::*,
@@ -12,19 +13,31 @@ struct Args {
     a: Option<String>, // arg for passing architecture, requires an output filename to save
     #[arg(short, long)]
     f: Option<String>, // arg for passing output filename along with architecture, or input model if passed alone
+    #[arg(long)]
+    list_architectures: bool, // Print the registered architectures and exit
+    #[command(flatten)]
+    config: ConfigArgs, // --config and --set, shared by all binaries
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let start_time = Instant::now();
     let args = Args::parse();
+    if args.list_architectures {
+        print!("{}", registry::architecture_list());
+        return Ok(());
+    }
+    args.config.install()?;
     let model_filename;
-    let (cfg, mut ltnet): (&'static Configuration, LUTNet);
+    let built_cfg: Configuration; // Owns the configuration of a newly built network, cfg borrows it
+    let (cfg, mut ltnet): (&Configuration, LUTNet);
 
     // Parse the arguments and load the mode. This code is exposed here because various algoritms may need additional arguments.
     match (args.a, args.f) {
         (Some(arch_name), Some(model_file_base)) => {
             let arch = Architecture::from_str(&arch_name)?;
-            (cfg, ltnet) = arch.build();
+            arch.validate()?.ensure_valid()?; // Report every settings problem before building anything
+            (built_cfg, ltnet) = arch.build();
+            cfg = &built_cfg;
             model_filename = format!("{}.ltnet", model_file_base);
             if std::path::Path::new(&model_filename).exists() {
                 panic!(
@@ -36,6 +49,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         (None, Some(model_file_base)) => {
             model_filename = format!("{}.ltnet", model_file_base);
             (cfg, ltnet) = load_model_from_file(&model_filename);
+            cfg.validate().ensure_valid()?;
         }
         (Some(_), None) => unreachable!(),
         (None, None) => {
@@ -47,18 +61,18 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     println!("Running Naive Evolutionary Algorithm Example");
     println!("Data split and size: {:?}", cfg.data.datasplit);
-    let (databits, labels) = lutnets::dataloader::csv_to_bitvec(cfg)?;
+    let mut batches = lutnets::dataloader::training_batches(cfg)?;
+    lutnets::tabular::write_encoder_sidecar(cfg, &model_filename)?;
     println!(
         "Read CSV rows: {}, Wall time: {:?}",
-        labels.len(),
+        batches.num_rows(),
         start_time.elapsed()
     );
     ltnet.verify_lut_bank_integrity();
     train(
         &mut ltnet,
         cfg,
-        &databits,
-        &labels,
+        batches.as_mut(),
         100000,
         1,
         &model_filename,
@@ -0,0 +1,88 @@
+use clap::Parser;
+use lutnets::{
+    architectures::*, experiment::resolved_manifest, layered_config::ConfigArgs,
+    modelloader::decode_model, settings::Manifest, utils::set_global_seed,
+};
+use std::{error::Error, fs, path::Path, path::PathBuf, str::FromStr, time::Instant};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(required_unless_present_any = ["list_architectures", "show_manifest"])]
+    manifest: Option<PathBuf>, // Experiment file, e.g. experiments/mnist_cnn_iv0_evolutionary.toml
+    #[arg(long)]
+    list_architectures: bool, // Print the registered architectures and exit
+    #[arg(long, value_name = "MODEL")]
+    show_manifest: Option<PathBuf>, // Print the experiment file a model was trained from and exit
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>, // Applied on top of the experiment file, e.g. --set trainer.epochs=10
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // Trains one model as described by an experiment file, and stores the resolved file in the model
+    let start_time = Instant::now();
+    let args = Args::parse();
+    if args.list_architectures {
+        print!("{}", registry::architecture_list());
+        return Ok(());
+    }
+    if let Some(model_file) = args.show_manifest {
+        let ltnet = decode_model(&fs::read(&model_file)?)?;
+        let manifest = ltnet.manifest.ok_or_else(|| {
+            format!(
+                "{} wasn't trained by run_experiment, it has no manifest",
+                model_file.display()
+            )
+        })?;
+        print!("{}", manifest);
+        return Ok(());
+    }
+    let experiment_file = args.manifest.unwrap(); // Required unless listing or showing a manifest
+    ConfigArgs {
+        config: Some(experiment_file.clone()),
+        overrides: args.overrides,
+    }
+    .install()?;
+    let manifest = Manifest::load()?;
+    if let Some(seed) = manifest.experiment.seed {
+        set_global_seed(seed)?;
+    }
+    println!(
+        "Running experiment {} from {}",
+        manifest.experiment.name,
+        experiment_file.display()
+    );
+
+    let arch = Architecture::from_str(&manifest.experiment.architecture)?;
+    arch.validate()?.ensure_valid()?;
+    let model_filename = format!("{}.ltnet", manifest.experiment.model);
+    if Path::new(&model_filename).exists() {
+        return Err(format!(
+            "File {} already exists! Set experiment.model to train a new model.",
+            model_filename
+        )
+        .into());
+    }
+    if let Some(dir) = Path::new(&model_filename).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let (cfg, mut ltnet) = arch.build();
+    let cfg = &cfg;
+    manifest.trainer.validate(cfg)?;
+    ltnet.manifest = Some(resolved_manifest(&manifest)?);
+
+    println!("Data split and size: {:?}", cfg.data.datasplit);
+    let mut batches = lutnets::dataloader::training_batches(cfg)?;
+    lutnets::tabular::write_encoder_sidecar(cfg, &model_filename)?;
+    println!(
+        "Read CSV rows: {}, Wall time: {:?}",
+        batches.num_rows(),
+        start_time.elapsed()
+    );
+    ltnet.verify_lut_bank_integrity();
+    manifest
+        .trainer
+        .train(&mut ltnet, cfg, batches.as_mut(), &model_filename);
+    println!("Total time: {:?}", start_time.elapsed());
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use clap::Parser;
+use lutnets::{
+    layered_config::ConfigArgs, search::run_search, settings::SearchManifest,
+    utils::set_global_seed,
+};
+use std::{error::Error, path::PathBuf, time::Instant};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    manifest: PathBuf, // Search file, e.g. experiments/search_spans.toml
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>, // Applied on top of the search file, e.g. --set search.candidates=4
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // Samples architectures from the search space of a search file, trains them with successive halving and
+    // ranks them on validation accuracy
+    let start_time = Instant::now();
+    let args = Args::parse();
+    ConfigArgs {
+        config: Some(args.manifest.clone()),
+        overrides: args.overrides,
+    }
+    .install()?;
+    let manifest = SearchManifest::load()?;
+    let search = &manifest.search;
+    if let Some(seed) = search.seed {
+        set_global_seed(seed)?;
+    }
+    println!(
+        "Running search {} from {}, rounds of (candidates, epochs): {:?}",
+        search.name,
+        args.manifest.display(),
+        search.schedule()
+    );
+
+    let leaderboard = run_search(search, &manifest.trainer)?;
+    print!("\n{}", leaderboard);
+    if let Some(best) = leaderboard.ranked().first() {
+        println!(
+            "Best candidate written to {} and {}",
+            search.spec_path(best.id).display(),
+            search.model_path(best.id)
+        );
+    }
+    println!(
+        "Leaderboard written to {}",
+        search.leaderboard_path().display()
+    );
+    println!("Total time: {:?}", start_time.elapsed());
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use crate::{dataloader::read_csv_pixels, settings::*};
+use bitvec::prelude::*;
+use std::error::Error;
+
+// Turns decoded 8-bit pixels into the bits that are fed to the first layer of the network.
+// The Raw encoding reproduces the original behaviour of writing all 8 bits of each pixel,
+// the other encodings trade bits per pixel for representations that are easier for LUTs to use.
+
+// 4x4 Bayer matrix used for ordered dithering
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+#[derive(Debug, Clone)]
+pub struct PixelEncoder {
+    pub encoding: Encoding,
+    pub width: usize, // pixels per image row, needed for position dependent encodings
+    pub quantiles: Option<Vec<u8>>, // levels - 1 fitted thresholds per pixel, only for Quantile encoding
+}
+
+impl PixelEncoder {
+    pub fn new(encoding: Encoding, width: usize) -> Self {
+        PixelEncoder {
+            encoding,
+            width,
+            quantiles: None,
+        }
+    }
+
+    pub fn from_cfg(cfg: &Configuration) -> Result<Self, Box<dyn Error>> {
+        // Quantile encoding needs to see training data. We always fit on the start of the train file, whatever split is being loaded.
+        cfg.data.encoding.validate()?;
+        let mut encoder = PixelEncoder::new(cfg.data.encoding.clone(), cfg.data.dim2);
+        if let Encoding::Quantile { fit_rows, .. } = cfg.data.encoding {
+            let (pixels, _) = read_csv_pixels(cfg, &cfg.data.train_filepath, 0, fit_rows)?;
+            encoder.fit(&pixels, cfg.derived.cols);
+        }
+        Ok(encoder)
+    }
+
+    pub fn bits_per_pixel(&self) -> usize {
+        self.encoding.bits_per_pixel()
+    }
+
+    pub fn fit(&mut self, pixels: &[u8], cols: usize) {
+        // Computes per-pixel quantile thresholds from rows of training pixels laid out back to back
+        let Encoding::Quantile { levels, .. } = self.encoding else {
+            return;
+        };
+        let rows = pixels.len() / cols;
+        assert!(rows > 0, "Need at least one row to fit quantile thresholds");
+        let mut quantiles = Vec::with_capacity(cols * (levels - 1));
+        let mut column = Vec::with_capacity(rows);
+        for col in 0..cols {
+            column.clear();
+            column.extend(pixels.iter().skip(col).step_by(cols));
+            column.sort_unstable();
+            for level in 1..levels {
+                quantiles.push(column[(level * rows / levels).min(rows - 1)]);
+            }
+        }
+        self.quantiles = Some(quantiles);
+    }
+
+    #[inline]
+    fn encode_pixel(&self, value: u8, pixel_idx: usize, bv: &mut BitVec<u8, Msb0>) {
+        match self.encoding {
+            Encoding::Raw => bv.extend_from_bitslice(value.view_bits::<Msb0>()),
+            Encoding::Threshold(levels) => {
+                let level = value as usize * levels / 256;
+                let width = self.bits_per_pixel();
+                bv.extend_from_bitslice(&level.view_bits::<Msb0>()[usize::BITS as usize - width..]);
+            }
+            Encoding::Thermometer(levels) => {
+                let level = value as usize * levels / 256;
+                bv.extend((1..levels).map(|j| level >= j));
+            }
+            Encoding::Gray(bits) => {
+                let v = value >> (8 - bits);
+                let gray = v ^ (v >> 1);
+                bv.extend_from_bitslice(&gray.view_bits::<Msb0>()[8 - bits..]);
+            }
+            Encoding::Quantile { levels, .. } => {
+                let quantiles = self
+                    .quantiles
+                    .as_ref()
+                    .expect("Quantile encoder must be fitted before encoding");
+                let thresholds =
+                    &quantiles[pixel_idx * (levels - 1)..(pixel_idx + 1) * (levels - 1)];
+                bv.extend(thresholds.iter().map(|&t| value > t));
+            }
+            Encoding::Dither(levels) => {
+                let (x, y) = (pixel_idx % self.width, pixel_idx / self.width);
+                let bayer = BAYER_4X4[y % 4][x % 4] as usize;
+                // level = floor(value * (levels - 1) / 255 + (bayer + 0.5) / 16), kept in integers
+                let level =
+                    (value as usize * (levels - 1) * 32 + 255 * (2 * bayer + 1)) / (255 * 32);
+                bv.extend((1..levels).map(|j| level >= j));
+            }
+        }
+    }
+
+    pub fn encode_image(&self, pixels: &[u8], bv: &mut BitVec<u8, Msb0>) {
+        for (pixel_idx, &value) in pixels.iter().enumerate() {
+            self.encode_pixel(value, pixel_idx, bv);
+        }
+    }
+
+    pub fn encode(&self, pixels: &[u8], cols: usize) -> BitVec<u8, Msb0> {
+        // Encodes rows of pixels laid out back to back into one bitvec, ready to be copied into dbv batch by batch
+        let mut bv = BitVec::with_capacity(pixels.len() * self.bits_per_pixel());
+        for image in pixels.chunks_exact(cols) {
+            self.encode_image(image, &mut bv);
+        }
+        bv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_one(encoder: &PixelEncoder, value: u8) -> Vec<bool> {
+        let mut bv = BitVec::<u8, Msb0>::new();
+        encoder.encode_image(&[value], &mut bv);
+        bv.iter().by_vals().collect()
+    }
+
+    #[test]
+    fn encodings_produce_expected_bits() {
+        let raw = PixelEncoder::new(Encoding::Raw, 1);
+        let bits: BitVec<u8, Msb0> = encode_one(&raw, 209).into_iter().collect();
+        assert_eq!(bits.load_be::<u8>(), 209);
+
+        let threshold = PixelEncoder::new(Encoding::Threshold(4), 1);
+        assert_eq!(threshold.bits_per_pixel(), 2);
+        assert_eq!(encode_one(&threshold, 0), vec![false, false]);
+        assert_eq!(encode_one(&threshold, 130), vec![true, false]);
+        assert_eq!(encode_one(&threshold, 255), vec![true, true]);
+
+        let thermometer = PixelEncoder::new(Encoding::Thermometer(4), 1);
+        assert_eq!(thermometer.bits_per_pixel(), 3);
+        assert_eq!(encode_one(&thermometer, 10), vec![false, false, false]);
+        assert_eq!(encode_one(&thermometer, 130), vec![true, true, false]);
+        assert_eq!(encode_one(&thermometer, 255), vec![true, true, true]);
+
+        let gray = PixelEncoder::new(Encoding::Gray(3), 1);
+        // top 3 bits of 0b1100_0000 are 0b110, gray code is 0b101
+        assert_eq!(encode_one(&gray, 0b1100_0000), vec![true, false, true]);
+    }
+
+    #[test]
+    fn gray_code_neighbours_differ_in_one_bit() {
+        let gray = PixelEncoder::new(Encoding::Gray(8), 1);
+        for value in 0..255u8 {
+            let a = encode_one(&gray, value);
+            let b = encode_one(&gray, value + 1);
+            assert_eq!(a.iter().zip(&b).filter(|(x, y)| x != y).count(), 1);
+        }
+    }
+
+    #[test]
+    fn quantile_encoder_fits_per_pixel_thresholds() {
+        // Two pixels per image. Pixel 0 is always 0, pixel 1 spreads over 0..=250.
+        let pixels: Vec<u8> = (0..=25u8).flat_map(|i| [0, i * 10]).collect();
+        let mut encoder = PixelEncoder::new(
+            Encoding::Quantile {
+                levels: 2,
+                fit_rows: 26,
+            },
+            2,
+        );
+        encoder.fit(&pixels, 2);
+        let quantiles = encoder.quantiles.clone().unwrap();
+        assert_eq!(quantiles, vec![0, 130]);
+        let bv = encoder.encode(&[0, 200, 0, 50], 2);
+        assert_eq!(
+            bv.iter().by_vals().collect::<Vec<_>>(),
+            vec![false, true, false, false]
+        );
+    }
+
+    #[test]
+    fn dithering_preserves_average_intensity() {
+        let dither = PixelEncoder::new(Encoding::Dither(2), 4);
+        for value in [0u8, 64, 128, 192, 255] {
+            let image = vec![value; 16];
+            let bv = dither.encode(&image, 16);
+            let expected = (value as f32 / 255.0 * 16.0).round() as isize;
+            assert!((bv.count_ones() as isize - expected).abs() <= 1);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use crate::{settings::*, splits::source_filepath};
+use bitvec::prelude::*;
+use memmap2::Mmap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::ops::Deref;
+use std::path::Path;
+
+// Binary cache of encoded splits, so short runs don't spend their startup parsing CSV files.
+// A cache file holds a header, the labels as u32 and the packed input bits of every row.
+// Its name is a hash of the settings that affect the bits: the source path, the split and the encoding. The header
+// also holds a digest of the source contents, so an edited source rebuilds the cache in place and a copied or
+// touched one still hits it.
+//
+// Layout (little endian): magic, version, key, source digest, rows, img_bitcount as 8 byte fields, rows * u32
+// labels, packed bits.
+
+const MAGIC: &[u8; 8] = b"LUTNCACH";
+const CACHE_VERSION: u64 = 2; // Bump whenever the layout or the encoders change
+const HEADER_BYTES: usize = 48;
+
+pub struct CachedSplit {
+    mmap: Mmap,
+    rows: usize,
+    img_bitcount: usize,
+}
+
+impl CachedSplit {
+    pub fn open(path: &Path, key: u64, digest: u64) -> Result<Option<Self>, Box<dyn Error>> {
+        // Returns None when the file is missing, stale or truncated, so the caller rebuilds it
+        let Ok(file) = File::open(path) else {
+            return Ok(None);
+        };
+        // Safety: cache files are only ever replaced by a rename, never modified in place
+        let mmap = unsafe { Mmap::map(&file)? };
+        if mmap.len() < HEADER_BYTES || &mmap[..8] != MAGIC {
+            return Ok(None);
+        }
+        let field = |i: usize| u64::from_le_bytes(mmap[8 * i..8 * (i + 1)].try_into().unwrap());
+        if field(1) != CACHE_VERSION || field(2) != key || field(3) != digest {
+            return Ok(None);
+        }
+        let (rows, img_bitcount) = (field(4) as usize, field(5) as usize);
+        if mmap.len() != HEADER_BYTES + 4 * rows + (rows * img_bitcount).div_ceil(8) {
+            return Ok(None);
+        }
+        Ok(Some(CachedSplit {
+            mmap,
+            rows,
+            img_bitcount,
+        }))
+    }
+
+    pub fn labels(&self) -> Vec<usize> {
+        self.mmap[HEADER_BYTES..HEADER_BYTES + 4 * self.rows]
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+            .collect()
+    }
+
+    pub fn bits(&self) -> &BitSlice<u8, Msb0> {
+        let start = HEADER_BYTES + 4 * self.rows;
+        &self.mmap[start..].view_bits::<Msb0>()[..self.rows * self.img_bitcount]
+    }
+}
+
+pub fn write_cache(
+    path: &Path,
+    key: u64,
+    digest: u64,
+    databits: &BitSlice<u8, Msb0>,
+    labels: &[usize],
+    img_bitcount: usize,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Write next to the final file and rename, so readers never see a half written cache
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(MAGIC)?;
+    for field in [
+        CACHE_VERSION,
+        key,
+        digest,
+        labels.len() as u64,
+        img_bitcount as u64,
+    ] {
+        writer.write_all(&field.to_le_bytes())?;
+    }
+    for &label in labels {
+        writer.write_all(&(label as u32).to_le_bytes())?;
+    }
+    writer.write_all(databits.to_bitvec().as_raw_slice())?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Input bits of a split, either decoded in this run or mapped from the cache
+pub enum DataBits {
+    Owned(BitVec<u8, Msb0>),
+    Mapped(CachedSplit),
+}
+
+impl DataBits {
+    pub fn into_bitvec(self) -> BitVec<u8, Msb0> {
+        match self {
+            DataBits::Owned(bv) => bv,
+            DataBits::Mapped(cached) => cached.bits().to_bitvec(),
+        }
+    }
+}
+
+impl Deref for DataBits {
+    type Target = BitSlice<u8, Msb0>;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            DataBits::Owned(bv) => bv,
+            DataBits::Mapped(cached) => cached.bits(),
+        }
+    }
+}
+
+impl From<BitVec<u8, Msb0>> for DataBits {
+    fn from(bv: BitVec<u8, Msb0>) -> Self {
+        DataBits::Owned(bv)
+    }
+}
+
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+fn hash_file(hasher: &mut Fnv1a, path: &Path) -> Result<(), Box<dyn Error>> {
+    // One streaming pass over the contents
+    let mut reader = File::open(path)?;
+    let mut buffer = vec![0u8; 1 << 16];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+pub fn source_digest(cfg: &Configuration, split: &DataSplit) -> Result<u64, Box<dyn Error>> {
+    // Hash of the files the split is encoded from
+    let mut hasher = Fnv1a::new();
+    hasher.update(&CACHE_VERSION.to_le_bytes());
+    hash_file(
+        &mut hasher,
+        Path::new(source_filepath(&cfg.data, split.source())),
+    )?;
+    if matches!(cfg.data.encoding, Encoding::Quantile { .. }) {
+        // Quantile thresholds are fitted on the train file, whatever the split
+        hash_file(&mut hasher, Path::new(&cfg.data.train_filepath))?;
+    }
+    Ok(hasher.0)
+}
+
+pub fn cache_key(cfg: &Configuration, split: &DataSplit) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.update(&CACHE_VERSION.to_le_bytes());
+    let params = format!(
+        "{}|{}|{:?}|{:?}|{:?}|{}x{}|{}|{}",
+        source_filepath(&cfg.data, split.source()),
+        cfg.data.train_filepath,
+        split,
+        cfg.data.encoding,
+        cfg.data.tabular.as_ref().map(|t| &t.encoder),
+        cfg.data.dim2,
+        cfg.data.dim3,
+        cfg.data.train_rows,
+        cfg.data.test_rows
+    );
+    hasher.update(params.as_bytes());
+    hasher.0
+}
+
+pub fn load_or_build(
+    cfg: &Configuration,
+    split: &DataSplit,
+    build: impl FnOnce() -> Result<(BitVec<u8, Msb0>, Vec<usize>), Box<dyn Error>>,
+) -> Result<(DataBits, Vec<usize>), Box<dyn Error>> {
+    // Without a cache_dir in the settings this just calls build
+    let Some(cache_dir) = &cfg.data.cache_dir else {
+        let (databits, labels) = build()?;
+        return Ok((DataBits::Owned(databits), labels));
+    };
+    let key = cache_key(cfg, split);
+    let digest = source_digest(cfg, split)?;
+    let path = Path::new(cache_dir).join(format!("{:016x}.bin", key));
+
+    if let Some(cached) = CachedSplit::open(&path, key, digest)? {
+        println!("Loaded cached data from {}", path.display());
+        let labels = cached.labels();
+        return Ok((DataBits::Mapped(cached), labels));
+    }
+    let (databits, labels) = build()?;
+    // A failed write only costs the next run its startup time, so don't fail the load over it
+    match write_cache(
+        &path,
+        key,
+        digest,
+        &databits,
+        &labels,
+        cfg.derived.img_bitcount,
+    ) {
+        Ok(()) => println!("Data cache written to {}", path.display()),
+        Err(e) => eprintln!("Could not write data cache {}: {}", path.display(), e),
+    }
+    Ok((DataBits::Owned(databits), labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_round_trips_bits_and_labels() {
+        let path =
+            std::env::temp_dir().join(format!("lutnets_cache_test_{}.bin", std::process::id()));
+        let databits = bitvec![u8, Msb0; 1,0,1, 1,1,0, 0,0,1];
+        let labels = vec![3, 7, 9];
+        write_cache(&path, 42, 7, &databits, &labels, 3).unwrap();
+
+        let cached = CachedSplit::open(&path, 42, 7).unwrap().unwrap();
+        assert_eq!(cached.labels(), labels);
+        assert_eq!(cached.bits(), databits.as_bitslice());
+        // A different key means the split changed, a different digest that its source did
+        assert!(CachedSplit::open(&path, 43, 7).unwrap().is_none());
+        assert!(CachedSplit::open(&path, 42, 8).unwrap().is_none());
+
+        // Truncated files are treated as missing
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(CachedSplit::open(&path, 42, 7).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,88 +1,191 @@
-use crate::settings::*;
+use crate::{
+    augmentation::AugmentedBatches, binarization::PixelEncoder, datacache::*, sampler::*,
+    settings::*, splits::*, streaming::StreamingBatches, synthetic::load_synthetic_split,
+    tabular::load_tabular_split,
+};
 use bitvec::prelude::*;
 use csv::ReaderBuilder;
 use std::error::Error;
-use std::{fs::File, path::PathBuf};
+use std::fs::File;
 
-pub fn csv_to_bitvec(
+// Input bits of every row followed by the labels, as the loaders return a split
+pub type LoadedSplit = (BitVec<u8, Msb0>, Vec<usize>);
+
+pub fn csv_to_bitvec(cfg: &Configuration) -> Result<LoadedSplit, Box<dyn Error>> {
+    load_split(cfg, &cfg.data.datasplit)
+}
+
+pub fn load_split(cfg: &Configuration, split: &DataSplit) -> Result<LoadedSplit, Box<dyn Error>> {
+    // Any split can be loaded with any config, so several splits can live in one process
+    let (databits, labels) = load_split_cached(cfg, split)?;
+    Ok((databits.into_bitvec(), labels))
+}
+
+pub fn load_split_cached(
     cfg: &Configuration,
-) -> Result<(BitVec<u8, Msb0>, Vec<usize>), Box<dyn Error>> {
-    let (relative_filepath, rows, start_row) = match cfg.data.datasplit {
-        // We are going to treat last 10k train rows as validation set
-        DataSplit::Train(r) => {
-            if r == 0 || r > 50000 {
-                return Err("Train rows must be between 1 and 50000".into());
-            }
-            (cfg.data.train_filepath.clone(), r as usize, 0)
-        }
-        DataSplit::Val(r) => {
-            if r == 0 || r > 10000 {
-                return Err("Validation rows must be between 1 and 10000".into());
-            }
-            (cfg.data.train_filepath.clone(), r as usize, 50000)
+    split: &DataSplit,
+) -> Result<(DataBits, Vec<usize>), Box<dyn Error>> {
+    // Like load_split, but a cache hit stays memory mapped instead of being copied
+    if let Some(synthetic) = &cfg.data.synthetic {
+        // Generating is faster than reading a cache, so synthetic data skips it
+        let (databits, labels) = load_synthetic_split(cfg, synthetic, split)?;
+        return Ok((databits.into(), labels));
+    }
+    load_or_build(cfg, split, || {
+        if cfg.data.tabular.is_some() {
+            return load_tabular_split(cfg, split);
         }
-        DataSplit::Test(r) => {
-            if r == 0 || r > 10000 {
-                return Err("Test rows must be between 1 and 10000".into());
-            }
-            (cfg.data.test_filepath.clone(), r as usize, 0)
+        let (pixels, labels) = load_split_pixels(cfg, split)?;
+        let encoder = PixelEncoder::from_cfg(cfg)?;
+        Ok((encoder.encode(&pixels, cfg.derived.cols), labels))
+    })
+}
+
+pub fn training_batches(cfg: &Configuration) -> Result<Box<dyn BatchSource>, Box<dyn Error>> {
+    // Batches of the configured datasplit for the trainers, augmented on the fly if the settings ask for it.
+    // Augmentation works on pixels, so it always reads the CSV rather than the bit cache, and synthetic or tabular data skips it.
+    match &cfg.data.augmentation {
+        Some(params)
+            if cfg.data.streaming.is_none()
+                && cfg.data.synthetic.is_none()
+                && cfg.data.tabular.is_none() =>
+        {
+            let (pixels, labels) = load_split_pixels(cfg, &cfg.data.datasplit)?;
+            let encoder = PixelEncoder::from_cfg(cfg)?;
+            Ok(Box::new(AugmentedBatches::new(
+                cfg,
+                pixels,
+                labels,
+                encoder,
+                params.clone(),
+            )))
         }
+        _ => split_batches(cfg),
+    }
+}
+
+pub fn split_batches(cfg: &Configuration) -> Result<Box<dyn BatchSource>, Box<dyn Error>> {
+    // Batches of the configured datasplit, read from the CSV a batch at a time when streaming is set and held in
+    // memory (or memory mapped from the cache) otherwise. Streamed batches are augmented if the settings ask for it.
+    if let Some(params) = &cfg.data.streaming {
+        return Ok(Box::new(StreamingBatches::new(cfg, params)?));
+    }
+    let (databits, labels) = load_split_cached(cfg, &cfg.data.datasplit)?;
+    Ok(Box::new(InMemoryBatches::new(cfg, databits, labels)))
+}
+
+pub fn load_split_pixels(
+    cfg: &Configuration,
+    split: &DataSplit,
+) -> Result<(Vec<u8>, Vec<usize>), Box<dyn Error>> {
+    split.validate(&cfg.data)?;
+    let filepath = source_filepath(&cfg.data, split.source());
+    let source_labels = if split.is_stratified() {
+        Some(read_csv_labels(filepath)?)
+    } else {
+        None
     };
-    let cols = cfg.derived.cols;
-    let mut data_filepath = PathBuf::from(crate::HOME_DIR);
-    data_filepath.push(relative_filepath);
+    let rows = split.rows(&cfg.data, source_labels.as_deref());
+    read_csv_rows(cfg, filepath, &rows)
+}
 
-    let file = File::open(data_filepath)?;
-    let mut rdr = ReaderBuilder::new().has_headers(true).from_reader(file);
-    let mut bit_vector = BitVec::new();
-    let mut labels = Vec::with_capacity(rows);
-    let mut records_iter = rdr.records();
+pub fn read_csv_pixels(
+    cfg: &Configuration,
+    filepath: &str,
+    start_row: usize,
+    rows: usize,
+) -> Result<(Vec<u8>, Vec<usize>), Box<dyn Error>> {
+    // Reads the raw 8-bit pixels of rows start_row..start_row + rows, laid out back to back, before any binarization
+    let row_indices: Vec<usize> = (start_row..start_row + rows).collect();
+    read_csv_rows(cfg, filepath, &row_indices)
+}
 
-    // Skip rows if necessary
-    for _ in 0..start_row {
-        if records_iter.next().is_none() {
-            return Err(format!(
-                "Expected to skip {} rows, but the file ended before that.",
-                start_row
-            )
-            .into());
-        }
+pub fn open_csv(filepath: &str) -> Result<csv::Reader<File>, Box<dyn Error>> {
+    let file = File::open(filepath)?;
+    Ok(ReaderBuilder::new().has_headers(true).from_reader(file))
+}
+
+pub fn read_csv_labels(filepath: &str) -> Result<Vec<usize>, Box<dyn Error>> {
+    // Labels of every row in the file, needed to stratify splits
+    let mut rdr = open_csv(filepath)?;
+    let mut labels = Vec::new();
+    for record in rdr.records() {
+        let record = record?;
+        let label_field = record.get(0).ok_or("Found an empty row")?;
+        labels.push(label_field.trim().parse()?);
     }
+    Ok(labels)
+}
 
-    for i in 0..rows {
-        match records_iter.next() {
-            Some(Ok(record)) => {
-                if record.len() != cols + 1 {
-                    return Err(format!(
-                        "Row {} has {} columns, but {} were expected.",
-                        i + 1,
-                        record.len(),
-                        cols + 1
-                    )
-                    .into());
-                }
+pub fn read_csv_rows(
+    cfg: &Configuration,
+    filepath: &str,
+    row_indices: &[usize],
+) -> Result<(Vec<u8>, Vec<usize>), Box<dyn Error>> {
+    // Reads the given rows in the given order, in a single pass over the file
+    let cols = cfg.derived.cols;
+    let rows = row_indices.len();
+    let Some(&last_row) = row_indices.iter().max() else {
+        return Ok((Vec::new(), Vec::new()));
+    };
+    let mut position_of_row = vec![usize::MAX; last_row + 1];
+    for (position, &row) in row_indices.iter().enumerate() {
+        position_of_row[row] = position;
+    }
 
-                let mut fields = record.iter();
-                let first_field = fields.next().unwrap();
-                let label_value: usize = first_field.trim().parse()?;
-                labels.push(label_value);
+    let mut rdr = open_csv(filepath)?;
+    let mut pixels = vec![0u8; rows * cols];
+    let mut labels = vec![0usize; rows];
+    let mut records_iter = rdr.records();
 
-                for field in fields {
-                    let value: u8 = field.trim().parse()?;
-                    bit_vector.extend_from_bitslice(value.view_bits::<Msb0>());
+    for (i, &position) in position_of_row.iter().enumerate() {
+        match records_iter.next() {
+            Some(Ok(record)) => {
+                if position == usize::MAX {
+                    continue;
                 }
+                labels[position] = parse_record(
+                    &record,
+                    i,
+                    &mut pixels[position * cols..(position + 1) * cols],
+                )?;
             }
             Some(Err(e)) => return Err(e.into()),
             None => {
                 return Err(format!(
-                    "Expected to read {} rows, but the file ended after {} rows.",
-                    rows, i
+                    "Expected to read up to row {}, but the file ended after {} rows.",
+                    last_row + 1,
+                    i
                 )
                 .into());
             }
         }
     }
-    Ok((bit_vector, labels))
+    Ok((pixels, labels))
+}
+
+pub fn parse_record(
+    record: &csv::StringRecord,
+    row: usize,
+    pixels: &mut [u8],
+) -> Result<usize, Box<dyn Error>> {
+    // Writes the pixels of one label-first CSV record and returns its label
+    if record.len() != pixels.len() + 1 {
+        return Err(format!(
+            "Row {} has {} columns, but {} were expected.",
+            row + 1,
+            record.len(),
+            pixels.len() + 1
+        )
+        .into());
+    }
+    let mut fields = record.iter();
+    let first_field = fields.next().unwrap();
+    let label = first_field.trim().parse()?;
+    for (pixel, field) in pixels.iter_mut().zip(fields) {
+        *pixel = field.trim().parse()?;
+    }
+    Ok(label)
 }
 
 #[cfg(test)]
@@ -0,0 +1,298 @@
+use crate::settings::*;
+use bitvec::prelude::*;
+use std::cmp::Reverse;
+
+// Output heads. A decoder turns one sample's output bits into a class, and gives every class the output bits it
+// is trained towards (its target). The loss of a head counts what stands between the outputs and a right decode,
+// so changing the head in [decoder] changes what every trainer optimizes.
+
+// Label of a prediction the Margin head is not confident about. Never equal to a true label, so it counts as wrong.
+pub const REJECTED: usize = usize::MAX;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankedClass {
+    pub class: usize,
+    pub distance: u32,   // Distance from the outputs to the class's target
+    pub confidence: f32, // Softmax of minus the distance: every bit further away makes a class e times less likely
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankedPrediction {
+    pub ranked: Vec<RankedClass>, // Nearest first, ties in class order. May be cut to the top k.
+    pub margin: u32,              // Distance of the second choice minus distance of the first
+}
+
+impl RankedPrediction {
+    pub fn top(&self) -> &RankedClass {
+        &self.ranked[0]
+    }
+
+    pub fn rank_of(&self, class: usize) -> Option<usize> {
+        self.ranked.iter().position(|r| r.class == class)
+    }
+
+    pub fn is_uncertain(&self, min_margin: u32) -> bool {
+        self.margin < min_margin
+    }
+}
+
+impl Decoder {
+    pub fn validate(&self, cfg: &Configuration) -> Result<(), String> {
+        let bits = cfg.derived.output_bitsize;
+        match self {
+            Decoder::GroupVote if bits < cfg.network.output_embedding.len() => Err(format!(
+                "GroupVote needs at least one of the {} output bits per class, there are {} classes",
+                bits,
+                cfg.network.output_embedding.len()
+            )),
+            Decoder::Weighted { bit_weights } if bit_weights.len() != bits => Err(format!(
+                "Weighted has {} bit_weights for {} output bits",
+                bit_weights.len(),
+                bits
+            )),
+            Decoder::Weighted { bit_weights } if bit_weights.iter().all(|&w| w == 0) => {
+                Err("Weighted needs a non-zero bit weight".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn targets(&self, cfg: &Configuration) -> Vec<usize> {
+        // Output bits (as a codeword) each class is trained towards
+        match self {
+            Decoder::GroupVote => {
+                // All ones in the class's group and zeros elsewhere. Bits left over after the groups are trained to 0.
+                let bits = cfg.derived.output_bitsize;
+                let group = group_size(cfg);
+                (0..cfg.network.output_embedding.len())
+                    .map(|class| {
+                        let ones = usize::MAX >> (usize::BITS as usize - group);
+                        ones << (bits - (class + 1) * group)
+                    })
+                    .collect()
+            }
+            _ => cfg.network.output_embedding.clone(),
+        }
+    }
+
+    pub fn decode(&self, cfg: &Configuration, outputs: &BitSlice<u8, Msb0>) -> usize {
+        // One sample's output bits to a class, or REJECTED
+        match self {
+            Decoder::GroupVote => {
+                let group = group_size(cfg);
+                (0..cfg.network.output_embedding.len())
+                    .min_by_key(|&class| {
+                        Reverse(outputs[class * group..(class + 1) * group].count_ones())
+                    })
+                    .unwrap()
+            }
+            Decoder::Margin { min_margin } => {
+                let ((class, nearest), runner_up) = nearest_two(self, cfg, outputs);
+                if runner_up - nearest < *min_margin {
+                    REJECTED
+                } else {
+                    class
+                }
+            }
+            _ => nearest_two(self, cfg, outputs).0.0,
+        }
+    }
+
+    pub fn rank(&self, targets: &[usize], outputs: &BitSlice<u8, Msb0>) -> RankedPrediction {
+        // Every class ordered by distance to its target. The top class is what Nearest and Weighted decode to,
+        // GroupVote counts ones instead and Margin may reject it.
+        let mut ranked: Vec<RankedClass> = targets
+            .iter()
+            .enumerate()
+            .map(|(class, &target)| RankedClass {
+                class,
+                distance: self.distance(outputs, target),
+                confidence: 0.0,
+            })
+            .collect();
+        ranked.sort_by_key(|r| r.distance);
+        let nearest = ranked[0].distance;
+        let weight = |r: &RankedClass| (-((r.distance - nearest) as f32)).exp();
+        let total: f32 = ranked.iter().map(weight).sum();
+        for r in ranked.iter_mut() {
+            r.confidence = weight(r) / total;
+        }
+        let margin = ranked.get(1).map_or(0, |r| r.distance - nearest);
+        RankedPrediction { ranked, margin }
+    }
+
+    pub fn distance(&self, outputs: &BitSlice<u8, Msb0>, codeword: usize) -> u32 {
+        // Distance from the outputs to a codeword, weighted for the Weighted head
+        let wrong = outputs.load_be::<usize>() ^ codeword;
+        match self {
+            Decoder::Weighted { bit_weights } => {
+                let bits = outputs.len();
+                bit_weights
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| wrong >> (bits - 1 - i) & 1 == 1)
+                    .map(|(_, &w)| w)
+                    .sum()
+            }
+            _ => wrong.count_ones(),
+        }
+    }
+
+    pub fn loss(
+        &self,
+        cfg: &Configuration,
+        outputs: &BitSlice<u8, Msb0>,
+        wrong_bits: &BitSlice<u8, Msb0>,
+        labels: &[usize],
+    ) -> usize {
+        // wrong_bits are the outputs XOR the targets of the true labels, see processing::get_loss_vec
+        let bits = cfg.derived.output_bitsize;
+        match self {
+            Decoder::Nearest | Decoder::GroupVote => wrong_bits.count_ones(),
+            _ => outputs
+                .chunks_exact(bits)
+                .zip(wrong_bits.chunks_exact(bits))
+                .zip(labels)
+                .map(|((sample, wrong), &label)| self.sample_loss(cfg, sample, wrong, label))
+                .sum(),
+        }
+    }
+
+    pub fn sample_loss(
+        &self,
+        cfg: &Configuration,
+        sample: &BitSlice<u8, Msb0>,
+        wrong: &BitSlice<u8, Msb0>,
+        label: usize,
+    ) -> usize {
+        match self {
+            Decoder::Nearest | Decoder::GroupVote => wrong.count_ones(),
+            Decoder::Weighted { bit_weights } => {
+                wrong.iter_ones().map(|i| bit_weights[i] as usize).sum()
+            }
+            Decoder::Margin { min_margin } => {
+                // Wrong bits, plus how far the sample is from beating its nearest wrong codeword by min_margin
+                let (to_true, to_wrong) =
+                    self.true_and_nearest_wrong(&cfg.network.output_embedding, sample, label);
+                wrong.count_ones() + (to_true + min_margin).saturating_sub(to_wrong) as usize
+            }
+        }
+    }
+
+    pub fn true_and_nearest_wrong(
+        &self,
+        targets: &[usize],
+        sample: &BitSlice<u8, Msb0>,
+        label: usize,
+    ) -> (u32, u32) {
+        // Distance to the target of the true class, and to the nearest target of any other class
+        let to_wrong = targets
+            .iter()
+            .enumerate()
+            .filter(|&(class, _)| class != label)
+            .map(|(_, &codeword)| self.distance(sample, codeword))
+            .min()
+            .unwrap_or(u32::MAX);
+        (self.distance(sample, targets[label]), to_wrong)
+    }
+}
+
+fn group_size(cfg: &Configuration) -> usize {
+    cfg.derived.output_bitsize / cfg.network.output_embedding.len()
+}
+
+fn nearest_two(
+    decoder: &Decoder,
+    cfg: &Configuration,
+    outputs: &BitSlice<u8, Msb0>,
+) -> ((usize, u32), u32) {
+    // Nearest class with its distance, and the distance of the runner up. Ties go to the lowest class.
+    let mut nearest = (0, u32::MAX);
+    let mut runner_up = u32::MAX;
+    for (class, &codeword) in cfg.network.output_embedding.iter().enumerate() {
+        let distance = decoder.distance(outputs, codeword);
+        if distance < nearest.1 {
+            runner_up = nearest.1;
+            nearest = (class, distance);
+        } else if distance < runner_up {
+            runner_up = distance;
+        }
+    }
+    (nearest, runner_up)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoder_cfg(decoder: Decoder, output_embedding: Vec<usize>, bits: usize) -> Configuration {
+        let mut cfg = initialize_app_config_with_network(None);
+        cfg.network.output_embedding = output_embedding;
+        cfg.network.layer_sizes = vec![64, bits];
+        cfg.data.batch_size = 2;
+        cfg.derived = DerivedValues::new(&cfg.data, &cfg.network);
+        cfg.decoder = decoder;
+        cfg
+    }
+
+    #[test]
+    fn heads_decode_and_score_outputs() {
+        let outputs = bitvec![u8, Msb0; 1, 1, 0, 0, 0, 1, /**/ 0, 0, 1, 1, 1, 1];
+
+        // Three groups of two bits: the second sample votes 0, 2, 2
+        let cfg = decoder_cfg(Decoder::GroupVote, vec![0, 1, 2], 6);
+        assert!(cfg.decoder.validate(&cfg).is_ok());
+        assert_eq!(
+            cfg.decoder.targets(&cfg),
+            vec![0b110000, 0b001100, 0b000011]
+        );
+        assert_eq!(cfg.decoder.decode(&cfg, &outputs[..6]), 0);
+        assert_eq!(cfg.decoder.decode(&cfg, &outputs[6..]), 1);
+
+        // 110001 is 1 bit away from 110000 and 3 from 000011, but the last bit weighs 4
+        let weighted = Decoder::Weighted {
+            bit_weights: vec![1, 1, 1, 1, 1, 4],
+        };
+        let cfg = decoder_cfg(weighted, vec![0b110000, 0b000011], 6);
+        assert_eq!(cfg.decoder.decode(&cfg, &outputs[..6]), 1);
+        let wrong = bits![u8, Msb0; 0, 0, 0, 0, 1, 1, /**/ 1, 0, 0, 0, 0, 0];
+        assert_eq!(cfg.decoder.loss(&cfg, &outputs, wrong, &[0, 1]), 6);
+
+        // 001111 is 2 away from both codewords: rejected, and the hinge adds the missing margin of 1
+        let cfg = decoder_cfg(
+            Decoder::Margin { min_margin: 1 },
+            vec![0b001100, 0b000011],
+            6,
+        );
+        assert_eq!(cfg.decoder.decode(&cfg, &outputs[6..]), REJECTED);
+        assert_eq!(cfg.decoder.decode(&cfg, &outputs[..6]), 1);
+        let wrong = bits![u8, Msb0; 0; 12];
+        assert_eq!(cfg.decoder.loss(&cfg, &outputs[6..], &wrong[6..], &[1]), 1);
+        assert!(
+            Decoder::Weighted {
+                bit_weights: vec![1]
+            }
+            .validate(&cfg)
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn classes_are_ranked_by_distance() {
+        let outputs = bits![u8, Msb0; 1, 1, 0, 1];
+        let prediction = Decoder::Nearest.rank(&[0b0011, 0b1100, 0b1111, 0b1101], outputs);
+        let order: Vec<(usize, u32)> = prediction
+            .ranked
+            .iter()
+            .map(|r| (r.class, r.distance))
+            .collect();
+        assert_eq!(order, vec![(3, 0), (1, 1), (2, 1), (0, 3)]);
+        assert_eq!(prediction.margin, 1);
+        assert_eq!(prediction.rank_of(2), Some(2));
+        assert!(prediction.is_uncertain(2) && !prediction.is_uncertain(1));
+        let total: f32 = prediction.ranked.iter().map(|r| r.confidence).sum();
+        assert!((total - 1.0).abs() < 1e-6);
+        let ratio = prediction.top().confidence / prediction.ranked[1].confidence;
+        assert!((ratio - std::f32::consts::E).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,533 @@
+use crate::{
+    dataloader::split_batches, decoding::REJECTED, netcore::LUTNet, processing::*, settings::*,
+};
+use bitvec::prelude::*;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::PathBuf;
+
+// Evaluates a model over a whole split, chunk_rows samples per forward pass so large networks and splits fit in
+// memory. Image splits are streamed from the CSV a chunk at a time, so the split itself is never held whole. Every
+// sample keeps its prediction and how clear it was: the distance from the outputs to the nearest class target and
+// the margin to the runner up. The report summarizes them as a confusion matrix, per-class precision/recall/F1,
+// distance and margin histograms, accuracy when rejecting small margins, and the worst mistakes.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleResult {
+    pub index: usize,       // Position in the split
+    pub label: usize,       // True class
+    pub predicted: usize,   // Decoded class, REJECTED if the decoder rejected it
+    pub distance: u32,      // Distance to the nearest class target
+    pub margin: u32,        // Distance to the runner up minus distance to the nearest
+    pub true_distance: u32, // Distance to the true class's target
+    pub true_rank: usize,   // Position of the true class in the ranking, 0 = nearest
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassMetrics {
+    pub support: usize,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejectPoint {
+    pub min_margin: u32, // Samples with a smaller margin are rejected
+    pub coverage: f64,   // Share of samples kept
+    pub accuracy: f64,   // Accuracy on the kept samples
+}
+
+#[derive(Debug, Clone)]
+pub struct EvaluationReport {
+    pub split: String,
+    pub loss: usize,
+    pub confusion: Vec<Vec<usize>>, // confusion[true][predicted], the last column counts rejected samples
+    pub samples: Vec<SampleResult>,
+    pub worst: usize, // Number of worst misclassified samples listed
+}
+
+pub fn evaluation_cfg(cfg: &Configuration, split: &DataSplit, chunk_rows: usize) -> Configuration {
+    // The split in batches of chunk_rows in file order, the last smaller one padded, without augmentation.
+    // Image CSVs are streamed, generated and tabular splits are small enough to load.
+    let chunk_rows = chunk_rows.clamp(1, split.num_rows(&cfg.data).max(1));
+    let mut split_cfg = cfg.clone();
+    split_cfg.data.sampling = Sampling {
+        remainder: Remainder::Pad,
+        ..Default::default()
+    };
+    split_cfg.data.augmentation = None;
+    if split_cfg.data.synthetic.is_none() && split_cfg.data.tabular.is_none() {
+        split_cfg.data.streaming = Some(Streaming {
+            prefetch_batches: 2,
+            shuffle_window: 1,
+        });
+    }
+    split_cfg.for_split(split.clone(), Some(chunk_rows))
+}
+
+pub fn evaluate(ltnet: &LUTNet, cfg: &Configuration) -> Result<EvaluationReport, Box<dyn Error>> {
+    // cfg holds the split and the chunk size, see evaluation_cfg
+    let split = &cfg.data.datasplit;
+    let mut batches = split_batches(cfg)?;
+    let num_rows = batches.num_rows();
+    let chunk_rows = cfg.data.batch_size;
+    let targets = cfg.decoder.targets(cfg);
+    let img_bitcount = cfg.derived.img_bitcount;
+    let mut samples = Vec::with_capacity(num_rows);
+    let mut loss = 0;
+    let mut batch_dbv = bitvec![u8, Msb0; 0; cfg.derived.batch_bitcount];
+    batches.start_epoch(0);
+    for batch_num in 0..batches.num_batches() {
+        let start = batch_num * chunk_rows;
+        let labels = batches.load_batch(batch_num, &mut batch_dbv);
+        // Only the last chunk can be smaller, its padding rows are left out
+        let rows = chunk_rows.min(num_rows - start);
+        let chunk_cfg = cfg.for_split(split.clone(), Some(rows));
+        let mut dbv = bitvec![u8, Msb0; 0; chunk_cfg.derived.bitvec_size];
+        dbv[..rows * img_bitcount].copy_from_bitslice(&batch_dbv[..rows * img_bitcount]);
+        ltnet.apply_gates(&chunk_cfg, &mut dbv);
+
+        let y = &labels[..rows];
+        loss += get_loss(&chunk_cfg, &dbv, y);
+        let predicted = get_labels(&chunk_cfg, &dbv);
+        let outputs = get_outputs(&chunk_cfg, &dbv);
+        for (i, sample) in outputs.chunks_exact(cfg.derived.output_bitsize).enumerate() {
+            let prediction = cfg.decoder.rank(&targets, sample);
+            let true_rank = prediction.rank_of(y[i]).unwrap();
+            samples.push(SampleResult {
+                index: start + i,
+                label: y[i],
+                predicted: predicted[i],
+                distance: prediction.top().distance,
+                margin: prediction.margin,
+                true_distance: prediction.ranked[true_rank].distance,
+                true_rank,
+            });
+        }
+    }
+    Ok(EvaluationReport::from_samples(
+        format!("{:?}", split),
+        targets.len(),
+        loss,
+        samples,
+    ))
+}
+
+impl EvaluationReport {
+    pub fn from_samples(
+        split: String,
+        classes: usize,
+        loss: usize,
+        samples: Vec<SampleResult>,
+    ) -> Self {
+        let mut confusion = vec![vec![0; classes + 1]; classes];
+        for sample in &samples {
+            let column = if sample.predicted == REJECTED {
+                classes
+            } else {
+                sample.predicted
+            };
+            confusion[sample.label][column] += 1;
+        }
+        EvaluationReport {
+            split,
+            loss,
+            confusion,
+            samples,
+            worst: 20,
+        }
+    }
+
+    pub fn classes(&self) -> usize {
+        self.confusion.len()
+    }
+
+    pub fn accuracy(&self) -> f64 {
+        let correct = (0..self.classes())
+            .map(|c| self.confusion[c][c])
+            .sum::<usize>();
+        ratio(correct, self.samples.len())
+    }
+
+    pub fn top_k_accuracy(&self, k: usize) -> f64 {
+        // True class among the k nearest targets
+        let hits = self.samples.iter().filter(|s| s.true_rank < k).count();
+        ratio(hits, self.samples.len())
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.confusion.iter().map(|row| row[self.classes()]).sum()
+    }
+
+    pub fn class_metrics(&self) -> Vec<ClassMetrics> {
+        (0..self.classes())
+            .map(|class| {
+                let correct = self.confusion[class][class];
+                let support = self.confusion[class].iter().sum();
+                let predicted = self.confusion.iter().map(|row| row[class]).sum();
+                let (precision, recall) = (ratio(correct, predicted), ratio(correct, support));
+                let f1 = if precision + recall > 0.0 {
+                    2.0 * precision * recall / (precision + recall)
+                } else {
+                    0.0
+                };
+                ClassMetrics {
+                    support,
+                    precision,
+                    recall,
+                    f1,
+                }
+            })
+            .collect()
+    }
+
+    pub fn macro_f1(&self) -> f64 {
+        let metrics = self.class_metrics();
+        metrics.iter().map(|m| m.f1).sum::<f64>() / metrics.len().max(1) as f64
+    }
+
+    pub fn distance_histogram(&self) -> Vec<usize> {
+        histogram(self.samples.iter().map(|s| s.distance))
+    }
+
+    pub fn margin_histogram(&self) -> Vec<usize> {
+        histogram(self.samples.iter().map(|s| s.margin))
+    }
+
+    pub fn reject_curve(&self) -> Vec<RejectPoint> {
+        let max_margin = self.samples.iter().map(|s| s.margin).max().unwrap_or(0);
+        (0..=max_margin)
+            .map(|min_margin| {
+                let kept: Vec<&SampleResult> = self
+                    .samples
+                    .iter()
+                    .filter(|s| s.margin >= min_margin)
+                    .collect();
+                let correct = kept.iter().filter(|s| s.predicted == s.label).count();
+                RejectPoint {
+                    min_margin,
+                    coverage: ratio(kept.len(), self.samples.len()),
+                    accuracy: ratio(correct, kept.len()),
+                }
+            })
+            .collect()
+    }
+
+    pub fn worst_misclassified(&self) -> Vec<&SampleResult> {
+        // Wrong predictions, those whose true target is furthest behind the winner first
+        let mut wrong: Vec<&SampleResult> = self
+            .samples
+            .iter()
+            .filter(|s| s.predicted != s.label)
+            .collect();
+        wrong.sort_by_key(|s| (std::cmp::Reverse(s.true_distance - s.distance), s.index));
+        wrong.truncate(self.worst);
+        wrong
+    }
+
+    pub fn to_csv(&self) -> String {
+        // One row per true class: its metrics, then the confusion matrix row
+        let mut csv = String::from("class,support,precision,recall,f1");
+        for class in 0..self.classes() {
+            write!(csv, ",predicted_{}", class).unwrap();
+        }
+        csv.push_str(",rejected\n");
+        for (class, m) in self.class_metrics().iter().enumerate() {
+            write!(
+                csv,
+                "{},{},{:.4},{:.4},{:.4}",
+                class, m.support, m.precision, m.recall, m.f1
+            )
+            .unwrap();
+            for count in &self.confusion[class] {
+                write!(csv, ",{}", count).unwrap();
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    pub fn to_json(&self) -> String {
+        let list = |items: Vec<String>| format!("[{}]", items.join(", "));
+        let numbers = |values: &[usize]| list(values.iter().map(|v| v.to_string()).collect());
+        let predicted = |p: usize| {
+            if p == REJECTED {
+                "null".to_string()
+            } else {
+                p.to_string()
+            }
+        };
+        let fields = [
+            ("split", format!("\"{}\"", self.split.replace('"', "\\\""))),
+            ("samples", self.samples.len().to_string()),
+            ("accuracy", format!("{:.6}", self.accuracy())),
+            ("rejected", self.rejected().to_string()),
+            ("loss", self.loss.to_string()),
+            ("macro_f1", format!("{:.6}", self.macro_f1())),
+            (
+                "top_k_accuracy",
+                list(
+                    (1..=self.classes().min(5))
+                        .map(|k| format!("{:.6}", self.top_k_accuracy(k)))
+                        .collect(),
+                ),
+            ),
+            (
+                "confusion",
+                list(self.confusion.iter().map(|row| numbers(row)).collect()),
+            ),
+            (
+                "classes",
+                list(
+                    self.class_metrics()
+                        .iter()
+                        .enumerate()
+                        .map(|(class, m)| {
+                            format!(
+                                "{{\"class\": {}, \"support\": {}, \"precision\": {:.6}, \"recall\": {:.6}, \"f1\": {:.6}}}",
+                                class, m.support, m.precision, m.recall, m.f1
+                            )
+                        })
+                        .collect(),
+                ),
+            ),
+            ("distance_histogram", numbers(&self.distance_histogram())),
+            ("margin_histogram", numbers(&self.margin_histogram())),
+            (
+                "reject_curve",
+                list(
+                    self.reject_curve()
+                        .iter()
+                        .map(|p| {
+                            format!(
+                                "{{\"min_margin\": {}, \"coverage\": {:.6}, \"accuracy\": {:.6}}}",
+                                p.min_margin, p.coverage, p.accuracy
+                            )
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "worst_misclassified",
+                list(
+                    self.worst_misclassified()
+                        .iter()
+                        .map(|s| {
+                            format!(
+                                "{{\"index\": {}, \"label\": {}, \"predicted\": {}, \"distance\": {}, \"true_distance\": {}}}",
+                                s.index,
+                                s.label,
+                                predicted(s.predicted),
+                                s.distance,
+                                s.true_distance
+                            )
+                        })
+                        .collect(),
+                ),
+            ),
+        ];
+        let body: Vec<String> = fields
+            .iter()
+            .map(|(key, value)| format!("  \"{}\": {}", key, value))
+            .collect();
+        format!("{{\n{}\n}}\n", body.join(",\n"))
+    }
+
+    pub fn write_files(&self, prefix: &str) -> Result<(PathBuf, PathBuf), Box<dyn Error>> {
+        // <prefix>.csv with the per-class table and <prefix>.json with everything
+        let (csv_path, json_path) = (
+            PathBuf::from(format!("{}.csv", prefix)),
+            PathBuf::from(format!("{}.json", prefix)),
+        );
+        fs::write(&csv_path, self.to_csv())?;
+        fs::write(&json_path, self.to_json())?;
+        Ok((csv_path, json_path))
+    }
+}
+
+impl fmt::Display for EvaluationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let classes = self.classes();
+        writeln!(
+            f,
+            "Evaluation of {}: {} samples, accuracy {:.2}%, rejected {}, loss {}, macro F1 {:.4}",
+            self.split,
+            self.samples.len(),
+            self.accuracy() * 100.0,
+            self.rejected(),
+            self.loss,
+            self.macro_f1()
+        )?;
+        let top_k: Vec<String> = (1..=classes.min(5))
+            .map(|k| format!("top-{} {:.2}%", k, self.top_k_accuracy(k) * 100.0))
+            .collect();
+        writeln!(f, "Nearest target accuracy: {}", top_k.join(", "))?;
+
+        writeln!(
+            f,
+            "\nConfusion matrix (rows true, columns predicted, R rejected)"
+        )?;
+        write!(f, "{:>6}", "")?;
+        for class in 0..classes {
+            write!(f, "{:>7}", class)?;
+        }
+        writeln!(f, "{:>7}", "R")?;
+        for (class, row) in self.confusion.iter().enumerate() {
+            write!(f, "{:>6}", class)?;
+            for count in row {
+                write!(f, "{:>7}", count)?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(
+            f,
+            "\n{:>6}{:>9}{:>11}{:>9}{:>9}",
+            "class", "support", "precision", "recall", "f1"
+        )?;
+        for (class, m) in self.class_metrics().iter().enumerate() {
+            writeln!(
+                f,
+                "{:>6}{:>9}{:>11.4}{:>9.4}{:>9.4}",
+                class, m.support, m.precision, m.recall, m.f1
+            )?;
+        }
+
+        writeln!(f, "\n{:>10}{:>10}{:>10}", "distance", "winners", "margins")?;
+        let (distances, margins) = (self.distance_histogram(), self.margin_histogram());
+        for d in 0..distances.len().max(margins.len()) {
+            let count = |h: &[usize]| h.get(d).copied().unwrap_or(0);
+            writeln!(
+                f,
+                "{:>10}{:>10}{:>10}",
+                d,
+                count(&distances),
+                count(&margins)
+            )?;
+        }
+
+        writeln!(
+            f,
+            "\n{:>10}{:>10}{:>10}",
+            "min_margin", "coverage", "accuracy"
+        )?;
+        for p in self.reject_curve() {
+            writeln!(
+                f,
+                "{:>10}{:>9.2}%{:>9.2}%",
+                p.min_margin,
+                p.coverage * 100.0,
+                p.accuracy * 100.0
+            )?;
+        }
+
+        writeln!(f, "\nWorst misclassified samples")?;
+        writeln!(
+            f,
+            "{:>8}{:>7}{:>11}{:>10}{:>15}",
+            "index", "label", "predicted", "distance", "true_distance"
+        )?;
+        for s in self.worst_misclassified() {
+            let predicted = if s.predicted == REJECTED {
+                "R".to_string()
+            } else {
+                s.predicted.to_string()
+            };
+            writeln!(
+                f,
+                "{:>8}{:>7}{:>11}{:>10}{:>15}",
+                s.index, s.label, predicted, s.distance, s.true_distance
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn ratio(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+fn histogram(values: impl Iterator<Item = u32>) -> Vec<usize> {
+    let mut counts = Vec::new();
+    for value in values {
+        if counts.len() <= value as usize {
+            counts.resize(value as usize + 1, 0);
+        }
+        counts[value as usize] += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(
+        index: usize,
+        label: usize,
+        predicted: usize,
+        distance: u32,
+        margin: u32,
+    ) -> SampleResult {
+        let (true_distance, true_rank) = if label == predicted {
+            (distance, 0)
+        } else {
+            (distance + margin, 1)
+        };
+        SampleResult {
+            index,
+            label,
+            predicted,
+            distance,
+            margin,
+            true_distance,
+            true_rank,
+        }
+    }
+
+    #[test]
+    fn report_summarizes_samples() {
+        let samples = vec![
+            sample(0, 0, 0, 0, 4),
+            sample(1, 0, 1, 1, 2),
+            sample(2, 1, 1, 2, 1),
+            sample(3, 1, REJECTED, 1, 0),
+            sample(4, 1, 0, 2, 3),
+        ];
+        let report = EvaluationReport::from_samples("Test(5)".to_string(), 2, 9, samples);
+        assert_eq!(report.confusion, vec![vec![1, 1, 0], vec![1, 1, 1]]);
+        assert_eq!(report.accuracy(), 0.4);
+        assert_eq!(report.rejected(), 1);
+        assert_eq!(report.top_k_accuracy(2), 1.0);
+
+        let metrics = report.class_metrics();
+        assert_eq!(metrics[0].support, 2);
+        assert_eq!((metrics[0].precision, metrics[0].recall), (0.5, 0.5));
+        assert_eq!((metrics[1].precision, metrics[1].recall), (0.5, 1.0 / 3.0));
+
+        assert_eq!(report.distance_histogram(), vec![1, 2, 2]);
+        assert_eq!(report.margin_histogram(), vec![1, 1, 1, 1, 1]);
+        // Keeping margins >= 2 leaves samples 0, 1 and 4, of which only sample 0 is right
+        let at_2 = &report.reject_curve()[2];
+        assert_eq!((at_2.coverage, at_2.accuracy), (0.6, 1.0 / 3.0));
+        let worst: Vec<usize> = report
+            .worst_misclassified()
+            .iter()
+            .map(|s| s.index)
+            .collect();
+        assert_eq!(worst, vec![4, 1, 3]);
+
+        assert!(report.to_csv().starts_with(
+            "class,support,precision,recall,f1,predicted_0,predicted_1,rejected\n0,2,"
+        ));
+        let json = report.to_json();
+        assert!(json.contains("\"confusion\": [[1, 1, 0], [1, 1, 1]]"));
+        assert!(json.contains("\"predicted\": null"));
+    }
+}
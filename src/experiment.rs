@@ -0,0 +1,190 @@
+use crate::{
+    architectures::Architecture,
+    layered_config::*,
+    netcore::LUTNet,
+    sampler::BatchSource,
+    settings::*,
+    training_algos::{heads::check_heads, *},
+};
+use config::{ConfigError, Value};
+use std::error::Error;
+use std::str::FromStr;
+
+// An experiment file names everything a training run depends on: the data and its encoding ([data]), the
+// architecture and its parameters ([experiment] and [architectures.<name>]), the trainer and its
+// hyperparameters ([trainer]), the seed and the output model. run_experiment reads it as the --config file,
+// so it only needs the values that differ from the defaults.
+// The fully resolved file is embedded in the model (LUTNet::manifest), so the run can be repeated from the model
+// alone: run_experiment --show-manifest <model> prints it back.
+
+impl Manifest {
+    pub fn load() -> Result<Self, ConfigError> {
+        Value::new(None, resolved_table(None)?).try_deserialize()
+    }
+}
+
+pub fn resolved_manifest(manifest: &Manifest) -> Result<String, Box<dyn Error>> {
+    // The experiment file with every layer applied, as stored in the model
+    let arch = Architecture::from_str(&manifest.experiment.architecture)?;
+    let root = resolved_table(arch.default_settings().map(|d| (arch.name(), d)))?;
+    let header = format!(
+        "# Resolved settings of experiment {}. Save them to a file and repeat the run with\n# cargo run --release --bin run_experiment -- <file> --set experiment.model=<new model>\n\n",
+        manifest.experiment.name
+    );
+    Ok(header + to_toml(&root).trim_start())
+}
+
+impl Trainer {
+    pub fn validate(&self, cfg: &Configuration) -> Result<(), String> {
+        let err = |msg: &str| Err(format!("Invalid trainer {:?}: {}", self, msg));
+        match *self {
+            Trainer::NaiveEvolutionary {
+                corruption_ratio,
+                lut_sampling_depth,
+                ..
+            }
+            | Trainer::BitflipEvolutionary {
+                corruption_ratio,
+                lut_sampling_depth,
+                ..
+            } if !(corruption_ratio > 0.0 && corruption_ratio <= 1.0)
+                || lut_sampling_depth == 0 =>
+            {
+                err("need 0 < corruption_ratio <= 1 and a non-zero lut_sampling_depth")
+            }
+            Trainer::NaiveEvolutionary {
+                rewire_probability, ..
+            }
+            | Trainer::BitflipEvolutionary {
+                rewire_probability, ..
+            } if !(0.0..=1.0).contains(&rewire_probability) => {
+                err("rewire_probability must be between 0 and 1")
+            }
+            Trainer::NaiveEvolutionary { heads, .. }
+            | Trainer::BitflipEvolutionary { heads, .. }
+                if check_heads(cfg, heads).is_err() =>
+            {
+                err("heads must divide the output bits evenly")
+            }
+            Trainer::BitflipEvolutionary { .. } if cfg.network.lut_bank_size > 0 => {
+                err("bit flips can't keep LUTs inside a lut bank, set lut_bank_size = 0")
+            }
+            Trainer::PivNodeFlipper { write_freq: 0, .. } => err("write_freq must be non-zero"),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn with_epochs(&self, epochs: usize) -> Trainer {
+        // Same trainer and hyperparameters for another budget
+        let mut trainer = self.clone();
+        match &mut trainer {
+            Trainer::NaiveEvolutionary { epochs: e, .. }
+            | Trainer::BitflipEvolutionary { epochs: e, .. }
+            | Trainer::PivNodeFlipper { epochs: e, .. } => *e = epochs,
+        }
+        trainer
+    }
+
+    pub fn train(
+        &self,
+        ltnet: &mut LUTNet,
+        cfg: &Configuration,
+        data: &mut dyn BatchSource,
+        model_filename: &str,
+    ) {
+        match *self {
+            Trainer::NaiveEvolutionary {
+                corruption_ratio,
+                lut_sampling_depth,
+                rewire_probability,
+                heads,
+                epochs,
+            } => naive_evolutionary::trainer::train(
+                ltnet,
+                cfg,
+                data,
+                TrainParams {
+                    corruption_ratio,
+                    lut_sampling_depth,
+                    rewire_probability,
+                    heads,
+                    epochs,
+                },
+                model_filename,
+            ),
+            Trainer::BitflipEvolutionary {
+                corruption_ratio,
+                lut_sampling_depth,
+                rewire_probability,
+                heads,
+                epochs,
+            } => bitflip_evolutionary::trainer::train(
+                ltnet,
+                cfg,
+                data,
+                TrainParams {
+                    corruption_ratio,
+                    lut_sampling_depth,
+                    rewire_probability,
+                    heads,
+                    epochs,
+                },
+                model_filename,
+            ),
+            Trainer::PivNodeFlipper { epochs, write_freq } => piv_node_flipper::trainer::train(
+                ltnet,
+                cfg,
+                data,
+                epochs,
+                write_freq,
+                model_filename,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    #[test]
+    fn manifest_sections_are_read_and_overridden() {
+        let dir = std::env::temp_dir().join(format!("lutnets_experiment_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run.toml");
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(
+            file,
+            "[experiment]\nname = \"test\"\narchitecture = \"cnn_iv0\"\nseed = 7\nmodel = \"models/test\"\n\n\
+             [trainer]\nalgorithm = \"NaiveEvolutionary\"\ncorruption_ratio = 0.01\nlut_sampling_depth = 20\nepochs = 100"
+        )
+        .unwrap();
+        let sources = ConfigSources {
+            config_file: Some(path),
+            overrides: vec!["trainer.epochs=5".to_string()],
+        };
+        let root = layered_table(&sources, std::iter::empty()).unwrap();
+        let manifest: Manifest = Value::new(None, root).try_deserialize().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(manifest.experiment.seed, Some(7));
+        assert_eq!(
+            manifest.trainer,
+            Trainer::NaiveEvolutionary {
+                corruption_ratio: 0.01,
+                lut_sampling_depth: 20,
+                rewire_probability: 0.0,
+                heads: 0,
+                epochs: 5
+            }
+        );
+        // The model path is relative to the experiment file
+        assert_eq!(
+            Path::new(&manifest.experiment.model),
+            dir.join("models/test")
+        );
+    }
+}
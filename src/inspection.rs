@@ -0,0 +1,292 @@
+use crate::{architectures::builder::Shape, netcore::LUTNet};
+use bitvec::prelude::*;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::PathBuf;
+
+// What a network's wiring actually does, without any data: how many nodes every layer has, how many nodes of the
+// layer after read each of them (fan-out, 0 for dead nodes whose output nobody reads), and which input pixels can
+// reach each output bit (its receptive field). Fields are shown as heatmaps over the dim2 x dim3 pixel grid,
+// every pixel being dim1 input bits.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerStats {
+    pub nodes: usize,
+    pub fan_out: Vec<usize>, // [k]: nodes read by k nodes, [0] counts dead ones. Empty for the output layer.
+    pub mean_field: f64,     // Mean receptive field of the layer's nodes, in pixels
+    pub max_field: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct InspectionReport {
+    pub dims: Shape, // Input dims, pixels are (dim2, dim3) and dim1 bits each
+    pub input_fan_out: Vec<usize>, // Fan-out histogram of the input bits
+    pub layers: Vec<LayerStats>, // The last one is the output layer
+    pub output_fields: Vec<BitVec>, // Pixels each output bit reads, through any layer
+    pub show_fields: bool, // Also print the field of every output bit
+}
+
+pub fn inspect(ltnet: &LUTNet, dims: Shape) -> Result<InspectionReport, String> {
+    let (dim1, dim2, dim3) = dims;
+    if dim1 * dim2 * dim3 != ltnet.input_size_in_bits {
+        return Err(format!(
+            "Input dims {:?} hold {} bits but the network reads {}",
+            dims,
+            dim1 * dim2 * dim3,
+            ltnet.input_size_in_bits
+        ));
+    }
+    let pixels = dim2 * dim3;
+    let inputs = ltnet.input_size_in_bits;
+    // Consumers and fields of every address: input bits first, then the nodes
+    let mut consumers = vec![0usize; inputs + ltnet.nodes.len()];
+    let mut fields: Vec<BitVec> = (0..inputs)
+        .map(|bit| {
+            let mut field = bitvec![0; pixels];
+            field.set(bit / dim1, true);
+            field
+        })
+        .collect();
+    for (node_idx, node) in ltnet.nodes.iter().enumerate() {
+        let mut sources = node.indices.to_vec();
+        sources.sort();
+        sources.dedup();
+        let mut field = bitvec![0; pixels];
+        for &source in &sources {
+            if source >= inputs + node_idx {
+                return Err(format!(
+                    "Node {} reads address {}, which isn't computed before it",
+                    node_idx, source
+                ));
+            }
+            consumers[source] += 1;
+            field |= &fields[source];
+        }
+        fields.push(field);
+    }
+
+    let histogram = |addresses: std::ops::Range<usize>| {
+        let mut counts = Vec::new();
+        for address in addresses {
+            let k = consumers[address];
+            if counts.len() <= k {
+                counts.resize(k + 1, 0);
+            }
+            counts[k] += 1;
+        }
+        counts
+    };
+    let num_layers = ltnet.layer_edges.len() - 1;
+    let layers = (0..num_layers)
+        .map(|layer| {
+            let range = inputs + ltnet.layer_edges[layer]..inputs + ltnet.layer_edges[layer + 1];
+            let sizes: Vec<usize> = fields[range.clone()]
+                .iter()
+                .map(|f| f.count_ones())
+                .collect();
+            LayerStats {
+                nodes: range.len(),
+                fan_out: if layer + 1 < num_layers {
+                    histogram(range)
+                } else {
+                    Vec::new()
+                },
+                mean_field: sizes.iter().sum::<usize>() as f64 / sizes.len().max(1) as f64,
+                max_field: sizes.iter().copied().max().unwrap_or(0),
+            }
+        })
+        .collect();
+    let output_start = inputs + ltnet.layer_edges[num_layers - 1];
+    Ok(InspectionReport {
+        dims,
+        input_fan_out: histogram(0..inputs),
+        layers,
+        output_fields: fields.split_off(output_start),
+        show_fields: false,
+    })
+}
+
+impl InspectionReport {
+    pub fn pixels(&self) -> usize {
+        self.dims.1 * self.dims.2
+    }
+
+    pub fn coverage(&self) -> Vec<usize> {
+        // Output bits whose field holds each pixel
+        (0..self.pixels())
+            .map(|p| self.output_fields.iter().filter(|f| f[p]).count())
+            .collect()
+    }
+
+    pub fn reached_fraction(&self) -> f64 {
+        // Share of input pixels that reach the output at all
+        let reached = self.coverage().iter().filter(|&&c| c > 0).count();
+        reached as f64 / self.pixels().max(1) as f64
+    }
+
+    pub fn ascii_heatmap(&self, counts: &[usize]) -> String {
+        // One character per pixel, . for 0 and denser characters for higher counts
+        const RAMP: &[u8] = b":-=+*#%@";
+        let max = counts.iter().copied().max().unwrap_or(0).max(1);
+        let mut out = String::with_capacity(counts.len() + self.dims.2);
+        for row in counts.chunks(self.dims.1) {
+            for &count in row {
+                out.push(if count == 0 {
+                    '.'
+                } else {
+                    RAMP[(count * RAMP.len()).div_ceil(max) - 1] as char
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn to_pgm(&self, counts: &[usize]) -> String {
+        // Plain PGM, white where the count is highest
+        let max = counts.iter().copied().max().unwrap_or(0).max(1);
+        let mut pgm = format!("P2\n{} {}\n{}\n", self.dims.1, self.dims.2, max);
+        for row in counts.chunks(self.dims.1) {
+            let values: Vec<String> = row.iter().map(|c| c.to_string()).collect();
+            pgm.push_str(&values.join(" "));
+            pgm.push('\n');
+        }
+        pgm
+    }
+
+    pub fn write_files(&self, prefix: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        // <prefix>_coverage.pgm, and <prefix>_output_<i>.pgm with the field of every output bit
+        let mut paths = vec![PathBuf::from(format!("{}_coverage.pgm", prefix))];
+        fs::write(&paths[0], self.to_pgm(&self.coverage()))?;
+        for (i, field) in self.output_fields.iter().enumerate() {
+            let path = PathBuf::from(format!("{}_output_{}.pgm", prefix, i));
+            let counts: Vec<usize> = field.iter().map(|b| *b as usize).collect();
+            fs::write(&path, self.to_pgm(&counts))?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+impl fmt::Display for InspectionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Network over {:?} input dims: {} layers, {} nodes, {} output bits",
+            self.dims,
+            self.layers.len(),
+            self.layers.iter().map(|l| l.nodes).sum::<usize>(),
+            self.output_fields.len()
+        )?;
+        writeln!(
+            f,
+            "\n{:>6}{:>8}{:>7}{:>9}{:>9}{:>12}{:>11}",
+            "layer", "nodes", "dead", "mean out", "max out", "mean field", "max field"
+        )?;
+        let row = |f: &mut fmt::Formatter<'_>, name: &str, nodes: usize, fan_out: &[usize]| {
+            let total: usize = fan_out.iter().enumerate().map(|(k, n)| k * n).sum();
+            write!(
+                f,
+                "{:>6}{:>8}{:>7}{:>9.2}{:>9}",
+                name,
+                nodes,
+                fan_out.first().copied().unwrap_or(0),
+                total as f64 / nodes.max(1) as f64,
+                fan_out.len().saturating_sub(1)
+            )
+        };
+        row(
+            f,
+            "input",
+            self.input_fan_out.iter().sum(),
+            &self.input_fan_out,
+        )?;
+        writeln!(f, "{:>12.1}{:>11}", 1.0, 1)?;
+        for (l, layer) in self.layers.iter().enumerate() {
+            if layer.fan_out.is_empty() {
+                write!(
+                    f,
+                    "{:>6}{:>8}{:>7}{:>9}{:>9}",
+                    l, layer.nodes, "-", "-", "-"
+                )?;
+            } else {
+                row(f, &l.to_string(), layer.nodes, &layer.fan_out)?;
+            }
+            writeln!(f, "{:>12.1}{:>11}", layer.mean_field, layer.max_field)?;
+        }
+
+        writeln!(
+            f,
+            "\nFan-out histograms (nodes read by 0, 1, 2, ... nodes of the next layer)"
+        )?;
+        let mut line = format!("{:>6}:", "input");
+        for count in &self.input_fan_out {
+            write!(line, " {}", count).unwrap();
+        }
+        writeln!(f, "{}", line)?;
+        for (l, layer) in self.layers.iter().enumerate() {
+            if !layer.fan_out.is_empty() {
+                let counts: Vec<String> = layer.fan_out.iter().map(|c| c.to_string()).collect();
+                writeln!(f, "{:>6}: {}", l, counts.join(" "))?;
+            }
+        }
+
+        writeln!(
+            f,
+            "\nPixels reaching the output: {:.2}%. Output bits reading each pixel:",
+            self.reached_fraction() * 100.0
+        )?;
+        write!(f, "{}", self.ascii_heatmap(&self.coverage()))?;
+        if self.show_fields {
+            for (i, field) in self.output_fields.iter().enumerate() {
+                let counts: Vec<usize> = field.iter().map(|b| *b as usize).collect();
+                writeln!(f, "\nOutput bit {}: {} pixels", i, field.count_ones())?;
+                write!(f, "{}", self.ascii_heatmap(&counts))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netcore::Node;
+
+    #[test]
+    fn report_traces_fan_out_and_fields() {
+        // 3 x 2 pixels of one bit. The output reads only node 0, which reads pixels 0, 1 and 2.
+        let node = |indices| Node { lut: 0, indices };
+        let ltnet = LUTNet::new(
+            vec![
+                node([0, 0, 1, 1, 2, 2]),
+                node([0, 1, 0, 1, 0, 1]),
+                node([6, 6, 6, 6, 6, 6]),
+            ],
+            6,
+            vec![0, 2, 3],
+            None,
+            vec![0, 1],
+        );
+        assert!(inspect(&ltnet, (2, 3, 2)).is_err());
+        let report = inspect(&ltnet, (1, 3, 2)).unwrap();
+        assert_eq!(report.input_fan_out, vec![3, 1, 2]);
+        assert_eq!(report.layers[0].fan_out, vec![1, 1]);
+        assert_eq!(report.layers[0].mean_field, 2.5);
+        assert_eq!(report.layers[1].fan_out, Vec::<usize>::new());
+        assert_eq!(report.layers[1].max_field, 3);
+        assert_eq!(report.coverage(), vec![1, 1, 1, 0, 0, 0]);
+        assert_eq!(report.reached_fraction(), 0.5);
+        assert_eq!(report.ascii_heatmap(&report.coverage()), "@@@\n...\n");
+        assert_eq!(
+            report.to_pgm(&report.coverage()),
+            "P2\n3 2\n1\n1 1 1\n0 0 0\n"
+        );
+        assert!(
+            report
+                .to_string()
+                .contains("Pixels reaching the output: 50.00%")
+        );
+    }
+}
@@ -0,0 +1,452 @@
+use config::{ConfigError, File, FileFormat, Map, Source, Value, ValueKind};
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Settings are built from layers, each replacing what the previous ones set:
+//   1. the Settings.toml compiled into the binary
+//   2. the file passed with --config
+//   3. LUTNETS_* environment variables, e.g. LUTNETS_DATA__BATCH_SIZE=500 (double underscore between levels)
+//   4. --set key=value arguments, e.g. --set data.batch_size=500 or --set 'data.datasplit={ Train = 100 }'
+// Architecture settings use the same layers under architectures.<name>, e.g. --set architectures.cnn_iv0.lut_bank_size=64.
+// Unlike the config crate's own merging, a table set in a later layer replaces the earlier table instead of
+// merging into it, so enum values like datasplit can switch variants.
+// Relative paths in the --config file resolve against its directory, all others against the crate directory.
+
+const DEFAULT_SETTINGS: &str = include_str!("../Settings.toml");
+const ENV_PREFIX: &str = "LUTNETS_";
+const PATH_KEYS: [&str; 5] = [
+    "data.train_filepath",
+    "data.test_filepath",
+    "data.cache_dir",
+    "experiment.model",
+    "search.out",
+];
+
+pub static CONFIG_SOURCES: OnceLock<ConfigSources> = OnceLock::new();
+
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSources {
+    pub config_file: Option<PathBuf>,
+    pub overrides: Vec<String>, // key=value
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ConfigArgs {
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>, // Settings file layered over the built-in defaults
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>, // Overrides applied after the settings file and environment
+}
+
+impl ConfigArgs {
+    pub fn install(&self) -> Result<(), Box<dyn Error>> {
+        // Must run before anything reads the configuration
+        if let Some(bad) = self.overrides.iter().find(|o| !o.contains('=')) {
+            return Err(format!("--set expects key=value, got '{}'", bad).into());
+        }
+        CONFIG_SOURCES
+            .set(ConfigSources {
+                config_file: self.config.clone(),
+                overrides: self.overrides.clone(),
+            })
+            .map_err(|_| "Configuration sources were already installed".into())
+    }
+}
+
+pub fn config_sources() -> ConfigSources {
+    CONFIG_SOURCES.get().cloned().unwrap_or_default()
+}
+
+fn set_path(root: &mut Map<String, Value>, path: &[&str], value: Value) {
+    // Replaces whatever is at path, creating tables on the way
+    let (last, parents) = path.split_last().expect("Empty settings key");
+    let mut table = root;
+    for key in parents {
+        let entry = table
+            .entry(key.to_string())
+            .or_insert_with(|| Map::<String, Value>::new().into());
+        if !matches!(entry.kind, ValueKind::Table(_)) {
+            *entry = Map::<String, Value>::new().into();
+        }
+        let ValueKind::Table(inner) = &mut entry.kind else {
+            unreachable!()
+        };
+        table = inner;
+    }
+    table.insert(last.to_string(), value);
+}
+
+fn set_leaves(root: &mut Map<String, Value>, prefix: &mut Vec<String>, table: Map<String, Value>) {
+    // Layers a settings file over root, key by key. Sections ([data], [network], architectures.<name>) are
+    // walked into, anything below them is a value and replaces the earlier one whole.
+    let section_depth = if prefix.first().is_some_and(|p| p == "architectures") {
+        2
+    } else {
+        1
+    };
+    for (key, value) in table {
+        prefix.push(key);
+        match value.kind {
+            ValueKind::Table(inner) if prefix.len() <= section_depth => {
+                set_leaves(root, prefix, inner)
+            }
+            _ => {
+                let path: Vec<&str> = prefix.iter().map(String::as_str).collect();
+                set_path(root, &path, value);
+            }
+        }
+        prefix.pop();
+    }
+}
+
+fn parse_value(raw: &str) -> Value {
+    // Values are read as TOML, so numbers, arrays and inline tables work. Anything else is taken as a plain string.
+    File::from_str(&format!("value = {}", raw), FileFormat::Toml)
+        .collect()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::new(None, raw.to_string()))
+}
+
+fn resolve_paths(root: &mut Map<String, Value>, base_dir: &Path) {
+    for key in PATH_KEYS {
+        let (section, name) = key.split_once('.').unwrap();
+        if let Some(ValueKind::Table(table)) = root.get_mut(section).map(|v| &mut v.kind)
+            && let Some(value) = table.get_mut(name)
+            && let ValueKind::String(path) = &value.kind
+        {
+            *value = Value::new(None, resolve_path(base_dir, path));
+        }
+    }
+}
+
+fn set_override(root: &mut Map<String, Value>, key: &str, raw: &str) {
+    let path: Vec<&str> = key.split('.').collect();
+    set_path(root, &path, parse_value(raw.trim()));
+}
+
+fn apply_layers(
+    mut root: Map<String, Value>,
+    sources: &ConfigSources,
+    env: impl Iterator<Item = (String, String)>,
+) -> Result<Map<String, Value>, ConfigError> {
+    // Layers 2 to 4 over root
+    if let Some(path) = &sources.config_file {
+        let mut file_table = File::from(path.as_path()).collect()?;
+        // Made absolute first, a bare file name has an empty parent
+        let path = std::path::absolute(path).map_err(|e| ConfigError::Foreign(Box::new(e)))?;
+        resolve_paths(&mut file_table, path.parent().unwrap());
+        set_leaves(&mut root, &mut Vec::new(), file_table);
+    }
+    let mut env_vars: Vec<(String, String)> = env
+        .filter_map(|(k, v)| {
+            Some((
+                k.strip_prefix(ENV_PREFIX)?
+                    .to_lowercase()
+                    .replace("__", "."),
+                v,
+            ))
+        })
+        .collect();
+    env_vars.sort(); // Deterministic order when a variable sets a table and another a key inside it
+    for (key, raw) in env_vars {
+        set_override(&mut root, &key, &raw);
+    }
+    for entry in &sources.overrides {
+        let (key, raw) = entry.split_once('=').ok_or_else(|| {
+            ConfigError::Message(format!("--set expects key=value, got '{}'", entry))
+        })?;
+        set_override(&mut root, key.trim(), raw);
+    }
+    Ok(root)
+}
+
+pub fn layered_table(
+    sources: &ConfigSources,
+    env: impl Iterator<Item = (String, String)>,
+) -> Result<Map<String, Value>, ConfigError> {
+    let defaults = File::from_str(DEFAULT_SETTINGS, FileFormat::Toml).collect()?;
+    let mut root = apply_layers(defaults, sources, env)?;
+    resolve_paths(&mut root, Path::new(crate::HOME_DIR));
+    Ok(root)
+}
+
+pub fn sets_key(
+    sources: &ConfigSources,
+    env: impl Iterator<Item = (String, String)>,
+    key: &str,
+) -> Result<bool, ConfigError> {
+    // Whether the file, environment or --set layers set key (or something below it), defaults aside
+    let mut value = &Value::new(None, apply_layers(Map::new(), sources, env)?);
+    for part in key.split('.') {
+        match &value.kind {
+            ValueKind::Table(table) if table.contains_key(part) => value = &table[part],
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+pub fn user_sets(key: &str) -> Result<bool, ConfigError> {
+    sets_key(&config_sources(), std::env::vars(), key)
+}
+
+pub fn layered_settings<T: DeserializeOwned>() -> Result<T, ConfigError> {
+    let root = layered_table(&config_sources(), std::env::vars())?;
+    Value::new(None, root).try_deserialize()
+}
+
+fn architecture_table(
+    main_root: &Map<String, Value>,
+    name: &str,
+    defaults: &str,
+) -> Result<Value, ConfigError> {
+    // defaults is the architecture's own Settings.toml with a [network] table, compiled into the binary
+    let mut arch_root = File::from_str(defaults, FileFormat::Toml).collect()?;
+    let overrides = main_root
+        .get("architectures")
+        .and_then(|a| a.clone().into_table().ok())
+        .and_then(|mut a| a.remove(name))
+        .map(|v| v.into_table())
+        .transpose()?;
+    for (key, value) in overrides.into_iter().flatten() {
+        set_path(&mut arch_root, &["network", &key], value);
+    }
+    arch_root
+        .remove("network")
+        .ok_or_else(|| ConfigError::NotFound("network".to_string()))
+}
+
+pub fn load_architecture_settings<T: DeserializeOwned>(
+    name: &str,
+    defaults: &str,
+) -> Result<T, ConfigError> {
+    let main_root = layered_table(&config_sources(), std::env::vars())?;
+    architecture_table(&main_root, name, defaults)?.try_deserialize()
+}
+
+pub fn resolved_table(
+    architecture: Option<(&str, &str)>,
+) -> Result<Map<String, Value>, ConfigError> {
+    // Every layer applied, with the full settings of the architecture (name, defaults) under architectures.<name>.
+    // Written out with to_toml this repeats the run without any other file, variable or argument.
+    let mut root = layered_table(&config_sources(), std::env::vars())?;
+    if let Some((name, defaults)) = architecture {
+        let table = architecture_table(&root, name, defaults)?;
+        set_path(&mut root, &["architectures", name], table);
+    }
+    Ok(root)
+}
+
+pub fn resolve_path(base_dir: &Path, path: &str) -> String {
+    // Absolute paths are kept as they are
+    base_dir.join(path).to_string_lossy().to_string()
+}
+
+pub fn to_toml(root: &Map<String, Value>) -> String {
+    // Writes a layered table back out as a settings file: top level values, then one [section] per table
+    // with everything below it inline. Keys are sorted so the same settings always give the same text.
+    let mut keys: Vec<&String> = root.keys().collect();
+    keys.sort();
+    let (sections, values): (Vec<&String>, Vec<&String>) = keys
+        .into_iter()
+        .partition(|k| matches!(root[*k].kind, ValueKind::Table(_)));
+    let mut out = String::new();
+    for key in values {
+        out.push_str(&format!(
+            "{} = {}\n",
+            toml_key(key),
+            inline_toml(&root[key])
+        ));
+    }
+    for key in sections {
+        let ValueKind::Table(table) = &root[key].kind else {
+            unreachable!()
+        };
+        out.push_str(&format!("\n[{}]\n", toml_key(key)));
+        let mut entries: Vec<(&String, &Value)> = table.iter().collect();
+        entries.sort_by_key(|(k, _)| *k);
+        for (k, v) in entries {
+            out.push_str(&format!("{} = {}\n", toml_key(k), inline_toml(v)));
+        }
+    }
+    out
+}
+
+fn toml_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        key.to_string()
+    } else {
+        format!("{:?}", key)
+    }
+}
+
+fn inline_toml(value: &Value) -> String {
+    match &value.kind {
+        ValueKind::Nil => "\"\"".to_string(), // TOML has no null, only unset keys deserialize to None
+        ValueKind::Boolean(b) => b.to_string(),
+        ValueKind::I64(n) => n.to_string(),
+        ValueKind::I128(n) => n.to_string(),
+        ValueKind::U64(n) => n.to_string(),
+        ValueKind::U128(n) => n.to_string(),
+        ValueKind::Float(x) => format!("{:?}", x), // Always has a decimal point, or is inf/NaN
+        ValueKind::String(s) => format!("{:?}", s),
+        ValueKind::Array(items) => format!(
+            "[{}]",
+            items.iter().map(inline_toml).collect::<Vec<_>>().join(", ")
+        ),
+        ValueKind::Table(table) => {
+            let mut entries: Vec<(&String, &Value)> = table.iter().collect();
+            entries.sort_by_key(|(k, _)| *k);
+            let fields: Vec<String> = entries
+                .into_iter()
+                .map(|(k, v)| format!("{} = {}", toml_key(k), inline_toml(v)))
+                .collect();
+            format!("{{ {} }}", fields.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn get<'a>(root: &'a Map<String, Value>, path: &str) -> &'a Value {
+        let mut parts = path.split('.');
+        let mut value = &root[parts.next().unwrap()];
+        for part in parts {
+            let ValueKind::Table(table) = &value.kind else {
+                panic!("{} is not a table", path)
+            };
+            value = &table[part];
+        }
+        value
+    }
+
+    #[test]
+    fn later_layers_replace_earlier_values() {
+        let path = std::env::temp_dir().join(format!("lutnets_layers_{}.toml", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(
+            file,
+            "[data]\nbatch_size = 100\ndatasplit = {{ Test = 300 }}"
+        )
+        .unwrap();
+        let sources = ConfigSources {
+            config_file: Some(path.clone()),
+            overrides: vec![
+                "data.batch_size=500".to_string(),
+                "architectures.cnn_iv0.lut_bank_size = 8".to_string(),
+            ],
+        };
+        let env = vec![
+            ("LUTNETS_DATA__DIM2".to_string(), "14".to_string()),
+            ("LUTNETS_DATA__BATCH_SIZE".to_string(), "200".to_string()),
+            ("UNRELATED".to_string(), "1".to_string()),
+        ];
+        let root = layered_table(&sources, env.into_iter()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            get(&root, "data.batch_size").clone().into_int().unwrap(),
+            500
+        );
+        assert_eq!(get(&root, "data.dim2").clone().into_int().unwrap(), 14);
+        // The file's datasplit replaced the default Train variant instead of merging with it
+        let ValueKind::Table(split) = &get(&root, "data.datasplit").kind else {
+            panic!()
+        };
+        assert_eq!(split.keys().collect::<Vec<_>>(), vec!["Test"]);
+        assert_eq!(
+            get(&root, "architectures.cnn_iv0.lut_bank_size")
+                .clone()
+                .into_int()
+                .unwrap(),
+            8
+        );
+        // Untouched defaults survive
+        assert_eq!(get(&root, "data.dim3").clone().into_int().unwrap(), 28);
+    }
+
+    #[test]
+    fn paths_in_a_relative_config_file_resolve_against_its_directory() {
+        let name = format!("target/lutnets_relative_{}.toml", std::process::id());
+        std::fs::write(&name, "[data]\ntrain_filepath = \"train.csv\"\n").unwrap();
+        let sources = ConfigSources {
+            config_file: Some(PathBuf::from(&name)),
+            overrides: vec![],
+        };
+        let root = layered_table(&sources, std::iter::empty()).unwrap();
+        std::fs::remove_file(&name).unwrap();
+
+        let expected = std::env::current_dir().unwrap().join("target/train.csv");
+        assert_eq!(
+            get(&root, "data.train_filepath")
+                .clone()
+                .into_string()
+                .unwrap(),
+            expected.to_string_lossy()
+        );
+    }
+
+    #[test]
+    fn only_keys_set_above_the_defaults_count_as_set() {
+        let sources = ConfigSources {
+            config_file: None,
+            overrides: vec!["decoder={ head = \"GroupVote\" }".to_string()],
+        };
+        let env = || vec![("LUTNETS_LOSS__KIND".to_string(), "Bits".to_string())].into_iter();
+        assert!(sets_key(&sources, env(), "decoder").unwrap());
+        assert!(sets_key(&sources, env(), "decoder.head").unwrap());
+        assert!(sets_key(&sources, env(), "loss").unwrap());
+        assert!(!sets_key(&sources, env(), "loss.bit_weights").unwrap());
+        assert!(!sets_key(&sources, env(), "network").unwrap());
+        assert!(!sets_key(&ConfigSources::default(), std::iter::empty(), "decoder").unwrap());
+    }
+
+    #[test]
+    fn written_settings_read_back_the_same() {
+        let sources = ConfigSources {
+            config_file: None,
+            overrides: vec![
+                "data.augmentation={ max_rotation_deg = 10.0, max_shift = 2 }".to_string(),
+                "architectures.cnn_iv2.output_embedding=[1, 2]".to_string(),
+                "experiment.name=\"a \\\"quoted\\\" name\"".to_string(),
+            ],
+        };
+        let root = layered_table(&sources, std::iter::empty()).unwrap();
+        let text = to_toml(&root);
+        let reread = File::from_str(&text, FileFormat::Toml).collect().unwrap();
+        assert_eq!(to_toml(&reread), text);
+        assert_eq!(
+            get(&reread, "experiment.name")
+                .clone()
+                .into_string()
+                .unwrap(),
+            "a \"quoted\" name"
+        );
+        // Default paths are made absolute, so the written file works from anywhere
+        let train = get(&reread, "data.train_filepath")
+            .clone()
+            .into_string()
+            .unwrap();
+        assert!(Path::new(&train).is_absolute());
+    }
+
+    #[test]
+    fn override_values_are_parsed_as_toml() {
+        assert_eq!(parse_value("[1, 2]").into_array().unwrap().len(), 2);
+        assert_eq!(parse_value("Raw").into_string().unwrap(), "Raw");
+        assert!(matches!(
+            parse_value("{ Threshold = 4 }").kind,
+            ValueKind::Table(_)
+        ));
+    }
+}
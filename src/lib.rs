@@ -1,12 +1,28 @@
 pub mod architectures;
+pub mod augmentation;
+pub mod binarization;
+pub mod datacache;
 pub mod dataloader;
+pub mod decoding;
+pub mod evaluation;
+pub mod experiment;
+pub mod inspection;
 pub mod iterators;
+pub mod layered_config;
+pub mod loss;
 pub mod lut_bank_creators;
 pub mod modelloader;
 pub mod netcore;
 pub mod processing;
+pub mod sampler;
+pub mod search;
 pub mod settings;
+pub mod splits;
+pub mod streaming;
+pub mod synthetic;
+pub mod tabular;
 pub mod training_algos;
 pub mod utils;
+pub mod validation;
 
 pub const HOME_DIR: &str = env!("CARGO_MANIFEST_DIR");
@@ -0,0 +1,191 @@
+use crate::settings::*;
+use bitvec::prelude::*;
+
+// Losses the trainers minimize, picked with [loss] in Settings.toml. processing::get_loss and get_loss_vec go
+// through here, so every trainer uses the configured loss without changes.
+// Counting wrong bits rewards getting most bits right even when the nearest codeword is wrong, Margin and ZeroOne
+// only reward what changes the decoded class. The loss vector for piv_node_flipper holds the wrong bits of the
+// samples that carry loss. Weights only scale the loss value, a bit vector has no room for them.
+
+impl LossSettings {
+    pub fn validate(&self, cfg: &Configuration) -> Result<(), String> {
+        let classes = cfg.network.output_embedding.len();
+        if !self.class_weights.is_empty() && self.class_weights.len() < classes {
+            return Err(format!(
+                "loss.class_weights has {} weights for {} classes",
+                self.class_weights.len(),
+                classes
+            ));
+        }
+        if !self.bit_weights.is_empty() && self.bit_weights.len() != cfg.derived.output_bitsize {
+            return Err(format!(
+                "loss.bit_weights has {} weights for {} output bits",
+                self.bit_weights.len(),
+                cfg.derived.output_bitsize
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn loss(
+        &self,
+        cfg: &Configuration,
+        outputs: &BitSlice<u8, Msb0>,
+        wrong_bits: &BitSlice<u8, Msb0>,
+        labels: &[usize],
+    ) -> usize {
+        // outputs are the last layer's bits for the batch, wrong_bits the outputs XOR the targets of the true labels
+        match self.kind {
+            LossKind::Decoder if self.class_weights.is_empty() => {
+                cfg.decoder.loss(cfg, outputs, wrong_bits, labels)
+            }
+            LossKind::Bits if self.class_weights.is_empty() && self.bit_weights.is_empty() => {
+                wrong_bits.count_ones()
+            }
+            _ => {
+                let bits = cfg.derived.output_bitsize;
+                let targets = cfg.decoder.targets(cfg);
+                outputs
+                    .chunks_exact(bits)
+                    .zip(wrong_bits.chunks_exact(bits))
+                    .zip(labels)
+                    .map(|((sample, wrong), &label)| {
+                        self.sample_loss(cfg, &targets, sample, wrong, label)
+                            * self.class_weight(label)
+                    })
+                    .sum()
+            }
+        }
+    }
+
+    pub fn loss_vec(
+        &self,
+        cfg: &Configuration,
+        outputs: &BitSlice<u8, Msb0>,
+        wrong_bits: BitVec<u8, Msb0>,
+        labels: &[usize],
+    ) -> BitVec<u8, Msb0> {
+        // Wrong bits of the samples that carry loss, the rest cleared
+        if matches!(self.kind, LossKind::Decoder | LossKind::Bits) && self.class_weights.is_empty()
+        {
+            return wrong_bits;
+        }
+        let bits = cfg.derived.output_bitsize;
+        let targets = cfg.decoder.targets(cfg);
+        let mut loss_vec = wrong_bits;
+        for ((sample, wrong), &label) in outputs
+            .chunks_exact(bits)
+            .zip(loss_vec.chunks_exact_mut(bits))
+            .zip(labels)
+        {
+            let counts = self.class_weight(label) > 0
+                && match self.kind {
+                    LossKind::Decoder | LossKind::Bits => true,
+                    LossKind::Margin => {
+                        let (to_true, to_wrong) = self.distances(cfg, &targets, sample, label);
+                        to_true >= to_wrong
+                    }
+                    LossKind::ZeroOne => cfg.decoder.decode(cfg, sample) != label,
+                };
+            if !counts {
+                wrong.fill(false);
+            }
+        }
+        loss_vec
+    }
+
+    fn sample_loss(
+        &self,
+        cfg: &Configuration,
+        targets: &[usize],
+        sample: &BitSlice<u8, Msb0>,
+        wrong: &BitSlice<u8, Msb0>,
+        label: usize,
+    ) -> usize {
+        match self.kind {
+            LossKind::Decoder => cfg.decoder.sample_loss(cfg, sample, wrong, label),
+            LossKind::Bits if self.bit_weights.is_empty() => wrong.count_ones(),
+            LossKind::Bits => wrong
+                .iter_ones()
+                .map(|i| self.bit_weights[i] as usize)
+                .sum(),
+            LossKind::Margin => {
+                // Shifted by the largest possible distance, so a sample right on its target with every wrong
+                // target as far as possible scores 0
+                let (to_true, to_wrong) = self.distances(cfg, targets, sample, label);
+                let max = self.max_distance(cfg);
+                (max + to_true - to_wrong.min(max)) as usize
+            }
+            LossKind::ZeroOne => (cfg.decoder.decode(cfg, sample) != label) as usize,
+        }
+    }
+
+    fn distances(
+        &self,
+        cfg: &Configuration,
+        targets: &[usize],
+        sample: &BitSlice<u8, Msb0>,
+        label: usize,
+    ) -> (u32, u32) {
+        // Distance to the true target and to the nearest wrong one, with the loss's bit weights if set
+        if self.bit_weights.is_empty() {
+            return cfg.decoder.true_and_nearest_wrong(targets, sample, label);
+        }
+        let weighted = Decoder::Weighted {
+            bit_weights: self.bit_weights.clone(),
+        };
+        weighted.true_and_nearest_wrong(targets, sample, label)
+    }
+
+    fn max_distance(&self, cfg: &Configuration) -> u32 {
+        match (&cfg.decoder, self.bit_weights.is_empty()) {
+            (_, false) => self.bit_weights.iter().sum(),
+            (Decoder::Weighted { bit_weights }, true) => bit_weights.iter().sum(),
+            _ => cfg.derived.output_bitsize as u32,
+        }
+    }
+
+    fn class_weight(&self, label: usize) -> usize {
+        self.class_weights.get(label).map_or(1, |&w| w as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loss_kinds_score_and_mask_samples() {
+        let mut cfg = initialize_app_config_with_network(None);
+        cfg.network.output_embedding = vec![0b1100, 0b0011];
+        cfg.network.layer_sizes = vec![64, 4];
+        cfg.data.batch_size = 2;
+        cfg.derived = DerivedValues::new(&cfg.data, &cfg.network);
+        // The first sample is one bit off class 0, the second decodes to class 0 but is class 1
+        let outputs = bits![u8, Msb0; 1, 1, 0, 1, /**/ 1, 1, 1, 0];
+        let labels = [0, 1];
+        let mut wrong_bits = outputs.to_bitvec();
+        wrong_bits[..4] ^= bits![u8, Msb0; 1, 1, 0, 0];
+        wrong_bits[4..] ^= bits![u8, Msb0; 0, 0, 1, 1];
+        let score = |loss: &LossSettings| loss.loss(&cfg, outputs, &wrong_bits, &labels);
+
+        let mut loss = LossSettings::default();
+        assert_eq!(score(&loss), 4);
+        loss.kind = LossKind::ZeroOne;
+        assert_eq!(score(&loss), 1);
+        let loss_vec = loss.loss_vec(&cfg, outputs, wrong_bits.clone(), &labels);
+        assert_eq!(loss_vec, bits![u8, Msb0; 0, 0, 0, 0, /**/ 1, 1, 0, 1]);
+
+        // 4 + 1 - 3 for the first sample, 4 + 3 - 1 for the second
+        loss.kind = LossKind::Margin;
+        assert_eq!(score(&loss), 8);
+        loss.class_weights = vec![1, 3];
+        assert_eq!(score(&loss), 2 + 18);
+        loss.kind = LossKind::Bits;
+        loss.bit_weights = vec![1, 1, 1, 5];
+        assert_eq!(score(&loss), 5 + 3 * 7);
+        assert!(loss.validate(&cfg).is_ok());
+        loss.bit_weights.pop();
+        assert!(loss.validate(&cfg).is_err());
+    }
+}
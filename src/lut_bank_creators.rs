@@ -1,5 +1,5 @@
-use crate::settings::*;
-use rand::{Rng, SeedableRng};
+use crate::{settings::*, utils::new_rng};
+use rand::Rng;
 use rand_xoshiro::Xoshiro256PlusPlus;
 use std::collections::HashSet;
 
@@ -15,7 +15,7 @@ pub fn generate_luts(cfg: &Configuration) -> Option<Vec<u64>> {
     if cfg.network.lut_bank_size == 0 {
         return None;
     }
-    let mut rng: Xoshiro256PlusPlus = Xoshiro256PlusPlus::from_rng(&mut rand::rng());
+    let mut rng: Xoshiro256PlusPlus = new_rng();
     let mut luts = vec![0u64; cfg.network.lut_bank_size];
     rng.fill(&mut luts[..]);
     Some(luts)
@@ -1,6 +1,85 @@
-use crate::{netcore::*, settings::*};
+use crate::{layered_config::user_sets, netcore::*, settings::*, tabular::*};
+use serde::{Deserialize, Serialize};
 use std::{fs::File, io::Read};
 
+#[derive(Serialize, Deserialize)]
+struct ManifestLUTNet {
+    // Models saved before LUTNet carried its decoder head
+    nodes: Vec<Node>,
+    input_size_in_bits: usize,
+    layer_edges: Vec<usize>,
+    lut_bank: Option<Vec<u64>>,
+    output_embedding: Vec<usize>,
+    frozen: Vec<usize>,
+    manifest: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FrozenLUTNet {
+    // Models saved before LUTNet carried its experiment manifest
+    nodes: Vec<Node>,
+    input_size_in_bits: usize,
+    layer_edges: Vec<usize>,
+    lut_bank: Option<Vec<u64>>,
+    output_embedding: Vec<usize>,
+    frozen: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct LegacyLUTNet {
+    // Models saved before LUTNet had frozen nodes
+    nodes: Vec<Node>,
+    input_size_in_bits: usize,
+    layer_edges: Vec<usize>,
+    lut_bank: Option<Vec<u64>>,
+    output_embedding: Vec<usize>,
+}
+
+pub fn decode_model(buffer: &[u8]) -> Result<LUTNet, bincode::error::DecodeError> {
+    // bincode isn't self-describing, so a missing trailing field can't default, older models are read as such.
+    // The current layout is read with bincode's own Decode, serde can't read the tagged decoder head from bincode.
+    let config = bincode::config::standard();
+    bincode::decode_from_slice(buffer, config)
+        .map(|(ltnet, _)| ltnet)
+        .or_else(|_| {
+            let (old, _): (ManifestLUTNet, usize) =
+                bincode::serde::decode_from_slice(buffer, config)?;
+            let mut ltnet = LUTNet::new(
+                old.nodes,
+                old.input_size_in_bits,
+                old.layer_edges,
+                old.lut_bank,
+                old.output_embedding,
+            )
+            .with_frozen(old.frozen);
+            ltnet.manifest = old.manifest;
+            Ok(ltnet)
+        })
+        .or_else(|_: bincode::error::DecodeError| {
+            let (old, _): (FrozenLUTNet, usize) =
+                bincode::serde::decode_from_slice(buffer, config)?;
+            Ok(LUTNet::new(
+                old.nodes,
+                old.input_size_in_bits,
+                old.layer_edges,
+                old.lut_bank,
+                old.output_embedding,
+            )
+            .with_frozen(old.frozen))
+        })
+        .or_else(|_: bincode::error::DecodeError| {
+            let (old, _): (LegacyLUTNet, usize) =
+                bincode::serde::decode_from_slice(buffer, config)?;
+            Ok(LUTNet::new(
+                old.nodes,
+                old.input_size_in_bits,
+                old.layer_edges,
+                old.lut_bank,
+                old.output_embedding,
+            ))
+        })
+}
+
 pub fn load_model_from_file(model_filename: &str) -> (&'static Configuration, LUTNet) {
     if !std::path::Path::new(&model_filename).exists() {
         panic!(
@@ -12,9 +91,7 @@ pub fn load_model_from_file(model_filename: &str) -> (&'static Configuration, LU
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)
         .expect("Model file opened, but unable to read it.");
-    let (ltnet, _): (LUTNet, usize) =
-        bincode::serde::decode_from_slice(&buffer, bincode::config::standard())
-            .expect("Failed to deserialize model.");
+    let ltnet = decode_model(&buffer).expect("Failed to deserialize model.");
     let output_embedding = ltnet.output_embedding.clone();
     let layer_sizes = ltnet
         .layer_edges
@@ -24,11 +101,85 @@ pub fn load_model_from_file(model_filename: &str) -> (&'static Configuration, LU
         .collect();
     let lut_bank_size = ltnet.lut_bank.as_ref().map(|v| v.len()).unwrap_or(0);
     println!("Loaded model from file: {}", &model_filename);
+    // Tabular models carry their fitted encoder, so inference encodes rows exactly like training did
+    if let Some(encoder) = TabularEncoder::read_sidecar(model_filename)
+        .expect("Tabular encoder file exists, but unable to read it.")
+    {
+        println!(
+            "Loaded tabular encoder from file: {}",
+            TabularEncoder::sidecar_path(model_filename).display()
+        );
+        let _ = LOADED_ENCODER.set(encoder);
+    }
 
-    let cfg = get_cfg(Some(Network {
-        output_embedding,
-        layer_sizes,
-        lut_bank_size,
-    }));
+    // The model decodes with the head it was trained for, unless the settings set one
+    let decoder = ltnet
+        .decoder
+        .clone()
+        .filter(|_| !user_sets("decoder").expect("Failed to load configuration"));
+    let cfg = APP_CFG.get_or_init(|| {
+        let mut cfg = initialize_app_config_with_network(Some(Network {
+            output_embedding,
+            layer_sizes,
+            lut_bank_size,
+            output_code: None, // The model stores the codewords it was trained with
+        }));
+        if let Some(decoder) = decoder {
+            println!("Decoding with the model's {:?} head", decoder);
+            cfg.decoder = decoder;
+        }
+        cfg
+    });
     (cfg, ltnet)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_decode_with_and_without_a_decoder_or_manifest() {
+        let node = Node {
+            lut: 0xF0,
+            indices: [0, 1, 2, 3, 4, 5],
+        };
+        let mut ltnet =
+            LUTNet::new(vec![node; 2], 6, vec![0, 2], None, vec![1, 2]).with_frozen(vec![1]);
+        ltnet.manifest = Some("[experiment]\nname = \"test\"\n".to_string());
+        ltnet.decoder = Some(Decoder::Margin { min_margin: 2 });
+        let buffer = bincode::encode_to_vec(&ltnet, bincode::config::standard()).unwrap();
+        let decoded = decode_model(&buffer).unwrap();
+        assert_eq!(decoded.manifest, ltnet.manifest);
+        assert_eq!(decoded.decoder, ltnet.decoder);
+        assert_eq!(decoded.frozen, vec![1]);
+
+        let old = ManifestLUTNet {
+            nodes: ltnet.nodes.clone(),
+            input_size_in_bits: 6,
+            layer_edges: vec![0, 2],
+            lut_bank: None,
+            output_embedding: vec![1, 2],
+            frozen: vec![1],
+            manifest: ltnet.manifest.clone(),
+        };
+        let buffer = bincode::serde::encode_to_vec(&old, bincode::config::standard()).unwrap();
+        let decoded = decode_model(&buffer).unwrap();
+        assert_eq!(decoded.manifest, ltnet.manifest);
+        assert_eq!(decoded.decoder, None);
+        assert_eq!(decoded.frozen, vec![1]);
+
+        let old = FrozenLUTNet {
+            nodes: ltnet.nodes.clone(),
+            input_size_in_bits: 6,
+            layer_edges: vec![0, 2],
+            lut_bank: None,
+            output_embedding: vec![1, 2],
+            frozen: vec![1],
+        };
+        let buffer = bincode::serde::encode_to_vec(&old, bincode::config::standard()).unwrap();
+        let decoded = decode_model(&buffer).unwrap();
+        assert_eq!(decoded.manifest, None);
+        assert_eq!(decoded.frozen, vec![1]);
+        assert_eq!(decoded.nodes.len(), 2);
+    }
+}
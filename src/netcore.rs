@@ -1,25 +1,30 @@
-use crate::{iterators::*, settings::*};
-use bincode::Encode;
+use crate::{iterators::*, settings::*, utils::*};
+use bincode::{Decode, Encode};
 use bitvec::prelude::*;
 use rand::prelude::*;
-use rand_xoshiro::Xoshiro256PlusPlus;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, Encode)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Encode, Decode)]
 pub struct Node {
     // a node will have a LUT and 6 input indices
     pub lut: u64,
     pub indices: [usize; 6],
 }
-#[derive(Serialize, Deserialize, Debug, Clone, Encode)]
+#[derive(Serialize, Deserialize, Debug, Clone, Encode, Decode)]
 pub struct LUTNet {
     pub nodes: Vec<Node>,
     pub input_size_in_bits: usize, // This is the size, in bits, of each input item to the network. 784 for MNIST
     pub layer_edges: Vec<usize>,   // indices in nodes vector where each layer starts
     pub lut_bank: Option<Vec<u64>>, // If we want a network with finite lut bank for each node to pick from
     pub output_embedding: Vec<usize>,
+    #[serde(default)]
+    pub frozen: Vec<usize>, // Sorted indices of nodes with a fixed function (e.g. an OR pool), never trained
+    #[serde(default)]
+    pub manifest: Option<String>, // Resolved experiment file the model was trained from, set by run_experiment
+    #[serde(default)]
+    pub decoder: Option<Decoder>, // Head the model was trained for, set by the trainers
 }
 
 impl LUTNet {
@@ -36,49 +41,70 @@ impl LUTNet {
             layer_edges,
             lut_bank,
             output_embedding,
+            frozen: Vec::new(),
+            manifest: None,
+            decoder: None,
         }
     }
 
+    pub fn with_frozen(mut self, mut frozen: Vec<usize>) -> Self {
+        // Marks nodes with a fixed function, the trainers leave them alone
+        frozen.sort_unstable();
+        self.frozen = frozen;
+        self
+    }
+
+    pub fn is_frozen(&self, node_idx: usize) -> bool {
+        self.frozen.binary_search(&node_idx).is_ok()
+    }
+
+    pub fn trainable_nodes(&self) -> Vec<usize> {
+        // Every node the trainers may change
+        (0..self.nodes.len())
+            .filter(|&node_idx| !self.is_frozen(node_idx))
+            .collect()
+    }
+
     pub fn init_random(
         input_size_in_bits: usize,
         layer_edges: &[usize],
         lut_bank: Option<Vec<u64>>,
         output_embedding: &[usize],
     ) -> Self {
-        // This is a highly random and pretty impractical initialization. A slightly better (but still pretty bad) one is init_spanned in architectures/cnn_iv0/netimpl.rs
+        // This is a highly random and pretty impractical initialization. Local groups in architectures/builder.rs keep the randomness but only within a neighbourhood, see architectures/local_iv0.toml
         // This network basically just randomly connects a node in next layer to arbitrary 6 nodes in previous layer.
 
         let &network_size = layer_edges.last().unwrap();
         let mut nodes = Vec::with_capacity(network_size);
+        let base_seed = new_rng().next_u64();
         (0..network_size)
             .into_par_iter()
-            .map_with(
-                || Xoshiro256PlusPlus::from_rng(&mut rand::rng()),
-                |rng, node_index| {
-                    let layer_index = layer_edges[1..].partition_point(|&edge| edge <= node_index);
-                    // println!("layer_index for node {:?}: {}", layer_edges, layer_index);
-                    let (range_start, range_end) = if layer_index == 0 {
-                        (0, input_size_in_bits)
+            .map(|node_index| {
+                // One stream per node, so the network doesn't depend on how rayon splits the work
+                let mut rng = stream_rng(base_seed, node_index as u64);
+                let layer_index = layer_edges[1..].partition_point(|&edge| edge <= node_index);
+                // println!("layer_index for node {:?}: {}", layer_edges, layer_index);
+                let (range_start, range_end) = if layer_index == 0 {
+                    (0, input_size_in_bits)
+                } else {
+                    let start = input_size_in_bits + layer_edges[layer_index - 1];
+                    let end = input_size_in_bits + layer_edges[layer_index];
+                    (start, end)
+                };
+                // println!("range for node {}: {} to {}", node_index, range_start, range_end);
+                let mut indices = [0usize; 6];
+                for item in &mut indices {
+                    *item = rng.random_range(range_start..range_end);
+                }
+                Node {
+                    lut: if let Some(lut_bank) = &lut_bank {
+                        lut_bank[rng.random_range(0..lut_bank.len())]
                     } else {
-                        let start = input_size_in_bits + layer_edges[layer_index - 1];
-                        let end = input_size_in_bits + layer_edges[layer_index];
-                        (start, end)
-                    };
-                    // println!("range for node {}: {} to {}", node_index, range_start, range_end);
-                    let mut indices = [0usize; 6];
-                    for item in &mut indices {
-                        *item = rng().random_range(range_start..range_end);
-                    }
-                    Node {
-                        lut: if let Some(lut_bank) = &lut_bank {
-                            lut_bank[rng().random_range(0..lut_bank.len())]
-                        } else {
-                            rng().next_u64()
-                        },
-                        indices,
-                    }
-                },
-            )
+                        rng.next_u64()
+                    },
+                    indices,
+                }
+            })
             .collect_into_vec(&mut nodes);
 
         LUTNet {
@@ -87,6 +113,9 @@ impl LUTNet {
             layer_edges: layer_edges.to_owned(),
             lut_bank: None,
             output_embedding: output_embedding.to_owned(),
+            frozen: Vec::new(),
+            manifest: None,
+            decoder: None,
         }
     }
 
@@ -198,6 +227,9 @@ impl LUTNet {
         if let Some(lut_bank) = &self.lut_bank {
             let lut_set: HashSet<_> = lut_bank.iter().collect(); //faster lookups
             for (idx, node) in self.nodes.iter().enumerate() {
+                if self.is_frozen(idx) {
+                    continue; // Fixed functions don't come from the bank
+                }
                 assert!(
                     lut_set.contains(&node.lut),
                     "Failed at node {}, LUT value '{}' was not found in the LUT bank.",
@@ -252,12 +284,12 @@ mod tests {
         let architecture = Architecture::from_str(arch_name)
             .expect("Could not create architecture in span creation test");
         let (cfg, ltnet) = architecture.build();
-        let (databits, _) = csv_to_bitvec(cfg).unwrap();
+        let (databits, _) = csv_to_bitvec(&cfg).unwrap();
         // println!("DATA_BITS: {}, cfg.derived.batch_bitcount: {}, cfg.derived.bitvec_size: {}", cfg.derived.data_bitcount, cfg.derived.batch_bitcount, cfg.derived.bitvec_size);
         let mut dbv = bitvec![u8, Msb0; 0; cfg.derived.bitvec_size];
         dbv[..cfg.derived.batch_bitcount]
             .copy_from_bitslice(&databits[..cfg.derived.batch_bitcount]);
-        ltnet.apply_gates(cfg, &mut dbv);
+        ltnet.apply_gates(&cfg, &mut dbv);
         let mut rng = rand::rng();
         let some_node_ids: Vec<(usize, usize, usize)> = (0..cfg.derived.num_layers)
             .map(|i| {
@@ -1,44 +1,85 @@
+use crate::decoding::RankedPrediction;
 use crate::settings::*;
 use bitvec::prelude::*;
 use cached::proc_macro::cached;
-
+use config::ConfigError;
+use rand::prelude::*;
+use rand_xoshiro::Xoshiro256PlusPlus;
 
 pub fn get_labels(cfg: &Configuration, dbv: &BitVec<u8, Msb0>) -> Vec<usize> {
-    // Extract predicted labels from the final part of the bitvec
+    // Extract predicted labels from the final part of the bitvec, decoded by the configured head (see decoding.rs)
     let predicted_label_bitslice = &dbv[cfg.derived.bitvec_size
         - cfg.data.batch_size * cfg.network.layer_sizes[cfg.derived.num_layers - 1]
         ..cfg.derived.bitvec_size];
     predicted_label_bitslice
+        .chunks_exact(cfg.derived.output_bitsize)
+        .take(cfg.data.batch_size)
+        .map(|chunk| cfg.decoder.decode(cfg, chunk))
+        .collect::<Vec<usize>>()
+}
+
+pub fn get_ranked_predictions(
+    cfg: &Configuration,
+    dbv: &BitVec<u8, Msb0>,
+    k: usize,
+) -> Vec<RankedPrediction> {
+    // Classes of every sample ranked by distance to their targets, with confidences and the margin.
+    // Only the k nearest are kept.
+    let targets = cfg.decoder.targets(cfg);
+    get_outputs(cfg, dbv)
         .chunks_exact(cfg.derived.output_bitsize)
         .take(cfg.data.batch_size)
         .map(|chunk| {
-            cfg.network
-                .output_embedding
-                .iter()
-                .enumerate()
-                .min_by_key(|&(_index, &val)| (chunk.load_be::<usize>() ^ val).count_ones())
-                .map(|(index, _val)| index)
-                .unwrap()
+            let mut prediction = cfg.decoder.rank(&targets, chunk);
+            prediction.ranked.truncate(k.max(1));
+            prediction
         })
-        .collect::<Vec<usize>>()
+        .collect()
 }
 
-pub fn get_loss_vec(
+pub fn get_wrong_bits(
     cfg: &Configuration,
     dbv: &BitVec<u8, Msb0>,
     labels: &[usize],
 ) -> BitVec<u8, Msb0> {
-    // Calculate loss vector as differing bits between predicted and true labels
-    let predicted_label_bitslice = &dbv[cfg.derived.bitvec_size
-        - cfg.data.batch_size * cfg.derived.output_bitsize
-        ..cfg.derived.bitvec_size];
-    let mut true_label_bitslice = get_true_label_bitslice(cfg, labels);
+    // Differing bits between predicted and true labels (the head's targets for them)
+    let predicted_label_bitslice = get_outputs(cfg, dbv);
+    let mut true_label_bitslice = get_true_label_bitslice(
+        &cfg.decoder.targets(cfg),
+        cfg.derived.output_bitsize,
+        labels,
+    );
     true_label_bitslice ^= predicted_label_bitslice; // This is XOR between true labels and predicted labels, despite the name
     true_label_bitslice
 }
 
+pub fn get_loss_vec(
+    cfg: &Configuration,
+    dbv: &BitVec<u8, Msb0>,
+    labels: &[usize],
+) -> BitVec<u8, Msb0> {
+    // Calculate loss vector as the wrong bits of the samples that carry loss (all of them for the default loss)
+    cfg.loss.loss_vec(
+        cfg,
+        get_outputs(cfg, dbv),
+        get_wrong_bits(cfg, dbv, labels),
+        labels,
+    )
+}
+
 pub fn get_loss(cfg: &Configuration, dbv: &BitVec<u8, Msb0>, labels: &[usize]) -> usize {
-    get_loss_vec(cfg, dbv, labels).count_ones()
+    // The configured loss, see loss.rs. By default the number of wrong output bits.
+    cfg.loss.loss(
+        cfg,
+        get_outputs(cfg, dbv),
+        &get_wrong_bits(cfg, dbv, labels),
+        labels,
+    )
+}
+
+pub fn get_outputs<'a>(cfg: &Configuration, dbv: &'a BitVec<u8, Msb0>) -> &'a BitSlice<u8, Msb0> {
+    &dbv[cfg.derived.bitvec_size - cfg.data.batch_size * cfg.derived.output_bitsize
+        ..cfg.derived.bitvec_size]
 }
 
 pub fn get_predicted_embedding(cfg: &Configuration, dbv: &BitVec<u8, Msb0>) -> Vec<u8> {
@@ -53,31 +94,243 @@ pub fn get_predicted_embedding(cfg: &Configuration, dbv: &BitVec<u8, Msb0>) -> V
         .collect::<Vec<u8>>()
 }
 
-
 #[cached(
     size = 50, // max 50 cached results
-    key = "Vec<usize>", 
-    convert = r#"{ labels.to_vec() }"#,
+    key = "(Vec<usize>, usize, Vec<usize>)", 
+    convert = r#"{ (targets.to_vec(), chunk_size, labels.to_vec()) }"#,
 )]
 fn get_true_label_bitslice(
-    cfg: &Configuration,
+    targets: &[usize],
+    chunk_size: usize,
     labels: &[usize],
 ) -> BitVec<u8, Msb0> {
-    let chunk_size = cfg.derived.output_bitsize;
-    labels
+    labels.iter().map(|label_val| targets[*label_val]).fold(
+        BitVec::<u8, Msb0>::with_capacity(labels.len() * chunk_size),
+        |mut acc, embedding| {
+            // Get the bits and extend the accumulator. The borrow is temporary and safe.
+            let bits = embedding.view_bits::<Msb0>();
+            acc.extend_from_bitslice(&bits[usize::BITS as usize - chunk_size..]);
+            acc
+        },
+    )
+}
+
+// Output codewords ------------------------------------------------------------------------------------------
+// get_labels decodes to the nearest codeword and the loss counts wrong output bits, so codewords that are far
+// apart let a few wrong bits still decode to the right class. A code with minimum distance d corrects (d - 1) / 2 bit errors.
+
+impl OutputCode {
+    pub fn validate(&self, bits: usize) -> Result<(), ConfigError> {
+        // Whether generate can give every class a codeword of the output layer's width
+        let err = |msg: String| {
+            Err(ConfigError::Message(format!(
+                "Invalid output_code: {}",
+                msg
+            )))
+        };
+        if bits == 0 || bits > usize::BITS as usize {
+            return err(format!(
+                "codewords need 1 to {} bits, not {}",
+                usize::BITS,
+                bits
+            ));
+        }
+        if self.classes < 2 || (bits < usize::BITS as usize && self.classes > 1 << bits) {
+            return err(format!(
+                "can't give {} classes distinct codewords of {} bits",
+                self.classes, bits
+            ));
+        }
+        if self.construction == CodeConstruction::Hadamard
+            && let Err(e) = hadamard_codewords(self.classes, bits)
+        {
+            return err(e);
+        }
+        Ok(())
+    }
+
+    pub fn generate(&self, bits: usize) -> Result<Vec<usize>, ConfigError> {
+        self.validate(bits)?;
+        Ok(match self.construction {
+            CodeConstruction::Hadamard => hadamard_codewords(self.classes, bits).unwrap(),
+            CodeConstruction::Search => {
+                search_codewords(self.classes, bits, self.seed, self.iterations)
+            }
+            CodeConstruction::Best => {
+                let searched = search_codewords(self.classes, bits, self.seed, self.iterations);
+                match hadamard_codewords(self.classes, bits) {
+                    Ok(hadamard) if code_score(&hadamard) >= code_score(&searched) => hadamard,
+                    _ => searched,
+                }
+            }
+        })
+    }
+}
+
+pub fn hadamard_codewords(classes: usize, bits: usize) -> Result<Vec<usize>, String> {
+    // Rows of the 2^m Sylvester Hadamard matrix, any two of them 2^(m-1) apart. Column 0 is constant over the rows,
+    // so without it 2^m rows fit in 2^m - 1 bits (simplex code). More classes use the complements of the rows as
+    // well (first order Reed-Muller code), which needs all 2^m columns. The remaining bits repeat columns.
+    let log2 = |n: usize| (usize::BITS - 1 - n.leading_zeros()) as usize;
+    let simplex_rows = 1usize << log2(bits + 1).min(usize::BITS as usize - 1);
+    let (rows, columns): (usize, Vec<usize>) = if classes <= simplex_rows {
+        let rows = simplex_rows;
+        (rows, (0..bits).map(|j| j % (rows - 1) + 1).collect())
+    } else {
+        let rows = 1usize << log2(bits);
+        if classes > 2 * rows {
+            return Err(format!(
+                "A Hadamard code of {} bits has at most {} codewords, not {}",
+                bits,
+                2 * rows,
+                classes
+            ));
+        }
+        (rows, (0..bits).map(|j| j % rows).collect())
+    };
+    Ok((0..classes)
+        .map(|class| {
+            let row = class % rows;
+            columns.iter().fold(0usize, |codeword, &column| {
+                let bit = ((row & column).count_ones() & 1 == 1) ^ (class >= rows);
+                (codeword << 1) | bit as usize
+            })
+        })
+        .collect())
+}
+
+pub fn search_codewords(classes: usize, bits: usize, seed: u64, iterations: usize) -> Vec<usize> {
+    // Seeded local search: start from random distinct codewords and flip single bits, keeping flips that don't
+    // lower the minimum distance or raise the number of pairs at it. Half the flips target a closest pair.
+    let mask = if bits == usize::BITS as usize {
+        usize::MAX
+    } else {
+        (1 << bits) - 1
+    };
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let mut codewords: Vec<usize> = Vec::with_capacity(classes);
+    while codewords.len() < classes {
+        let codeword = rng.next_u64() as usize & mask;
+        if !codewords.contains(&codeword) {
+            codewords.push(codeword);
+        }
+    }
+    let mut score = code_score(&codewords);
+    for _ in 0..iterations {
+        let (class, bit) = if rng.random_bool(0.5) {
+            let (a, b) = closest_pair(&codewords, &mut rng);
+            let class = if rng.random_bool(0.5) { a } else { b };
+            // Flipping a bit where the pair agrees moves them apart
+            let agree = !(codewords[a] ^ codewords[b]) & mask;
+            if agree == 0 {
+                continue;
+            }
+            let nth = rng.random_range(0..agree.count_ones());
+            let bit = (0..bits)
+                .filter(|&i| agree >> i & 1 == 1)
+                .nth(nth as usize)
+                .unwrap();
+            (class, bit)
+        } else {
+            (rng.random_range(0..classes), rng.random_range(0..bits))
+        };
+        let flipped = codewords[class] ^ (1 << bit);
+        if codewords.contains(&flipped) {
+            continue;
+        }
+        let previous = std::mem::replace(&mut codewords[class], flipped);
+        let new_score = code_score(&codewords);
+        if new_score >= score {
+            score = new_score;
+        } else {
+            codewords[class] = previous;
+        }
+    }
+    codewords
+}
+
+fn closest_pair(codewords: &[usize], rng: &mut Xoshiro256PlusPlus) -> (usize, usize) {
+    let d_min = min_distance(codewords);
+    let pairs: Vec<(usize, usize)> = (0..codewords.len())
+        .flat_map(|a| (a + 1..codewords.len()).map(move |b| (a, b)))
+        .filter(|&(a, b)| (codewords[a] ^ codewords[b]).count_ones() == d_min)
+        .collect();
+    pairs[rng.random_range(0..pairs.len())]
+}
+
+fn code_score(codewords: &[usize]) -> (u32, i64) {
+    // Larger minimum distance first, then fewer pairs at that distance
+    let d_min = min_distance(codewords);
+    let at_min = distance_matrix(codewords)
         .iter()
-        .map(|label_val| cfg.network.output_embedding[*label_val])
-        .fold(
-            BitVec::<u8, Msb0>::with_capacity(labels.len() * chunk_size),
-            |mut acc, embedding| {
-                // Get the bits and extend the accumulator. The borrow is temporary and safe.
-                let bits = embedding.view_bits::<Msb0>();
-                acc.extend_from_bitslice(&bits[usize::BITS as usize - chunk_size..]);
-                acc
-            },
-        )
+        .enumerate()
+        .map(|(a, row)| row[a + 1..].iter().filter(|&&d| d == d_min).count() as i64)
+        .sum::<i64>();
+    (d_min, -at_min)
 }
 
+pub fn distance_matrix(codewords: &[usize]) -> Vec<Vec<u32>> {
+    codewords
+        .iter()
+        .map(|a| codewords.iter().map(|b| (a ^ b).count_ones()).collect())
+        .collect()
+}
+
+pub fn min_distance(codewords: &[usize]) -> u32 {
+    let matrix = distance_matrix(codewords);
+    matrix
+        .iter()
+        .enumerate()
+        .flat_map(|(a, row)| row[a + 1..].iter().copied())
+        .min()
+        .unwrap_or(0)
+}
+
+pub fn code_report(codewords: &[usize], bits: usize) -> String {
+    // The codewords and their pairwise Hamming distances as a table
+    let d_min = min_distance(codewords);
+    let mut report = format!(
+        "Output code: {} codewords of {} bits, minimum distance {} (corrects {} wrong bits)\n",
+        codewords.len(),
+        bits,
+        d_min,
+        d_min.saturating_sub(1) / 2
+    );
+    let width = bits
+        .to_string()
+        .len()
+        .max(codewords.len().to_string().len())
+        + 1;
+    report.push_str(&format!(
+        "{:>w$} {:>b$}  ",
+        "",
+        "codeword",
+        w = width,
+        b = bits.max(8)
+    ));
+    for class in 0..codewords.len() {
+        report.push_str(&format!("{:>w$}", class, w = width));
+    }
+    report.push('\n');
+    for (class, row) in distance_matrix(codewords).iter().enumerate() {
+        report.push_str(&format!(
+            "{:>w$} {:>b$}  ",
+            class,
+            format!("{:0bits$b}", codewords[class], bits = bits),
+            w = width,
+            b = bits.max(8)
+        ));
+        for (other, d) in row.iter().enumerate() {
+            if other == class {
+                report.push_str(&format!("{:>w$}", "-", w = width));
+            } else {
+                report.push_str(&format!("{:>w$}", d, w = width));
+            }
+        }
+        report.push('\n');
+    }
+    report
+}
 
 #[cfg(test)]
 mod tests {
@@ -158,4 +411,44 @@ mod tests {
             "The calculated loss vector did not match the expected XOR result."
         );
     }
+
+    #[test]
+    fn generated_codes_are_distinct_and_far_apart() {
+        // 10 classes in 8 bits: the Reed-Muller code reaches distance 4, the hand-written default has 2
+        let hadamard = hadamard_codewords(10, 8).unwrap();
+        assert_eq!(min_distance(&hadamard), 4);
+        // 16 bits: rows of the 16x16 Hadamard matrix are 8 apart, without the constant column
+        let rows = hadamard_codewords(16, 15).unwrap();
+        assert_eq!(min_distance(&rows), 8);
+        assert!(hadamard_codewords(33, 16).is_err());
+
+        let code = OutputCode {
+            classes: 10,
+            construction: CodeConstruction::Search,
+            seed: 3,
+            iterations: 2000,
+        };
+        let searched = code.generate(12).unwrap();
+        assert_eq!(searched, code.generate(12).unwrap());
+        assert!(searched.iter().all(|&c| c < 1 << 12));
+        assert!(min_distance(&searched) >= 5);
+        let best = OutputCode {
+            construction: CodeConstruction::Best,
+            ..code
+        };
+        assert!(min_distance(&best.generate(12).unwrap()) >= min_distance(&searched));
+        // Codes that don't fit are config errors, not panics
+        assert!(code.validate(12).is_ok());
+        assert!(code.validate(3).is_err());
+        let hadamard_code = OutputCode {
+            classes: 33,
+            construction: CodeConstruction::Hadamard,
+            ..code
+        };
+        assert!(hadamard_code.generate(16).is_err());
+
+        let report = code_report(&hadamard, 8);
+        assert!(report.starts_with("Output code: 10 codewords of 8 bits, minimum distance 4"));
+        assert_eq!(report.lines().count(), 12);
+    }
 }
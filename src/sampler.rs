@@ -0,0 +1,237 @@
+use crate::{datacache::DataBits, settings::*, splits::permutation};
+use bitvec::prelude::*;
+
+// Decides which rows go into which batch every epoch. Without shuffling or stratification this
+// gives the same contiguous batches the trainers always used.
+
+pub struct BatchSampler {
+    rows: usize,
+    batch_size: usize,
+    sampling: Sampling,
+    labels: Option<Vec<usize>>, // only kept for stratified sampling
+}
+
+impl BatchSampler {
+    pub fn new(cfg: &Configuration, labels: &[usize]) -> Self {
+        BatchSampler {
+            rows: labels.len(),
+            batch_size: cfg.data.batch_size,
+            sampling: cfg.data.sampling.clone(),
+            labels: cfg.data.sampling.stratified.then(|| labels.to_vec()),
+        }
+    }
+
+    pub fn num_batches(&self) -> usize {
+        match self.sampling.remainder {
+            Remainder::Drop => self.rows / self.batch_size,
+            Remainder::Pad => self.rows.div_ceil(self.batch_size),
+        }
+    }
+
+    pub fn reshuffles(&self) -> bool {
+        // True when batch contents change between epochs
+        self.sampling.shuffle
+    }
+
+    pub fn epoch_order(&self, epoch: usize) -> Vec<usize> {
+        if !self.sampling.shuffle && !self.sampling.stratified {
+            return (0..self.rows).collect();
+        }
+        let seed = if self.sampling.shuffle {
+            // splitmix style mixing so neighbouring epochs get unrelated orders
+            (self.sampling.seed ^ (epoch as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15))
+                .wrapping_add(0xBF58_476D_1CE4_E5B9)
+        } else {
+            self.sampling.seed
+        };
+        permutation(self.rows, seed, self.labels.as_deref())
+    }
+
+    pub fn epoch_batches(&self, epoch: usize) -> Vec<Vec<usize>> {
+        let order = self.epoch_order(epoch);
+        (0..self.num_batches())
+            .map(|batch_num| {
+                let start = batch_num * self.batch_size;
+                let end = ((batch_num + 1) * self.batch_size).min(self.rows);
+                let mut batch = order[start..end].to_vec();
+                // Only happens for the padded remainder batch
+                pad_remainder(&mut batch, &order, self.batch_size);
+                batch
+            })
+            .collect()
+    }
+}
+
+pub fn pad_remainder<T: Clone>(batch: &mut Vec<T>, epoch_order: &[T], batch_size: usize) {
+    // Fills a short batch up to batch_size with the first rows of the epoch, again and again if the epoch has
+    // fewer rows than a batch. Every batch source pads this way, so Pad means the same thing for all of them.
+    // Only the first batch_size rows of epoch_order are ever read.
+    batch.extend(
+        epoch_order
+            .iter()
+            .cycle()
+            .take(batch_size - batch.len())
+            .cloned(),
+    );
+}
+
+pub fn load_batch(
+    cfg: &Configuration,
+    databits: &BitSlice<u8, Msb0>,
+    labels: &[usize],
+    rows: &[usize],
+    dbv: &mut BitVec<u8, Msb0>,
+) -> Vec<usize> {
+    // Copies the input bits of the given rows into dbv[..batch_bitcount] and returns their labels
+    let img_bitcount = cfg.derived.img_bitcount;
+    let contiguous = rows.windows(2).all(|w| w[1] == w[0] + 1);
+    if contiguous && !rows.is_empty() {
+        dbv[..rows.len() * img_bitcount].copy_from_bitslice(
+            &databits[rows[0] * img_bitcount..(rows[0] + rows.len()) * img_bitcount],
+        );
+    } else {
+        for (img_num_in_batch, &row) in rows.iter().enumerate() {
+            dbv[img_num_in_batch * img_bitcount..(img_num_in_batch + 1) * img_bitcount]
+                .copy_from_bitslice(&databits[row * img_bitcount..(row + 1) * img_bitcount]);
+        }
+    }
+    rows.iter().map(|&row| labels[row]).collect()
+}
+
+// Anything trainers can pull batches from. The batch is written into dbv[..batch_bitcount] and its labels are returned.
+pub trait BatchSource {
+    fn num_rows(&self) -> usize;
+    fn num_batches(&self) -> usize;
+    // True when the bits of a given batch number can change from one epoch to the next
+    fn varies_per_epoch(&self) -> bool;
+    // Must be called before loading the batches of an epoch
+    fn start_epoch(&mut self, epoch: usize);
+    fn load_batch(&mut self, batch_num: usize, dbv: &mut BitVec<u8, Msb0>) -> Vec<usize>;
+}
+
+pub struct InMemoryBatches {
+    cfg: Configuration,
+    databits: DataBits,
+    labels: Vec<usize>,
+    sampler: BatchSampler,
+    batches: Vec<Vec<usize>>,
+}
+
+impl InMemoryBatches {
+    pub fn new(cfg: &Configuration, databits: DataBits, labels: Vec<usize>) -> Self {
+        let sampler = BatchSampler::new(cfg, &labels);
+        InMemoryBatches {
+            cfg: cfg.clone(),
+            databits,
+            labels,
+            sampler,
+            batches: Vec::new(),
+        }
+    }
+}
+
+impl BatchSource for InMemoryBatches {
+    fn num_rows(&self) -> usize {
+        self.labels.len()
+    }
+
+    fn num_batches(&self) -> usize {
+        self.sampler.num_batches()
+    }
+
+    fn varies_per_epoch(&self) -> bool {
+        self.sampler.reshuffles()
+    }
+
+    fn start_epoch(&mut self, epoch: usize) {
+        self.batches = self.sampler.epoch_batches(epoch);
+    }
+
+    fn load_batch(&mut self, batch_num: usize, dbv: &mut BitVec<u8, Msb0>) -> Vec<usize> {
+        load_batch(
+            &self.cfg,
+            &self.databits,
+            &self.labels,
+            &self.batches[batch_num],
+            dbv,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampler_cfg(batch_size: usize, sampling: Sampling) -> Configuration {
+        let mut cfg = initialize_app_config_with_network(None);
+        cfg.data.batch_size = batch_size;
+        cfg.data.sampling = sampling;
+        cfg
+    }
+
+    #[test]
+    fn default_sampler_gives_contiguous_batches() {
+        let cfg = sampler_cfg(4, Sampling::default());
+        let labels = vec![0; 10];
+        let sampler = BatchSampler::new(&cfg, &labels);
+        assert_eq!(
+            sampler.epoch_batches(3),
+            vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7]]
+        );
+    }
+
+    #[test]
+    fn shuffled_padded_batches_cover_every_row() {
+        let cfg = sampler_cfg(
+            4,
+            Sampling {
+                shuffle: true,
+                stratified: false,
+                seed: 5,
+                remainder: Remainder::Pad,
+            },
+        );
+        let labels = vec![0; 10];
+        let sampler = BatchSampler::new(&cfg, &labels);
+        let epoch0 = sampler.epoch_batches(0);
+        let epoch1 = sampler.epoch_batches(1);
+        assert_eq!(epoch0.len(), 3);
+        assert!(epoch0.iter().all(|batch| batch.len() == 4));
+        let mut rows: Vec<usize> = epoch0.concat()[..10].to_vec();
+        rows.sort();
+        assert_eq!(rows, (0..10).collect::<Vec<_>>());
+        assert_ne!(epoch0, epoch1);
+        assert_eq!(epoch0, sampler.epoch_batches(0));
+    }
+
+    #[test]
+    fn stratified_batches_are_class_balanced() {
+        let cfg = sampler_cfg(
+            10,
+            Sampling {
+                shuffle: true,
+                stratified: true,
+                seed: 1,
+                remainder: Remainder::Drop,
+            },
+        );
+        let labels: Vec<usize> = (0..100).map(|i| i / 50).collect();
+        let sampler = BatchSampler::new(&cfg, &labels);
+        for batch in sampler.epoch_batches(2) {
+            assert_eq!(batch.iter().filter(|&&row| labels[row] == 1).count(), 5);
+        }
+    }
+
+    #[test]
+    fn load_batch_copies_selected_rows() {
+        let mut cfg = sampler_cfg(2, Sampling::default());
+        cfg.derived.img_bitcount = 4;
+        cfg.derived.batch_bitcount = 8;
+        let databits = bitvec![u8, Msb0; 0,0,0,1, 0,0,1,0, 0,1,0,0];
+        let labels = vec![7, 8, 9];
+        let mut dbv = bitvec![u8, Msb0; 0; 8];
+        let y = load_batch(&cfg, &databits, &labels, &[2, 0], &mut dbv);
+        assert_eq!(y, vec![9, 7]);
+        assert_eq!(dbv, bitvec![u8, Msb0; 0,1,0,0, 0,0,0,1]);
+    }
+}
@@ -0,0 +1,512 @@
+use crate::{
+    architectures::{
+        LUTNetBuilder,
+        builder::{GroupOp, GroupSpec, NetBuilder, Shape, input_shape},
+        spec::settings::{SpecSettings, get_aligned_cfg},
+    },
+    dataloader::training_batches,
+    evaluation::{evaluate, evaluation_cfg},
+    layered_config::resolved_table,
+    netcore::LUTNet,
+    settings::*,
+    utils::new_rng,
+};
+use config::{ConfigError, Value};
+use rand::prelude::*;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::PathBuf;
+
+// Architecture search over span networks. A search file has a [search] section with the space to sample from
+// and the budget, and a [trainer] section as in an experiment file; the rest is layered like a --config file.
+// Candidates are span layers, then optional random layers, then a Mix layer down to the output bits, with a
+// lut bank size picked from the space. All of them are trained for a few epochs and scored on the validation
+// split (data.val_split, the default Val split when unset). Successive halving then keeps the best 1/eta of
+// them and trains those eta times longer, until one is left or the rounds run out.
+// Every candidate is written to <out>/<name>_<id>.toml, a spec that -a accepts, and its model next to it.
+
+impl SearchManifest {
+    pub fn load() -> Result<Self, ConfigError> {
+        Value::new(None, resolved_table(None)?).try_deserialize()
+    }
+}
+
+impl Search {
+    pub fn validate(&self) -> Result<(), String> {
+        let err = |msg: &str| Err(format!("Invalid search {}: {}", self.name, msg));
+        if self.candidates == 0 || self.epochs == 0 {
+            return err("candidates and epochs must be non-zero");
+        }
+        if self.eta < 2 {
+            return err("eta must be at least 2");
+        }
+        if self.rounds == Some(0) || self.chunk == 0 {
+            return err("rounds and chunk must be non-zero");
+        }
+        self.space.validate()
+    }
+
+    pub fn schedule(&self) -> Vec<(usize, usize)> {
+        halving_schedule(self.candidates, self.eta, self.epochs, self.rounds)
+    }
+
+    pub fn spec_path(&self, id: usize) -> PathBuf {
+        PathBuf::from(format!("{}/{}_{}.toml", self.out, self.name, id))
+    }
+
+    pub fn model_path(&self, id: usize) -> String {
+        format!("{}/{}_{}.ltnet", self.out, self.name, id)
+    }
+
+    pub fn leaderboard_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}/{}_leaderboard.csv", self.out, self.name))
+    }
+}
+
+pub fn halving_schedule(
+    candidates: usize,
+    eta: usize,
+    epochs: usize,
+    rounds: Option<usize>,
+) -> Vec<(usize, usize)> {
+    // (candidates trained, epochs each) of every round. Survivors carry on from where they were, so a candidate
+    // that makes it to round r has been trained epochs * (1 + eta + ... + eta^r) epochs.
+    let mut schedule = vec![(candidates, epochs)];
+    while rounds.is_none_or(|r| schedule.len() < r) {
+        let (count, epochs) = *schedule.last().unwrap();
+        if count.div_ceil(eta) <= 1 {
+            break;
+        }
+        schedule.push((count.div_ceil(eta), epochs * eta));
+    }
+    schedule
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub layers: Vec<Vec<GroupSpec>>,
+    pub lut_bank_size: usize,
+}
+
+impl SearchSpace {
+    pub fn validate(&self) -> Result<(), String> {
+        let err = |msg: &str| Err(format!("Invalid search space: {}", msg));
+        let ordered = |(low, high): (usize, usize)| low <= high;
+        if self.span_lens.is_empty() || self.span_hops.is_empty() {
+            return err("span_lens and span_hops can't be empty");
+        }
+        if self.depth.0 == 0 || !ordered(self.depth) {
+            return err("depth must be a range of at least 1 layer");
+        }
+        if !ordered(self.random_layers)
+            || (self.random_layers.1 > 0 && self.random_widths.is_empty())
+        {
+            return err("random_layers must be a range, and random layers need random_widths");
+        }
+        if self.output_nodes == 0 {
+            return err("output_nodes must be non-zero");
+        }
+        if self.fan_in.0 == 0 || self.fan_in.1 > 6 || !ordered(self.fan_in) {
+            return err("fan_in must be a range within 1..=6");
+        }
+        Ok(())
+    }
+
+    pub fn sample(&self, input: Shape, rng: &mut Xoshiro256PlusPlus) -> Result<Candidate, String> {
+        // Span layers stop early when no span of the space fits any more. Layers never get narrower than the
+        // output.
+        let bits = |shape: Shape| shape.0 * shape.1 * shape.2;
+        let mut net = NetBuilder::new(input);
+        let mut layers = Vec::new();
+        let mut push = |net: &mut NetBuilder, group: GroupSpec| {
+            net.layer(vec![group.clone()])
+                .map(|_| layers.push(vec![group]))
+        };
+        let mut spans: Vec<(Shape, Shape)> = self
+            .span_lens
+            .iter()
+            .flat_map(|&len| self.span_hops.iter().map(move |&hop| (len, hop)))
+            .collect();
+        for _ in 0..rng.random_range(self.depth.0..=self.depth.1) {
+            spans.shuffle(rng);
+            let fits = spans.iter().find(|&&(len, hop)| {
+                GroupOp::Span { len, hop }
+                    .output_shape(net.shape())
+                    .is_ok_and(|shape| bits(shape) >= self.output_nodes)
+            });
+            match fits {
+                Some(&(len, hop)) => push(&mut net, GroupSpec::span(len, hop))?,
+                None => break,
+            }
+        }
+        if net.layer_sizes().is_empty() {
+            return Err(format!(
+                "No span of the search space fits the input {:?}",
+                input
+            ));
+        }
+        for _ in 0..rng.random_range(self.random_layers.0..=self.random_layers.1) {
+            let size = net.last().size;
+            let widths: Vec<usize> = self
+                .random_widths
+                .iter()
+                .copied()
+                .filter(|&w| w < size && w >= self.output_nodes)
+                .collect();
+            match widths.choose(rng) {
+                Some(&nodes) => push(&mut net, GroupSpec::random(nodes))?,
+                None => break,
+            }
+        }
+        let fan_in = rng.random_range(self.fan_in.0..=self.fan_in.1);
+        push(
+            &mut net,
+            GroupSpec::new(GroupOp::Mix {
+                nodes: self.output_nodes,
+                fan_in,
+            }),
+        )?;
+        Ok(Candidate {
+            layers,
+            lut_bank_size: self.lut_bank_sizes.choose(rng).copied().unwrap_or(0),
+        })
+    }
+
+    pub fn sample_distinct(
+        &self,
+        input: Shape,
+        count: usize,
+        rng: &mut Xoshiro256PlusPlus,
+    ) -> Result<Vec<Candidate>, String> {
+        // Up to count different candidates, fewer when the space is smaller than that
+        let mut candidates: Vec<Candidate> = Vec::with_capacity(count);
+        for _ in 0..count * 20 {
+            if candidates.len() == count {
+                break;
+            }
+            let candidate = self.sample(input, rng)?;
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+        Ok(candidates)
+    }
+}
+
+fn group_toml(group: &GroupSpec) -> String {
+    let shape = |s: Shape| format!("[{}, {}, {}]", s.0, s.1, s.2);
+    match group.op {
+        GroupOp::Span { len, hop } => format!(
+            "{{ op = {{ Span = {{ len = {}, hop = {} }} }} }}",
+            shape(len),
+            shape(hop)
+        ),
+        GroupOp::Random { nodes } => format!("{{ op = {{ Random = {{ nodes = {} }} }} }}", nodes),
+        GroupOp::Mix { nodes, fan_in } => format!(
+            "{{ op = {{ Mix = {{ nodes = {}, fan_in = {} }} }} }}",
+            nodes, fan_in
+        ),
+        _ => unreachable!("The search only samples Span, Random and Mix groups"),
+    }
+}
+
+impl Candidate {
+    pub fn summary(&self) -> String {
+        // One line, e.g. span 6x1x1/1x1x1 > random 64 > mix 8/6, bank 0
+        let shape = |s: Shape| format!("{}x{}x{}", s.0, s.1, s.2);
+        let layers: Vec<String> = self
+            .layers
+            .iter()
+            .map(|groups| match groups[0].op {
+                GroupOp::Span { len, hop } => format!("span {}/{}", shape(len), shape(hop)),
+                GroupOp::Random { nodes } => format!("random {}", nodes),
+                GroupOp::Mix { nodes, fan_in } => format!("mix {}/{}", nodes, fan_in),
+                _ => unreachable!("The search only samples Span, Random and Mix groups"),
+            })
+            .collect();
+        format!("{}, bank {}", layers.join(" > "), self.lut_bank_size)
+    }
+
+    pub fn spec(&self, network: &Network) -> SpecSettings {
+        // The candidate with the output of the main settings
+        SpecSettings {
+            output_embedding: network.output_embedding.clone(),
+            lut_bank_size: self.lut_bank_size,
+            output_code: network.output_code.clone(),
+            layers: self.layers.clone(),
+            heads: None,
+        }
+    }
+
+    pub fn spec_toml(&self, header: &str, output_embedding: &[usize]) -> String {
+        // Spec file of the candidate, with the embedding it was trained with rather than the code that made it
+        let mut toml = String::new();
+        for line in header.lines() {
+            writeln!(toml, "# {}", line).unwrap();
+        }
+        writeln!(toml, "[network]\noutput_embedding = {:?}", output_embedding).unwrap();
+        writeln!(toml, "lut_bank_size = {}\nlayers = [", self.lut_bank_size).unwrap();
+        for groups in &self.layers {
+            let groups: Vec<String> = groups.iter().map(group_toml).collect();
+            writeln!(toml, "    [{}],", groups.join(", ")).unwrap();
+        }
+        toml.push_str("]\n");
+        toml
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaderboardEntry {
+    pub id: usize,
+    pub summary: String,
+    pub nodes: usize,
+    pub rounds: usize, // Rounds the candidate was trained in
+    pub epochs: usize, // Epochs it was trained for in total
+    pub accuracy: f64, // Validation accuracy after its last round
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Leaderboard {
+    pub entries: Vec<LeaderboardEntry>, // By id
+}
+
+impl Leaderboard {
+    pub fn ranked(&self) -> Vec<&LeaderboardEntry> {
+        // Candidates that lasted more rounds first, then by accuracy. Accuracies of different rounds aren't
+        // comparable, the later ones had more training.
+        let mut ranked: Vec<&LeaderboardEntry> = self.entries.iter().collect();
+        ranked.sort_by(|a, b| {
+            b.rounds
+                .cmp(&a.rounds)
+                .then(b.accuracy.total_cmp(&a.accuracy))
+                .then(a.id.cmp(&b.id))
+        });
+        ranked
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("rank,id,rounds,epochs,nodes,accuracy,architecture\n");
+        for (rank, e) in self.ranked().iter().enumerate() {
+            writeln!(
+                csv,
+                "{},{},{},{},{},{:.6},\"{}\"",
+                rank + 1,
+                e.id,
+                e.rounds,
+                e.epochs,
+                e.nodes,
+                e.accuracy,
+                e.summary
+            )
+            .unwrap();
+        }
+        csv
+    }
+}
+
+impl fmt::Display for Leaderboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>5}{:>5}{:>8}{:>8}{:>8}{:>10}  architecture",
+            "rank", "id", "rounds", "epochs", "nodes", "accuracy"
+        )?;
+        for (rank, e) in self.ranked().iter().enumerate() {
+            writeln!(
+                f,
+                "{:>5}{:>5}{:>8}{:>8}{:>8}{:>9.2}%  {}",
+                rank + 1,
+                e.id,
+                e.rounds,
+                e.epochs,
+                e.nodes,
+                e.accuracy * 100.0,
+                e.summary
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn run_search(search: &Search, trainer: &Trainer) -> Result<Leaderboard, Box<dyn Error>> {
+    // Samples, trains and halves the candidates. The leaderboard is rewritten after every round.
+    search.validate()?;
+    let base = initialize_app_config_with_network(None);
+    let mut rng = new_rng();
+    let candidates =
+        search
+            .space
+            .sample_distinct(input_shape(&base.data), search.candidates, &mut rng)?;
+    if candidates.len() < search.candidates {
+        println!(
+            "The search space only gave {} distinct candidates out of {}",
+            candidates.len(),
+            search.candidates
+        );
+    }
+    fs::create_dir_all(&search.out)?;
+
+    let mut leaderboard = Leaderboard::default();
+    let mut alive: Vec<(usize, Configuration, LUTNet)> = Vec::new();
+    for candidate in &candidates {
+        // Every candidate gets its own configuration, the global one stays as the settings made it
+        let spec = candidate.spec(&base.network);
+        let cfg = get_aligned_cfg(&spec)?;
+        if let Err(e) = trainer.validate(&cfg) {
+            // e.g. a lut bank, which the bitflip trainer can't train
+            println!("Skipping candidate {}: {}", candidate.summary(), e);
+            continue;
+        }
+        let id = alive.len();
+        let header = format!(
+            "Candidate {} of search {}: {}\nIts model is {}",
+            id,
+            search.name,
+            candidate.summary(),
+            search.model_path(id)
+        );
+        fs::write(
+            search.spec_path(id),
+            candidate.spec_toml(&header, &cfg.network.output_embedding),
+        )?;
+        let ltnet = spec.net_builder(input_shape(&cfg.data))?.build(&cfg);
+        leaderboard.entries.push(LeaderboardEntry {
+            id,
+            summary: candidate.summary(),
+            nodes: ltnet.nodes.len(),
+            rounds: 0,
+            epochs: 0,
+            accuracy: 0.0,
+        });
+        alive.push((id, cfg, ltnet));
+    }
+    if alive.is_empty() {
+        return Err(format!("None of the candidates can be trained with {:?}", trainer).into());
+    }
+
+    for (round, (count, epochs)) in search.schedule().into_iter().enumerate() {
+        alive.truncate(count);
+        println!(
+            "Round {}: training {} candidates for {} epochs",
+            round,
+            alive.len(),
+            epochs
+        );
+        let round_trainer = trainer.with_epochs(epochs);
+        for (id, cfg, ltnet) in alive.iter_mut() {
+            let model_filename = search.model_path(*id);
+            let mut batches = training_batches(cfg)?;
+            round_trainer.train(ltnet, cfg, batches.as_mut(), &model_filename);
+            // Trainers only save improvements, the model file always holds the candidate as scored
+            fs::write(
+                &model_filename,
+                bincode::encode_to_vec(&*ltnet, bincode::config::standard())?,
+            )?;
+            let split = cfg.data.val_split.clone().unwrap_or(DataSplit::Val(10000));
+            let entry = &mut leaderboard.entries[*id];
+            entry.accuracy =
+                evaluate(ltnet, &evaluation_cfg(cfg, &split, search.chunk))?.accuracy();
+            entry.rounds = round + 1;
+            entry.epochs += epochs;
+            println!(
+                "Candidate {}: {:.2}% validation accuracy after {} epochs ({})",
+                id,
+                entry.accuracy * 100.0,
+                entry.epochs,
+                entry.summary
+            );
+        }
+        alive.sort_by(|a, b| {
+            let accuracy = |id: usize| leaderboard.entries[id].accuracy;
+            accuracy(b.0).total_cmp(&accuracy(a.0)).then(a.0.cmp(&b.0))
+        });
+        fs::write(search.leaderboard_path(), leaderboard.to_csv())?;
+    }
+    Ok(leaderboard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn space() -> SearchSpace {
+        SearchSpace {
+            span_lens: vec![(6, 1, 1), (1, 3, 2), (1, 2, 3)],
+            span_hops: vec![(1, 1, 1), (1, 2, 2)],
+            depth: (2, 4),
+            random_layers: (0, 1),
+            random_widths: vec![32, 64],
+            output_nodes: 8,
+            fan_in: (4, 6),
+            lut_bank_sizes: vec![0, 256],
+        }
+    }
+
+    #[test]
+    fn halving_keeps_a_fraction_for_longer() {
+        assert_eq!(
+            halving_schedule(8, 2, 1, None),
+            vec![(8, 1), (4, 2), (2, 4)]
+        );
+        assert_eq!(
+            halving_schedule(10, 3, 5, None),
+            vec![(10, 5), (4, 15), (2, 45)]
+        );
+        assert_eq!(halving_schedule(8, 2, 1, Some(2)), vec![(8, 1), (4, 2)]);
+        assert_eq!(halving_schedule(1, 2, 3, None), vec![(1, 3)]);
+    }
+
+    #[test]
+    fn sampled_candidates_fit_and_round_trip_as_specs() {
+        let mnist = (8, 28, 28);
+        let space = space();
+        assert!(space.validate().is_ok());
+        let mut rng = new_rng();
+        let candidates = space.sample_distinct(mnist, 6, &mut rng).unwrap();
+        assert_eq!(candidates.len(), 6);
+        for candidate in &candidates {
+            let net = NetBuilder::with_layers(mnist, &candidate.layers).unwrap();
+            let sizes = net.layer_sizes();
+            assert_eq!(*sizes.last().unwrap(), 8);
+            assert!(sizes.iter().all(|&size| size >= 8));
+            assert!((3..=6).contains(&sizes.len()));
+
+            let toml = candidate.spec_toml("A candidate", &[1, 2]);
+            let spec = SpecSettings::new("search_candidate", &toml).unwrap();
+            assert_eq!(spec.layers, candidate.layers);
+            assert_eq!(spec.lut_bank_size, candidate.lut_bank_size);
+        }
+
+        // Nothing fits a 1 x 4 x 1 input
+        assert!(space.sample((1, 4, 1), &mut rng).is_err());
+        let no_widths = SearchSpace {
+            random_widths: vec![],
+            ..space
+        };
+        assert!(no_widths.validate().is_err());
+    }
+
+    #[test]
+    fn leaderboard_ranks_survivors_first() {
+        let entry = |id, rounds, accuracy| LeaderboardEntry {
+            id,
+            summary: format!("candidate {}", id),
+            nodes: 10,
+            rounds,
+            epochs: rounds,
+            accuracy,
+        };
+        let leaderboard = Leaderboard {
+            entries: vec![entry(0, 1, 0.9), entry(1, 2, 0.5), entry(2, 2, 0.7)],
+        };
+        let ids: Vec<usize> = leaderboard.ranked().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![2, 1, 0]);
+        assert!(
+            leaderboard.to_csv().starts_with(
+                "rank,id,rounds,epochs,nodes,accuracy,architecture\n1,2,2,2,10,0.700000"
+            )
+        );
+    }
+}
@@ -1,32 +1,309 @@
-use config::{Config, ConfigError, File};
-use serde::Deserialize;
-use std::{path::PathBuf, sync::OnceLock};
+use crate::{
+    layered_config::*,
+    processing::code_report,
+    tabular::{LOADED_ENCODER, TabularEncoder},
+};
+use bincode::{Decode, Encode};
+use config::ConfigError;
+use serde::{Deserialize, Serialize};
+use std::sync::{Once, OnceLock};
 
 pub static APP_CFG: OnceLock<Configuration> = OnceLock::new();
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum DataSource {
+    Train, // rows of train_filepath
+    Test,  // rows of test_filepath
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub enum DataSplit {
+    // Shortcuts: first rows of the train file, rows of the train file from 50000 onwards, first rows of the test file
     Train(u32),
     Val(u32),
     Test(u32),
+    // Explicit rows start..end of a file
+    Range {
+        source: DataSource,
+        start: usize,
+        end: usize,
+    },
+    // Rows between two fractions of a file, e.g. 0.0..0.8
+    Fraction {
+        source: DataSource,
+        start: f64,
+        end: f64,
+    },
+    // Positions start..end of a seeded random permutation of the file. Splits with the same seed never overlap if their ranges don't.
+    Permuted {
+        source: DataSource,
+        seed: u64,
+        start: usize,
+        end: usize,
+        #[serde(default)]
+        stratified: bool,
+    },
+    // Fold `fold` of k. The holdout fold when holdout is true, all other folds otherwise.
+    KFold {
+        source: DataSource,
+        k: usize,
+        fold: usize,
+        seed: u64,
+        #[serde(default)]
+        stratified: bool,
+        #[serde(default)]
+        holdout: bool,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Raw, // The 8 raw bits of each pixel, most significant bit first
+    Threshold(usize), // Quantize to k uniform levels and write the level index in binary
+    Thermometer(usize), // Quantize to k uniform levels and write the level in unary (k - 1 bits)
+    Gray(usize),      // Gray code of the top n bits of each pixel
+    Quantile {
+        levels: usize,
+        fit_rows: usize,
+    }, // Per-pixel quantile thresholds fitted on the first fit_rows training rows, written in unary
+    Dither(usize),    // Ordered (Bayer) dithering to k levels, written in unary
+}
+
+impl Encoding {
+    pub fn bits_per_pixel(&self) -> usize {
+        match *self {
+            Encoding::Raw => 8,
+            Encoding::Threshold(levels) => {
+                (usize::BITS - levels.saturating_sub(1).leading_zeros()) as usize
+            }
+            Encoding::Thermometer(levels) | Encoding::Dither(levels) => levels.saturating_sub(1),
+            Encoding::Gray(bits) => bits,
+            Encoding::Quantile { levels, .. } => levels.saturating_sub(1),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let ok = match *self {
+            Encoding::Raw => true,
+            Encoding::Threshold(levels) => (2..=256).contains(&levels),
+            Encoding::Thermometer(levels) | Encoding::Dither(levels) => (2..=256).contains(&levels),
+            Encoding::Gray(bits) => (1..=8).contains(&bits),
+            Encoding::Quantile { levels, fit_rows } => (2..=256).contains(&levels) && fit_rows > 0,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(format!(
+                "Invalid encoding {:?}: levels must be in 2..=256, Gray bits in 1..=8 and fit_rows non-zero",
+                self
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum Remainder {
+    #[default]
+    Drop, // Rows beyond num_batches * batch_size are not used in the epoch
+    Pad, // The last partial batch is filled up with rows from the start of the epoch
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Sampling {
+    #[serde(default)]
+    pub shuffle: bool, // Reshuffle rows into new batches every epoch
+    #[serde(default)]
+    pub stratified: bool, // Spread every class evenly over the batches
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default)]
+    pub remainder: Remainder,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Augmentation {
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default)]
+    pub max_shift: usize, // Random translation by up to this many pixels in each direction
+    #[serde(default)]
+    pub max_rotation_deg: f32, // Random rotation in -max..max degrees around the image centre
+    #[serde(default)]
+    pub elastic_alpha: f32, // Strength of elastic distortion in pixels, 0 disables it
+    #[serde(default = "default_elastic_sigma")]
+    pub elastic_sigma: f32, // Smoothness of the elastic displacement field
+    #[serde(default)]
+    pub erase_prob: f32, // Probability of blanking a random rectangle
+    #[serde(default)]
+    pub erase_max_size: usize, // Maximum side of the blanked rectangle
+    #[serde(default)]
+    pub noise_prob: f32, // Probability of replacing each pixel with a random value
+}
+
+fn default_elastic_sigma() -> f32 {
+    4.0
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub enum SyntheticTask {
+    Parity {
+        bits: usize,
+    }, // Label is the xor of all bits
+    Majority {
+        bits: usize,
+    }, // Label is 1 when more than half the bits are set
+    Multiplexer {
+        select_bits: usize,
+    }, // The first select_bits bits pick one of the 2^select_bits data bits
+    Comparator {
+        bits: usize,
+    }, // Label is 1 when the first bits-wide number is larger than the second
+    SparseFormula {
+        inputs: usize,
+        relevant: usize,
+        seed: u64,
+    }, // Random tree of LUTs over `relevant` of the inputs, the rest are distractors
+    Teacher {
+        inputs: usize,
+        layer_sizes: Vec<usize>,
+        seed: u64,
+    }, // Random layered LUT network, the label is its output bits read as a number
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Streaming {
+    #[serde(default = "default_prefetch_batches")]
+    pub prefetch_batches: usize, // Batches encoded ahead of the trainer by the background thread
+    #[serde(default = "default_shuffle_window")]
+    pub shuffle_window: usize, // With sampling.shuffle, rows are shuffled within windows of this many batches
+}
+
+fn default_prefetch_batches() -> usize {
+    4
+}
+
+fn default_shuffle_window() -> usize {
+    8
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Tabular {
+    pub label_column: String,
+    #[serde(default)]
+    pub categorical: Vec<String>, // Columns to one-hot encode even if they look numeric. Non-numeric columns always are.
+    #[serde(default)]
+    pub ignore: Vec<String>, // Columns left out of the input, e.g. row ids
+    #[serde(default = "default_tabular_levels")]
+    pub levels: usize, // Quantile levels per numeric column, written in unary with levels - 1 bits
+    #[serde(default = "default_max_categories")]
+    pub max_categories: usize, // Only the most frequent categories get a bit
+    #[serde(skip)]
+    pub encoder: Option<TabularEncoder>, // Fitted when the configuration is built, or loaded with the model
+}
+
+fn default_tabular_levels() -> usize {
+    4
+}
+
+fn default_max_categories() -> usize {
+    16
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Synthetic {
+    pub task: SyntheticTask,
+    #[serde(default)]
+    pub seed: u64, // Seed for the input bits. Train and test sources use different streams.
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Data {
-    pub dim1: usize, // 8 bits to represent each pixel
+    #[serde(default)]
+    pub dim1: usize, // bits used to represent each pixel, derived from the encoding
     pub dim2: usize, // 28 pixels wide
     pub dim3: usize, // 28 pixels tall
     pub train_filepath: String,
     pub test_filepath: String,
     pub datasplit: DataSplit, // Data split and number of rows to be loaded
+    #[serde(default)]
+    pub val_split: Option<DataSplit>, // Optional second split, e.g. to monitor validation during training
+    #[serde(default = "default_train_rows")]
+    pub train_rows: usize, // Total rows in train_filepath
+    #[serde(default = "default_test_rows")]
+    pub test_rows: usize, // Total rows in test_filepath
     pub batch_size: usize,
+    #[serde(default)]
+    pub sampling: Sampling,
+    #[serde(default)]
+    pub augmentation: Option<Augmentation>, // Applied to training batches on the fly, before binarization
+    #[serde(default)]
+    pub streaming: Option<Streaming>, // Read training batches from the CSV every epoch instead of holding the split in memory
+    #[serde(default)]
+    pub encoding: Encoding,
+    #[serde(default)]
+    pub cache_dir: Option<String>, // Encoded splits are cached here and memory mapped on later runs. No caching if unset.
+    #[serde(default)]
+    pub synthetic: Option<Synthetic>, // Generated boolean task used instead of the CSV files. Overrides dim1/dim2/dim3.
+    #[serde(default)]
+    pub tabular: Option<Tabular>, // Read train/test files as tabular data instead of images. Overrides dim1/dim2/dim3.
+}
+
+fn default_train_rows() -> usize {
+    60000
+}
+
+fn default_test_rows() -> usize {
+    10000
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Network {
+    #[serde(default)]
     pub output_embedding: Vec<usize>,
     pub layer_sizes: Vec<usize>,
     pub lut_bank_size: usize,
+    #[serde(default)]
+    pub output_code: Option<OutputCode>, // Generates output_embedding for the width of the last layer when set
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub enum CodeConstruction {
+    #[default]
+    Best, // Whichever of the two below has the larger minimum distance
+    Hadamard, // Hadamard rows and their complements, up to twice the largest power of 2 <= bits codewords
+    Search,   // Seeded local search, any number of classes that fits the bits
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct OutputCode {
+    pub classes: usize,
+    #[serde(default)]
+    pub construction: CodeConstruction,
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default = "default_code_search_iterations")]
+    pub iterations: usize,
+}
+
+fn default_code_search_iterations() -> usize {
+    20000
+}
+
+#[derive(Debug, Serialize, Deserialize, Encode, Decode, Clone, Default, PartialEq)]
+#[serde(tag = "head")]
+pub enum Decoder {
+    // How the last layer's bits become a class, and the loss the trainers minimize to get there.
+    // Kept out of [network] so it can be set for models loaded from file, which otherwise decode with their own.
+    #[default]
+    Nearest, // Nearest output_embedding codeword by Hamming distance, ties to the lowest class
+    GroupVote, // Last layer split into equal groups, one per class. The group with the most ones wins.
+    Weighted {
+        bit_weights: Vec<u32>,
+    }, // Nearest codeword, each differing bit costing its weight (first = leftmost bit)
+    Margin {
+        min_margin: u32,
+    }, // Nearest codeword, rejected unless the runner up is at least min_margin further away
 }
 
 #[derive(Debug, Clone)]
@@ -48,11 +325,7 @@ pub struct DerivedValues {
 
 impl DerivedValues {
     pub fn new(data: &Data, network: &Network) -> Self {
-        let rows = match data.datasplit {
-            DataSplit::Train(r) => r as usize,
-            DataSplit::Val(r) => r as usize,
-            DataSplit::Test(r) => r as usize,
-        };
+        let rows = data.datasplit.num_rows(data);
         let cols = data.dim2 * data.dim3;
         let img_bitcount = data.dim1 * data.dim2 * data.dim3;
         let data_bitcount = rows * img_bitcount;
@@ -69,7 +342,10 @@ impl DerivedValues {
         let bitvec_size = data.batch_size * (img_bitcount + network_size);
         let bitvec_edges: Vec<usize> = layer_edges.iter().map(|&x| x * data.batch_size).collect();
         let batch_bitcount = data.batch_size * img_bitcount;
-        let num_batches = rows / data.batch_size; // If batch size does not divide rows, some data will be ignored
+        let num_batches = match data.sampling.remainder {
+            Remainder::Drop => rows / data.batch_size, // If batch size does not divide rows, some data will be ignored
+            Remainder::Pad => rows.div_ceil(data.batch_size),
+        };
         let &output_bitsize = network.layer_sizes.last().unwrap();
         Self {
             rows,
@@ -89,20 +365,137 @@ impl DerivedValues {
     }
 }
 
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "algorithm")]
+pub enum Trainer {
+    NaiveEvolutionary {
+        corruption_ratio: f32,     // Fraction of nodes given new LUTs in each candidate
+        lut_sampling_depth: usize, // Candidates tried per batch
+        #[serde(default)]
+        rewire_probability: f32, // Chance a picked node moves one input to another bit instead of a LUT change
+        #[serde(default)]
+        heads: usize, // Split the output into this many heads and mutate one at a time, 0 for all nodes at once
+        epochs: usize,
+    },
+    BitflipEvolutionary {
+        corruption_ratio: f32,
+        lut_sampling_depth: usize,
+        #[serde(default)]
+        rewire_probability: f32,
+        #[serde(default)]
+        heads: usize,
+        epochs: usize,
+    },
+    PivNodeFlipper {
+        epochs: usize,
+        write_freq: usize, // Save the model every this many batches
+    },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Experiment {
+    pub name: String,
+    pub architecture: String, // As passed to -a, its settings go in architectures.<name>
+    #[serde(default)]
+    pub seed: Option<u64>, // Seeds network initialization and the trainer. Data seeds are in the data section.
+    pub model: String, // Output model file, without the .ltnet extension
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Manifest {
+    // The experiment and trainer sections of an experiment file. Its data and architecture sections are
+    // read through the usual settings layers, the experiment file being the --config file.
+    pub experiment: Experiment,
+    pub trainer: Trainer,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SearchSpace {
+    // Candidates are span layers picked from these lengths and hops, then random layers, then a Mix layer
+    // down to the output bits. Ranges are inclusive.
+    pub span_lens: Vec<(usize, usize, usize)>, // Each covering 6 bits
+    pub span_hops: Vec<(usize, usize, usize)>,
+    pub depth: (usize, usize), // Span layers, fewer when no span fits any more
+    #[serde(default)]
+    pub random_layers: (usize, usize),
+    #[serde(default)]
+    pub random_widths: Vec<usize>, // Random layers only take widths smaller than the layer before
+    pub output_nodes: usize,
+    #[serde(default = "default_search_fan_in")]
+    pub fan_in: (usize, usize), // Fan-in of the Mix layer
+    #[serde(default)]
+    pub lut_bank_sizes: Vec<usize>, // Empty or [0] for no lut bank
+}
+
+fn default_search_fan_in() -> (usize, usize) {
+    (6, 6)
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Search {
+    pub name: String,
+    pub candidates: usize,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    pub epochs: usize, // Trainer epochs of the first round, later rounds train eta times longer
+    #[serde(default = "default_search_eta")]
+    pub eta: usize, // Every round keeps the best 1/eta of the candidates
+    #[serde(default)]
+    pub rounds: Option<usize>, // Stop after this many rounds instead of when one candidate is left
+    #[serde(default = "default_search_chunk")]
+    pub chunk: usize, // Validation samples per forward pass
+    pub out: String,   // Directory for the candidate models and specs, and the leaderboard
+    pub space: SearchSpace,
+}
+
+fn default_search_eta() -> usize {
+    2
+}
+
+fn default_search_chunk() -> usize {
+    1000
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SearchManifest {
+    // The search and trainer sections of a search file, read like an experiment file
+    pub search: Search,
+    pub trainer: Trainer,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub enum LossKind {
+    #[default]
+    Decoder, // The loss matching the decoder head
+    Bits,    // Output bits that differ from the true class's target
+    Margin, // Distance to the true target minus distance to the nearest wrong one, shifted to be >= 0
+    ZeroOne, // Misclassified samples, rejected ones included
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct LossSettings {
+    #[serde(default)]
+    pub kind: LossKind,
+    #[serde(default)]
+    pub class_weights: Vec<u32>, // Loss of a sample is multiplied by the weight of its true class. Empty = all 1.
+    #[serde(default)]
+    pub bit_weights: Vec<u32>, // Cost of each wrong output bit (first = leftmost) for Bits and Margin. Empty = all 1.
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub data: Data,
     pub network: Network,
+    #[serde(default)]
+    pub decoder: Decoder,
+    #[serde(default)]
+    pub loss: LossSettings,
 }
 
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
-        let mut settings_path = PathBuf::from(crate::HOME_DIR);
-        settings_path.push("Settings.toml");
-        let s = Config::builder()
-            .add_source(File::from(settings_path))
-            .build()?;
-        s.try_deserialize()
+        // Built-in defaults, then --config, LUTNETS_* environment variables and --set overrides
+        layered_settings()
     }
 }
 
@@ -110,18 +503,66 @@ impl Settings {
 pub struct Configuration {
     pub data: Data,
     pub network: Network,
+    pub decoder: Decoder,
+    pub loss: LossSettings,
     pub derived: DerivedValues,
 }
 
+impl Configuration {
+    pub fn for_split(&self, split: DataSplit, batch_size: Option<usize>) -> Configuration {
+        // Same network and data settings, but a different split (and optionally batch size). Lets one process hold train and validation configs side by side.
+        let mut cfg = self.clone();
+        cfg.data.datasplit = split;
+        if let Some(batch_size) = batch_size {
+            cfg.data.batch_size = batch_size;
+        }
+        cfg.derived = DerivedValues::new(&cfg.data, &cfg.network);
+        cfg
+    }
+}
+
 pub fn initialize_app_config_with_network(network: Option<Network>) -> Configuration {
+    initialize_app_config_with(|_| network)
+}
+
+pub fn initialize_app_config_with(network: impl FnOnce(&Data) -> Option<Network>) -> Configuration {
+    // network gets the data settings with the input dims filled in, so it can size its layers to the input.
+    // None keeps the network of the main settings.
     let mut settings = Settings::new().expect("Failed to load configuration");
-    if let Some(network) = network {
+    settings.data.dim1 = settings.data.encoding.bits_per_pixel();
+    if let Some(synthetic) = &settings.data.synthetic {
+        // Synthetic inputs are already bits, laid out as a single row
+        (settings.data.dim1, settings.data.dim2, settings.data.dim3) =
+            (1, synthetic.task.num_inputs(), 1);
+    }
+    if let Some(tabular) = &settings.data.tabular {
+        // The input size depends on what the encoder learns from the training split
+        let encoder = match LOADED_ENCODER.get() {
+            Some(encoder) => encoder.clone(),
+            None => TabularEncoder::from_cfg(&settings.data, tabular)
+                .expect("Failed to fit the tabular encoder"),
+        };
+        (settings.data.dim1, settings.data.dim2, settings.data.dim3) = (1, encoder.bit_width(), 1);
+        settings.data.tabular.as_mut().unwrap().encoder = Some(encoder);
+    }
+    if let Some(network) = network(&settings.data) {
         settings.network = network;
     }
+    if let Some(code) = &settings.network.output_code {
+        // A code that doesn't fit the output layer keeps output_embedding, Configuration::validate reports it
+        let bits = settings.network.layer_sizes.last().copied().unwrap_or(0);
+        if let Ok(codewords) = code.generate(bits) {
+            static REPORT: Once = Once::new();
+            REPORT.call_once(|| print!("{}", code_report(&codewords, bits)));
+            settings.network.output_embedding = codewords;
+        }
+    }
     let derived = DerivedValues::new(&settings.data, &settings.network);
     Configuration {
         data: settings.data,
         network: settings.network,
+        decoder: settings.decoder,
+        loss: settings.loss,
         derived,
     }
 }
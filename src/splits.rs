@@ -0,0 +1,289 @@
+use crate::settings::*;
+use rand::prelude::*;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+// Turns a DataSplit into the list of file rows it covers. Row counts are known up front from
+// train_rows/test_rows in the settings, so DerivedValues can be computed without touching the files.
+
+impl DataSplit {
+    pub fn source(&self) -> DataSource {
+        match self {
+            DataSplit::Train(_) | DataSplit::Val(_) => DataSource::Train,
+            DataSplit::Test(_) => DataSource::Test,
+            DataSplit::Range { source, .. }
+            | DataSplit::Fraction { source, .. }
+            | DataSplit::Permuted { source, .. }
+            | DataSplit::KFold { source, .. } => *source,
+        }
+    }
+
+    pub fn is_stratified(&self) -> bool {
+        matches!(
+            self,
+            DataSplit::Permuted {
+                stratified: true,
+                ..
+            } | DataSplit::KFold {
+                stratified: true,
+                ..
+            }
+        )
+    }
+
+    pub fn num_rows(&self, data: &Data) -> usize {
+        let source_rows = source_rows(data, self.source());
+        match *self {
+            DataSplit::Train(r) | DataSplit::Val(r) | DataSplit::Test(r) => r as usize,
+            DataSplit::Range { start, end, .. } | DataSplit::Permuted { start, end, .. } => {
+                end.saturating_sub(start)
+            }
+            DataSplit::Fraction { start, end, .. } => fraction_to_row(end, source_rows)
+                .saturating_sub(fraction_to_row(start, source_rows)),
+            // No folds, nothing to hold out or train on (validate rejects it)
+            DataSplit::KFold { k: 0, .. } => 0,
+            DataSplit::KFold {
+                k, fold, holdout, ..
+            } => {
+                let holdout_rows = (source_rows + k - 1 - fold.min(k - 1)) / k;
+                if holdout {
+                    holdout_rows
+                } else {
+                    source_rows - holdout_rows
+                }
+            }
+        }
+    }
+
+    pub fn validate(&self, data: &Data) -> Result<(), String> {
+        let source_rows = source_rows(data, self.source());
+        let err = |msg: &str| Err(format!("Invalid split {:?}: {}", self, msg));
+        match *self {
+            // We are going to treat last 10k train rows as validation set
+            DataSplit::Train(r) if r == 0 || r > 50000 => {
+                err("Train rows must be between 1 and 50000")
+            }
+            DataSplit::Val(r) if r == 0 || r > 10000 => {
+                err("Validation rows must be between 1 and 10000")
+            }
+            DataSplit::Test(r) if r == 0 || r > 10000 => {
+                err("Test rows must be between 1 and 10000")
+            }
+            DataSplit::Range { start, end, .. } | DataSplit::Permuted { start, end, .. }
+                if start >= end || end > source_rows =>
+            {
+                err(&format!("need start < end <= {}", source_rows))
+            }
+            DataSplit::Fraction { start, end, .. }
+                if !(0.0 <= start && start < end && end <= 1.0) =>
+            {
+                err("need 0.0 <= start < end <= 1.0")
+            }
+            DataSplit::KFold { k, fold, .. } if k < 2 || fold >= k || k > source_rows => {
+                err("need 2 <= k <= rows and fold < k")
+            }
+            _ if self.num_rows(data) == 0 => err("split selects no rows"),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn rows(&self, data: &Data, source_labels: Option<&[usize]>) -> Vec<usize> {
+        // Row indices into the source file, in the order they should be loaded.
+        // Stratified splits need the labels of every row in the source file.
+        let source_rows = source_rows(data, self.source());
+        match *self {
+            DataSplit::Train(r) | DataSplit::Test(r) => (0..r as usize).collect(),
+            DataSplit::Val(r) => (50000..50000 + r as usize).collect(),
+            DataSplit::Range { start, end, .. } => (start..end).collect(),
+            DataSplit::Fraction { start, end, .. } => {
+                (fraction_to_row(start, source_rows)..fraction_to_row(end, source_rows)).collect()
+            }
+            DataSplit::Permuted {
+                seed,
+                start,
+                end,
+                stratified,
+                ..
+            } => {
+                let labels =
+                    stratified.then(|| source_labels.expect("Stratified splits need labels"));
+                permutation(source_rows, seed, labels)[start..end].to_vec()
+            }
+            DataSplit::KFold { k: 0, .. } => Vec::new(),
+            DataSplit::KFold {
+                k,
+                fold,
+                seed,
+                stratified,
+                holdout,
+                ..
+            } => {
+                let labels =
+                    stratified.then(|| source_labels.expect("Stratified splits need labels"));
+                // Folds are taken round robin over the permutation, so stratified folds stay balanced
+                permutation(source_rows, seed, labels)
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(position, _)| (position % k == fold) == holdout)
+                    .map(|(_, row)| row)
+                    .collect()
+            }
+        }
+    }
+}
+
+pub fn source_rows(data: &Data, source: DataSource) -> usize {
+    match source {
+        DataSource::Train => data.train_rows,
+        DataSource::Test => data.test_rows,
+    }
+}
+
+pub fn source_filepath(data: &Data, source: DataSource) -> &str {
+    match source {
+        DataSource::Train => &data.train_filepath,
+        DataSource::Test => &data.test_filepath,
+    }
+}
+
+fn fraction_to_row(fraction: f64, rows: usize) -> usize {
+    (fraction * rows as f64).round() as usize
+}
+
+pub fn permutation(n: usize, seed: u64, labels: Option<&[usize]>) -> Vec<usize> {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let mut order: Vec<usize> = (0..n).collect();
+    order.shuffle(&mut rng);
+    if let Some(labels) = labels {
+        // Spread each class evenly over the permutation: the r-th row (of n_c) of class c gets key (r + 0.5) / n_c.
+        // Any contiguous window of the result then keeps the class proportions of the whole file.
+        let num_classes = labels.iter().max().map_or(0, |&m| m + 1);
+        let mut class_counts = vec![0usize; num_classes];
+        for &row in &order {
+            class_counts[labels[row]] += 1;
+        }
+        let mut seen = vec![0usize; num_classes];
+        let mut keyed: Vec<(f64, usize)> = order
+            .iter()
+            .map(|&row| {
+                let class = labels[row];
+                seen[class] += 1;
+                ((seen[class] as f64 - 0.5) / class_counts[class] as f64, row)
+            })
+            .collect();
+        keyed.sort_by(|a, b| a.0.total_cmp(&b.0)); // stable, so ties keep the shuffled order
+        order = keyed.into_iter().map(|(_, row)| row).collect();
+    }
+    order
+}
+
+pub fn kfold_splits(
+    source: DataSource,
+    k: usize,
+    seed: u64,
+    stratified: bool,
+) -> Vec<(DataSplit, DataSplit)> {
+    // (train, holdout) pairs for each of the k folds
+    (0..k)
+        .map(|fold| {
+            let split = |holdout| DataSplit::KFold {
+                source,
+                k,
+                fold,
+                seed,
+                stratified,
+                holdout,
+            };
+            (split(false), split(true))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn test_data() -> Data {
+        let mut data = initialize_app_config_with_network(None).data;
+        data.train_rows = 100;
+        data.test_rows = 40;
+        data
+    }
+
+    #[test]
+    fn split_row_counts_match_resolved_rows() {
+        let data = test_data();
+        let labels: Vec<usize> = (0..100).map(|i| i % 3).collect();
+        let splits = [
+            DataSplit::Range {
+                source: DataSource::Train,
+                start: 10,
+                end: 30,
+            },
+            DataSplit::Fraction {
+                source: DataSource::Test,
+                start: 0.25,
+                end: 1.0,
+            },
+            DataSplit::Permuted {
+                source: DataSource::Train,
+                seed: 7,
+                start: 0,
+                end: 80,
+                stratified: true,
+            },
+            DataSplit::KFold {
+                source: DataSource::Train,
+                k: 3,
+                fold: 2,
+                seed: 7,
+                stratified: false,
+                holdout: true,
+            },
+        ];
+        for split in splits {
+            assert!(split.validate(&data).is_ok());
+            assert_eq!(
+                split.rows(&data, Some(&labels)).len(),
+                split.num_rows(&data)
+            );
+        }
+    }
+
+    #[test]
+    fn kfold_holdouts_partition_the_file() {
+        let data = test_data();
+        let labels: Vec<usize> = (0..100).map(|i| i % 4).collect();
+        let mut seen = HashSet::new();
+        for (train, holdout) in kfold_splits(DataSource::Train, 3, 11, true) {
+            let train_rows: HashSet<usize> = train.rows(&data, Some(&labels)).into_iter().collect();
+            let holdout_rows = holdout.rows(&data, Some(&labels));
+            assert_eq!(train_rows.len() + holdout_rows.len(), 100);
+            for row in holdout_rows {
+                assert!(!train_rows.contains(&row));
+                assert!(seen.insert(row));
+            }
+        }
+        assert_eq!(seen.len(), 100);
+
+        let no_folds = DataSplit::KFold {
+            source: DataSource::Train,
+            k: 0,
+            fold: 0,
+            seed: 11,
+            stratified: false,
+            holdout: true,
+        };
+        assert_eq!(no_folds.num_rows(&data), 0);
+        assert!(no_folds.rows(&data, None).is_empty());
+        assert!(no_folds.validate(&data).is_err());
+    }
+
+    #[test]
+    fn stratified_permutation_keeps_class_balance() {
+        let labels: Vec<usize> = (0..1000).map(|i| if i < 800 { 0 } else { 1 }).collect();
+        let order = permutation(1000, 3, Some(&labels));
+        let window_ones = order[..100].iter().filter(|&&row| labels[row] == 1).count();
+        assert!((19..=21).contains(&window_ones));
+    }
+}
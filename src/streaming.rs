@@ -0,0 +1,392 @@
+use crate::{
+    augmentation::Augmenter,
+    binarization::PixelEncoder,
+    dataloader::*,
+    sampler::{BatchSource, pad_remainder},
+    settings::*,
+    splits::*,
+};
+use bitvec::prelude::*;
+use rand::prelude::*;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::error::Error;
+use std::sync::{
+    Arc,
+    mpsc::{Receiver, SyncSender, sync_channel},
+};
+use std::thread;
+
+// Batches read straight from the CSV file every epoch, for splits whose expanded bits don't fit in memory.
+// A background thread parses, augments and encodes rows while the trainer works on the previous batch.
+// Memory is bounded by the shuffle window plus prefetch_batches encoded batches.
+//
+// The file can only be read front to back, so shuffling happens within windows of shuffle_window batches
+// rather than over the whole split, and stratified sampling is not available. Batches must be loaded in order.
+
+type Batch = Result<(BitVec<u8, Msb0>, Vec<usize>), String>;
+type Row = (usize, Vec<u8>, usize); // File row, pixels, label
+
+struct StreamPlan {
+    cfg: Configuration,
+    wanted: Vec<bool>, // Indexed by file row, true for the rows of the split
+    encoder: PixelEncoder,
+    augmenter: Option<Augmenter>,
+    num_batches: usize,
+    shuffle_window: usize,
+}
+
+pub struct StreamingBatches {
+    plan: Arc<StreamPlan>,
+    rows: usize,
+    prefetch_batches: usize,
+    receiver: Option<Receiver<Batch>>,
+    next_batch: usize,
+}
+
+impl StreamingBatches {
+    pub fn new(cfg: &Configuration, params: &Streaming) -> Result<Self, Box<dyn Error>> {
+        if cfg.data.synthetic.is_some() || cfg.data.tabular.is_some() {
+            return Err("Streaming only reads image CSV files".into());
+        }
+        if cfg.data.sampling.stratified {
+            return Err(
+                "Stratified sampling needs the whole split in memory, it can't be streamed".into(),
+            );
+        }
+        let split = &cfg.data.datasplit;
+        split.validate(&cfg.data)?;
+        let source_labels = if split.is_stratified() {
+            Some(read_csv_labels(source_filepath(&cfg.data, split.source()))?)
+        } else {
+            None
+        };
+        let rows = split.rows(&cfg.data, source_labels.as_deref());
+        let mut wanted = vec![false; rows.iter().max().map_or(0, |&m| m + 1)];
+        for &row in &rows {
+            wanted[row] = true;
+        }
+        let num_batches = match cfg.data.sampling.remainder {
+            Remainder::Drop => rows.len() / cfg.data.batch_size,
+            Remainder::Pad => rows.len().div_ceil(cfg.data.batch_size),
+        };
+        let plan = StreamPlan {
+            cfg: cfg.clone(),
+            wanted,
+            encoder: PixelEncoder::from_cfg(cfg)?,
+            augmenter: cfg
+                .data
+                .augmentation
+                .clone()
+                .map(|params| Augmenter::new(params, cfg.data.dim2, cfg.data.dim3)),
+            num_batches,
+            shuffle_window: params.shuffle_window.max(1),
+        };
+        Ok(StreamingBatches {
+            plan: Arc::new(plan),
+            rows: rows.len(),
+            prefetch_batches: params.prefetch_batches.max(1),
+            receiver: None,
+            next_batch: 0,
+        })
+    }
+}
+
+impl BatchSource for StreamingBatches {
+    fn num_rows(&self) -> usize {
+        self.rows
+    }
+
+    fn num_batches(&self) -> usize {
+        self.plan.num_batches
+    }
+
+    fn varies_per_epoch(&self) -> bool {
+        self.plan.cfg.data.sampling.shuffle || self.plan.augmenter.is_some()
+    }
+
+    fn start_epoch(&mut self, epoch: usize) {
+        // Dropping the old receiver makes the previous producer stop at its next send
+        let (sender, receiver) = sync_channel(self.prefetch_batches);
+        let plan = Arc::clone(&self.plan);
+        thread::spawn(move || {
+            if let Err(e) = produce_epoch(&plan, epoch, &sender) {
+                let _ = sender.send(Err(e.to_string()));
+            }
+        });
+        self.receiver = Some(receiver);
+        self.next_batch = 0;
+    }
+
+    fn load_batch(&mut self, batch_num: usize, dbv: &mut BitVec<u8, Msb0>) -> Vec<usize> {
+        assert_eq!(
+            batch_num, self.next_batch,
+            "Streamed batches must be loaded in order, once per epoch"
+        );
+        let receiver = self
+            .receiver
+            .as_ref()
+            .expect("start_epoch must be called before loading batches");
+        match receiver.recv() {
+            Ok(Ok((batch_bits, labels))) => {
+                dbv[..batch_bits.len()].copy_from_bitslice(&batch_bits);
+                self.next_batch += 1;
+                labels
+            }
+            Ok(Err(e)) => panic!("Streaming data loader failed: {}", e),
+            Err(_) => panic!("Streaming data loader stopped before batch {}", batch_num),
+        }
+    }
+}
+
+fn produce_epoch(
+    plan: &StreamPlan,
+    epoch: usize,
+    sender: &SyncSender<Batch>,
+) -> Result<(), Box<dyn Error>> {
+    let cfg = &plan.cfg;
+    let window_rows = cfg.data.batch_size * plan.shuffle_window;
+    let seed = (cfg.data.sampling.seed ^ (epoch as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15))
+        .wrapping_add(0xBF58_476D_1CE4_E5B9);
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let mut window: Vec<Row> = Vec::with_capacity(window_rows);
+    let mut epoch_start: Vec<Row> = Vec::with_capacity(cfg.data.batch_size); // Rows a padded batch is filled with
+    let mut sent = 0;
+
+    let mut rdr = open_csv(source_filepath(&cfg.data, cfg.data.datasplit.source()))?;
+    let mut rows_read = 0;
+    for (row, record) in rdr.records().enumerate().take(plan.wanted.len()) {
+        rows_read += 1;
+        let record = record?;
+        if !plan.wanted[row] {
+            continue;
+        }
+        let mut pixels = vec![0u8; cfg.derived.cols];
+        let label = parse_record(&record, row, &mut pixels)?;
+        window.push((row, pixels, label));
+        if window.len() == window_rows
+            && !send_window(
+                plan,
+                epoch,
+                &mut window,
+                &mut epoch_start,
+                &mut rng,
+                &mut sent,
+                sender,
+            )
+        {
+            return Ok(());
+        }
+    }
+    if rows_read < plan.wanted.len() {
+        return Err(format!(
+            "Expected to read up to row {}, but the file ended after {} rows.",
+            plan.wanted.len(),
+            rows_read
+        )
+        .into());
+    }
+    send_window(
+        plan,
+        epoch,
+        &mut window,
+        &mut epoch_start,
+        &mut rng,
+        &mut sent,
+        sender,
+    );
+    Ok(())
+}
+
+fn send_window(
+    plan: &StreamPlan,
+    epoch: usize,
+    window: &mut Vec<Row>,
+    epoch_start: &mut Vec<Row>,
+    rng: &mut Xoshiro256PlusPlus,
+    sent: &mut usize,
+    sender: &SyncSender<Batch>,
+) -> bool {
+    // Encodes and sends the batches of a window, returns false once the epoch is complete or the trainer is gone
+    let cfg = &plan.cfg;
+    let batch_size = cfg.data.batch_size;
+    if cfg.data.sampling.shuffle {
+        window.shuffle(rng);
+    }
+    for chunk in window.chunks(batch_size) {
+        if *sent == plan.num_batches {
+            return false;
+        }
+        // A short chunk only happens at the end of the file. With Pad it is filled like BatchSampler fills it,
+        // with the first rows of the epoch.
+        if chunk.len() < batch_size && cfg.data.sampling.remainder == Remainder::Drop {
+            break;
+        }
+        let missing = batch_size - epoch_start.len();
+        epoch_start.extend(chunk.iter().take(missing).cloned());
+        let mut rows: Vec<&Row> = chunk.iter().collect();
+        if rows.len() < batch_size {
+            pad_remainder(
+                &mut rows,
+                &epoch_start.iter().collect::<Vec<_>>(),
+                batch_size,
+            );
+        }
+        let mut batch_bits = BitVec::with_capacity(cfg.derived.batch_bitcount);
+        let mut labels = Vec::with_capacity(batch_size);
+        for (row, pixels, label) in rows {
+            match &plan.augmenter {
+                Some(augmenter) => plan
+                    .encoder
+                    .encode_image(&augmenter.augment(pixels, epoch, *row), &mut batch_bits),
+                None => plan.encoder.encode_image(pixels, &mut batch_bits),
+            }
+            labels.push(*label);
+        }
+        if sender.send(Ok((batch_bits, labels))).is_err() {
+            return false;
+        }
+        *sent += 1;
+    }
+    window.clear();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::BatchSampler;
+    use std::io::Write;
+
+    fn stream_cfg(path: &str, sampling: Sampling) -> Configuration {
+        let mut cfg = initialize_app_config_with_network(None);
+        cfg.data.dim2 = 2;
+        cfg.data.dim3 = 1;
+        cfg.data.encoding = Encoding::Raw;
+        cfg.data.dim1 = 8;
+        cfg.data.augmentation = None;
+        cfg.data.train_filepath = path.to_string();
+        cfg.data.train_rows = 10;
+        cfg.data.datasplit = DataSplit::Range {
+            source: DataSource::Train,
+            start: 2,
+            end: 9,
+        };
+        cfg.data.batch_size = 3;
+        cfg.data.sampling = sampling;
+        cfg.derived = DerivedValues::new(&cfg.data, &cfg.network);
+        cfg
+    }
+
+    fn write_csv(name: &str) -> String {
+        // Row r has label r and pixels (r, 100 + r)
+        let path = std::env::temp_dir().join(format!("{}_{}.csv", name, std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "label,p0,p1").unwrap();
+        for r in 0..10 {
+            writeln!(file, "{},{},{}", r, r, 100 + r).unwrap();
+        }
+        path.to_string_lossy().to_string()
+    }
+
+    fn read_epoch(batches: &mut StreamingBatches, cfg: &Configuration, epoch: usize) -> Vec<usize> {
+        let mut dbv = bitvec![u8, Msb0; 0; cfg.derived.bitvec_size];
+        batches.start_epoch(epoch);
+        let mut all_labels = Vec::new();
+        for batch_num in 0..batches.num_batches() {
+            let labels = batches.load_batch(batch_num, &mut dbv);
+            // The first pixel of each image holds the row number, which is also its label
+            for (i, &label) in labels.iter().enumerate() {
+                assert_eq!(dbv[i * 16..i * 16 + 8].load_be::<usize>(), label);
+            }
+            all_labels.extend(labels);
+        }
+        all_labels
+    }
+
+    #[test]
+    fn streamed_batches_match_in_memory_order() {
+        let path = write_csv("lutnets_stream_order");
+        let cfg = stream_cfg(
+            &path,
+            Sampling {
+                remainder: Remainder::Pad,
+                ..Default::default()
+            },
+        );
+        let params = Streaming {
+            prefetch_batches: 1,
+            shuffle_window: 1,
+        };
+        let mut batches = StreamingBatches::new(&cfg, &params).unwrap();
+        assert_eq!(batches.num_batches(), 3);
+        // Rows 2..9, the padded last batch is filled with the first rows of the epoch
+        assert_eq!(
+            read_epoch(&mut batches, &cfg, 0),
+            vec![2, 3, 4, 5, 6, 7, 8, 2, 3]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn padded_streams_match_the_batch_sampler() {
+        let path = write_csv("lutnets_stream_pad");
+        let params = Streaming {
+            prefetch_batches: 1,
+            shuffle_window: 2,
+        };
+        // 7 rows: a short last batch, an exact fit, and a split smaller than one batch
+        for batch_size in [3, 4, 7, 10] {
+            let mut cfg = stream_cfg(
+                &path,
+                Sampling {
+                    remainder: Remainder::Pad,
+                    ..Default::default()
+                },
+            );
+            cfg.data.batch_size = batch_size;
+            cfg.derived = DerivedValues::new(&cfg.data, &cfg.network);
+            let mut batches = StreamingBatches::new(&cfg, &params).unwrap();
+            let labels: Vec<usize> = (2..9).collect();
+            let sampler = BatchSampler::new(&cfg, &labels);
+            assert_eq!(batches.num_batches(), sampler.num_batches());
+            let expected: Vec<usize> = sampler
+                .epoch_batches(0)
+                .concat()
+                .iter()
+                .map(|&i| labels[i])
+                .collect();
+            assert_eq!(
+                read_epoch(&mut batches, &cfg, 0),
+                expected,
+                "{}",
+                batch_size
+            );
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn shuffled_streams_cover_the_split_every_epoch() {
+        let path = write_csv("lutnets_stream_shuffle");
+        let cfg = stream_cfg(
+            &path,
+            Sampling {
+                shuffle: true,
+                seed: 4,
+                ..Default::default()
+            },
+        );
+        let params = Streaming {
+            prefetch_batches: 2,
+            shuffle_window: 4,
+        };
+        let mut batches = StreamingBatches::new(&cfg, &params).unwrap();
+        let epoch0 = read_epoch(&mut batches, &cfg, 0);
+        assert_eq!(epoch0.len(), 6);
+        assert!(epoch0.iter().all(|label| (2..9).contains(label)));
+        // Abandoning an epoch half way and restarting is fine
+        batches.start_epoch(1);
+        assert_eq!(read_epoch(&mut batches, &cfg, 0), epoch0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
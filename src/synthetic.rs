@@ -0,0 +1,419 @@
+use crate::{
+    dataloader::LoadedSplit, netcore::*, processing::get_outputs, sampler::load_batch, settings::*,
+    splits::source_rows,
+};
+use bitvec::prelude::*;
+use rand::{prelude::*, seq::index};
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::error::Error;
+
+// Boolean tasks with a known answer, generated in the same (BitVec, labels) layout as csv_to_bitvec.
+// If a trainer can't fit these with a network at least as large as the planted network, the trainer is at fault.
+
+impl SyntheticTask {
+    pub fn num_inputs(&self) -> usize {
+        match *self {
+            SyntheticTask::Parity { bits } | SyntheticTask::Majority { bits } => bits,
+            SyntheticTask::Multiplexer { select_bits } => select_bits + (1 << select_bits),
+            SyntheticTask::Comparator { bits } => 2 * bits,
+            SyntheticTask::SparseFormula { inputs, .. } | SyntheticTask::Teacher { inputs, .. } => {
+                inputs
+            }
+        }
+    }
+
+    pub fn num_classes(&self) -> usize {
+        match self {
+            SyntheticTask::Teacher { layer_sizes, .. } => 1 << layer_sizes.last().unwrap_or(&0),
+            _ => 2,
+        }
+    }
+
+    pub fn validate(&self, output_embedding: &[usize]) -> Result<(), String> {
+        let err = |msg: &str| Err(format!("Invalid synthetic task {:?}: {}", self, msg));
+        match self {
+            SyntheticTask::Parity { bits }
+            | SyntheticTask::Majority { bits }
+            | SyntheticTask::Comparator { bits }
+                if *bits == 0 =>
+            {
+                return err("bits must be non-zero");
+            }
+            SyntheticTask::Multiplexer { select_bits } if !(1..=5).contains(select_bits) => {
+                return err("select_bits must be in 1..=5");
+            }
+            SyntheticTask::SparseFormula {
+                inputs, relevant, ..
+            } if *relevant == 0 || relevant > inputs => {
+                return err("need 0 < relevant <= inputs");
+            }
+            SyntheticTask::Teacher {
+                inputs,
+                layer_sizes,
+                ..
+            } if *inputs == 0 || layer_sizes.is_empty() || layer_sizes.contains(&0) => {
+                return err("need inputs and every layer size to be non-zero");
+            }
+            SyntheticTask::Teacher { layer_sizes, .. } if *layer_sizes.last().unwrap() > 16 => {
+                return err("the last layer can have at most 16 bits");
+            }
+            _ => {}
+        }
+        if self.num_classes() > output_embedding.len() {
+            return err(&format!(
+                "{} classes, but the output embedding only has {} codewords",
+                self.num_classes(),
+                output_embedding.len()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn planted_net(&self) -> Option<LUTNet> {
+        // The network that defines the task, for the tasks that are defined by one
+        match self {
+            SyntheticTask::SparseFormula {
+                inputs,
+                relevant,
+                seed,
+            } => Some(random_tree(
+                *inputs,
+                *relevant,
+                &mut Xoshiro256PlusPlus::seed_from_u64(*seed),
+            )),
+            SyntheticTask::Teacher {
+                inputs,
+                layer_sizes,
+                seed,
+            } => Some(random_layered(
+                *inputs,
+                layer_sizes,
+                &mut Xoshiro256PlusPlus::seed_from_u64(*seed),
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn planted_luts(&self) -> Option<usize> {
+        // Size of a known 6-input LUT network computing the task, a target for how small a trained network can be
+        match *self {
+            SyntheticTask::Parity { bits } => Some(bits.saturating_sub(1).div_ceil(5).max(1)),
+            SyntheticTask::Multiplexer { select_bits } => Some(match select_bits {
+                1 | 2 => 1,
+                // A 4:1 mux per group of 4 data bits, then a mux tree over the group outputs
+                s => (1 << (s - 2)) + tree_luts(1 << (s - 2)),
+            }),
+            SyntheticTask::Majority { .. } | SyntheticTask::Comparator { .. } => None,
+            _ => self.planted_net().map(|net| net.nodes.len()),
+        }
+    }
+
+    fn label(&self, x: &BitSlice<u8, Msb0>) -> usize {
+        // Labels of the tasks without a planted network
+        match *self {
+            SyntheticTask::Parity { .. } => x.count_ones() % 2,
+            SyntheticTask::Majority { bits } => (x.count_ones() * 2 > bits) as usize,
+            SyntheticTask::Multiplexer { select_bits } => {
+                let address = x[..select_bits].load_be::<usize>();
+                x[select_bits + address] as usize
+            }
+            SyntheticTask::Comparator { bits } => {
+                // Compare as big-endian bit strings, which also works beyond 64 bits
+                (x[..bits].iter().by_vals().cmp(x[bits..].iter().by_vals())
+                    == std::cmp::Ordering::Greater) as usize
+            }
+            SyntheticTask::SparseFormula { .. } | SyntheticTask::Teacher { .. } => {
+                unreachable!("Labelled by their planted network")
+            }
+        }
+    }
+}
+
+fn planted_node(inputs: &[usize], rng: &mut Xoshiro256PlusPlus) -> Node {
+    // A random LUT over up to 6 distinct inputs, indices[0] as the least significant address bit like every node.
+    // Fewer inputs repeat the last one in the unused slots, which the LUT ignores. Constant tables are redrawn, a
+    // node that ignores all its inputs plants nothing.
+    let used = inputs.len();
+    let mask = u64::MAX >> (64 - (1 << used));
+    let table = loop {
+        let table = rng.random::<u64>() & mask;
+        if table != 0 && table != mask {
+            break table;
+        }
+    };
+    let indices = std::array::from_fn(|slot| inputs[slot.min(used - 1)]);
+    let lut = (0..64).fold(0, |lut, address| {
+        lut | ((table >> (address & ((1 << used) - 1))) & 1) << address
+    });
+    Node { lut, indices }
+}
+
+fn tree_luts(leaves: usize) -> usize {
+    let mut width = leaves;
+    let mut luts = 0;
+    loop {
+        width = width.div_ceil(6);
+        luts += width;
+        if width == 1 {
+            return luts;
+        }
+    }
+}
+
+fn planted_lutnet(inputs: usize, layers: Vec<Vec<Node>>) -> LUTNet {
+    // layers read the inputs and then the layer before them, indices counted within that layer
+    let mut layer_edges = vec![0];
+    let mut nodes = Vec::new();
+    for layer in layers {
+        let base = match layer_edges.len() {
+            1 => 0,
+            l => inputs + layer_edges[l - 2],
+        };
+        nodes.extend(layer.into_iter().map(|node| Node {
+            lut: node.lut,
+            indices: node.indices.map(|i| base + i),
+        }));
+        layer_edges.push(nodes.len());
+    }
+    LUTNet::new(nodes, inputs, layer_edges, None, Vec::new())
+}
+
+fn random_tree(inputs: usize, relevant: usize, rng: &mut Xoshiro256PlusPlus) -> LUTNet {
+    // Groups of up to 6 signals are merged by random LUTs until a single output is left
+    let mut signals = index::sample(rng, inputs, relevant).into_vec();
+    let mut layers = Vec::new();
+    loop {
+        let layer: Vec<Node> = signals
+            .chunks(6)
+            .map(|group| planted_node(group, rng))
+            .collect();
+        let width = layer.len();
+        layers.push(layer);
+        if width == 1 {
+            return planted_lutnet(inputs, layers);
+        }
+        signals = (0..width).collect();
+    }
+}
+
+fn random_layered(inputs: usize, layer_sizes: &[usize], rng: &mut Xoshiro256PlusPlus) -> LUTNet {
+    // Every node reads 6 random signals of the previous layer, like a randomly wired LUTNet
+    let mut previous = inputs;
+    let layers = layer_sizes
+        .iter()
+        .map(|&size| {
+            let layer = (0..size)
+                .map(|_| {
+                    let node_inputs: Vec<usize> = if previous >= 6 {
+                        index::sample(rng, previous, 6).into_vec()
+                    } else {
+                        (0..6).map(|_| rng.random_range(0..previous)).collect()
+                    };
+                    planted_node(&node_inputs, rng)
+                })
+                .collect();
+            previous = size;
+            layer
+        })
+        .collect();
+    planted_lutnet(inputs, layers)
+}
+
+fn planted_labels(cfg: &Configuration, net: &LUTNet, databits: &BitSlice<u8, Msb0>) -> Vec<usize> {
+    // Runs the rows through the planted network in batches, the label is its output bits read as a number with the
+    // first output bit most significant
+    let inputs = net.input_size_in_bits;
+    let rows = databits.len() / inputs;
+    let mut labels = Vec::with_capacity(rows);
+    let mut teacher_cfg = cfg.clone();
+    (
+        teacher_cfg.data.dim1,
+        teacher_cfg.data.dim2,
+        teacher_cfg.data.dim3,
+    ) = (1, inputs, 1);
+    teacher_cfg.network.layer_sizes = net.layer_edges.windows(2).map(|w| w[1] - w[0]).collect();
+    for first_row in (0..rows).step_by(1000) {
+        teacher_cfg.data.batch_size = (rows - first_row).min(1000);
+        teacher_cfg.derived = DerivedValues::new(&teacher_cfg.data, &teacher_cfg.network);
+        let mut dbv = bitvec![u8, Msb0; 0; teacher_cfg.derived.bitvec_size];
+        let batch_bits = teacher_cfg.derived.batch_bitcount;
+        dbv[..batch_bits].copy_from_bitslice(&databits[first_row * inputs..][..batch_bits]);
+        net.apply_gates(&teacher_cfg, &mut dbv);
+        labels.extend(
+            get_outputs(&teacher_cfg, &dbv)
+                .chunks_exact(teacher_cfg.derived.output_bitsize)
+                .map(|bits| {
+                    bits.iter()
+                        .by_vals()
+                        .fold(0, |acc, bit| (acc << 1) | bit as usize)
+                }),
+        );
+    }
+    labels
+}
+
+pub fn generate_source(
+    cfg: &Configuration,
+    synthetic: &Synthetic,
+    source: DataSource,
+    rows: usize,
+) -> LoadedSplit {
+    // Uniformly random input bits, labelled by the task. The whole source is generated so splits can pick rows from it.
+    let task = &synthetic.task;
+    let num_inputs = task.num_inputs();
+    let stream = match source {
+        DataSource::Train => 0x5452_4149_4e00_0000, // "TRAIN"
+        DataSource::Test => 0x5445_5354_0000_0000,  // "TEST"
+    };
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(synthetic.seed ^ stream);
+    let mut databits = BitVec::with_capacity(rows * num_inputs);
+    databits.extend((0..rows * num_inputs).map(|_| rng.random_bool(0.5)));
+    let labels = match task.planted_net() {
+        Some(net) => planted_labels(cfg, &net, &databits),
+        None => databits
+            .chunks_exact(num_inputs)
+            .map(|x| task.label(x))
+            .collect(),
+    };
+    (databits, labels)
+}
+
+pub fn load_synthetic_split(
+    cfg: &Configuration,
+    synthetic: &Synthetic,
+    split: &DataSplit,
+) -> Result<LoadedSplit, Box<dyn Error>> {
+    synthetic.task.validate(&cfg.network.output_embedding)?;
+    split.validate(&cfg.data)?;
+    let (source_bits, source_labels) = generate_source(
+        cfg,
+        synthetic,
+        split.source(),
+        source_rows(&cfg.data, split.source()),
+    );
+    let rows = split.rows(&cfg.data, Some(&source_labels));
+    let mut databits = bitvec![u8, Msb0; 0; rows.len() * cfg.derived.img_bitcount];
+    let labels = load_batch(cfg, &source_bits, &source_labels, &rows, &mut databits);
+    Ok((databits, labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label_of(task: SyntheticTask, bits: &[u8]) -> usize {
+        let x: BitVec<u8, Msb0> = bits.iter().map(|&b| b == 1).collect();
+        task.label(&x)
+    }
+
+    fn eval_node(node: &Node, signals: &BitSlice<u8, Msb0>) -> bool {
+        let address = (0..6).fold(0, |address, slot| {
+            address | (signals[node.indices[slot]] as usize) << slot
+        });
+        (node.lut >> address) & 1 == 1
+    }
+
+    #[test]
+    fn fixed_tasks_label_correctly() {
+        assert_eq!(
+            label_of(SyntheticTask::Parity { bits: 4 }, &[1, 0, 1, 1]),
+            1
+        );
+        assert_eq!(
+            label_of(SyntheticTask::Majority { bits: 5 }, &[1, 1, 0, 0, 1]),
+            1
+        );
+        assert_eq!(
+            label_of(SyntheticTask::Majority { bits: 4 }, &[1, 1, 0, 0]),
+            0
+        );
+        // Address 0b10 picks data bit 2
+        let mux = SyntheticTask::Multiplexer { select_bits: 2 };
+        assert_eq!(label_of(mux.clone(), &[1, 0, 0, 0, 1, 0]), 1);
+        assert_eq!(label_of(mux, &[1, 0, 1, 1, 0, 1]), 0);
+        let cmp = SyntheticTask::Comparator { bits: 3 };
+        assert_eq!(label_of(cmp.clone(), &[1, 0, 0, 0, 1, 1]), 1);
+        assert_eq!(label_of(cmp, &[0, 1, 1, 0, 1, 1]), 0);
+        assert_eq!(SyntheticTask::Parity { bits: 11 }.planted_luts(), Some(2));
+        assert_eq!(
+            SyntheticTask::Multiplexer { select_bits: 3 }.planted_luts(),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn planted_nets_match_their_shape() {
+        let formula = SyntheticTask::SparseFormula {
+            inputs: 64,
+            relevant: 20,
+            seed: 3,
+        };
+        let net = formula.planted_net().unwrap();
+        assert_eq!(formula.planted_luts(), Some(5)); // 4 LUTs over the 20 inputs, then one merging them
+        assert_eq!(net.layer_edges, vec![0, 4, 5]);
+        assert!(
+            net.nodes[..4]
+                .iter()
+                .all(|node| node.indices.iter().all(|&i| i < 64))
+        );
+        assert!(net.nodes[4].indices.iter().all(|&i| (64..68).contains(&i)));
+
+        let teacher = SyntheticTask::Teacher {
+            inputs: 32,
+            layer_sizes: vec![16, 8, 2],
+            seed: 3,
+        };
+        let net = teacher.planted_net().unwrap();
+        assert_eq!(net.layer_edges, vec![0, 16, 24, 26]);
+        for layer in 1..4 {
+            let reads = match layer {
+                1 => 0..32,
+                l => 32 + net.layer_edges[l - 2]..32 + net.layer_edges[l - 1],
+            };
+            let nodes = &net.nodes[net.layer_edges[layer - 1]..net.layer_edges[layer]];
+            assert!(
+                nodes
+                    .iter()
+                    .all(|node| node.indices.iter().all(|i| reads.contains(i)))
+            );
+        }
+        assert_eq!(teacher.num_classes(), 4);
+        assert!(teacher.validate(&[0; 10]).is_ok());
+        assert!(teacher.validate(&[0; 3]).is_err());
+    }
+
+    #[test]
+    fn generated_sources_are_reproducible_and_split_by_source() {
+        let synthetic = Synthetic {
+            task: SyntheticTask::SparseFormula {
+                inputs: 16,
+                relevant: 8,
+                seed: 1,
+            },
+            seed: 9,
+        };
+        let cfg = initialize_app_config_with_network(None);
+        let (bits, labels) = generate_source(&cfg, &synthetic, DataSource::Train, 200);
+        assert_eq!(bits.len(), 200 * 16);
+        assert_eq!(
+            (bits.clone(), labels.clone()),
+            generate_source(&cfg, &synthetic, DataSource::Train, 200)
+        );
+        assert_ne!(
+            bits,
+            generate_source(&cfg, &synthetic, DataSource::Test, 200).0
+        );
+        // Random non-constant trees label some rows each way
+        assert!(labels.contains(&0) && labels.contains(&1));
+        // The labels are the planted network's output, evaluated node by node with indices[0] as address bit 0
+        let net = synthetic.task.planted_net().unwrap();
+        for row in 0..200 {
+            let mut signals: BitVec<u8, Msb0> = bits[row * 16..(row + 1) * 16].to_bitvec();
+            for node in &net.nodes {
+                let out = eval_node(node, &signals);
+                signals.push(out);
+            }
+            assert_eq!(*signals.last().unwrap() as usize, labels[row]);
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use crate::{dataloader::LoadedSplit, settings::*, splits::source_filepath};
+use bitvec::prelude::*;
+use csv::{ReaderBuilder, StringRecord};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::OnceLock;
+use std::{fs::File, io::Read, io::Write, path::PathBuf};
+
+// Tabular classification data: a CSV with a header row, mixed numeric and categorical columns and a label column.
+// Numeric columns get per-column quantile thresholds written in unary, categorical columns get one-hot codes.
+// Both are fitted on the training split. The fitted encoder decides the input size of the network, so it is
+// written next to the model file and reused when the model is loaded.
+
+// Set by the model loader before the configuration is built, so a loaded model keeps the encoding it was trained with
+pub static LOADED_ENCODER: OnceLock<TabularEncoder> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ColumnEncoder {
+    Numeric {
+        name: String,
+        thresholds: Vec<f64>,
+    }, // One bit per threshold, set when the value is above it
+    Categorical {
+        name: String,
+        categories: Vec<String>,
+    }, // One bit per category, unseen values set none
+}
+
+impl ColumnEncoder {
+    pub fn name(&self) -> &str {
+        match self {
+            ColumnEncoder::Numeric { name, .. } | ColumnEncoder::Categorical { name, .. } => name,
+        }
+    }
+
+    pub fn bit_width(&self) -> usize {
+        match self {
+            ColumnEncoder::Numeric { thresholds, .. } => thresholds.len(),
+            ColumnEncoder::Categorical { categories, .. } => categories.len(),
+        }
+    }
+
+    fn encode(&self, field: &str, bv: &mut BitVec<u8, Msb0>) {
+        let field = field.trim();
+        match self {
+            ColumnEncoder::Numeric { thresholds, .. } => {
+                // Missing or unparseable values are below every threshold
+                let value = field.parse::<f64>().unwrap_or(f64::NEG_INFINITY);
+                bv.extend(thresholds.iter().map(|&t| value > t));
+            }
+            ColumnEncoder::Categorical { categories, .. } => {
+                bv.extend(categories.iter().map(|c| c == field));
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TabularEncoder {
+    pub header: Vec<String>,
+    pub columns: Vec<Option<ColumnEncoder>>, // Aligned with header, None for the label and ignored columns
+    pub label_index: usize,
+    pub classes: Vec<String>, // Label values, the class index is the position in this list
+}
+
+impl TabularEncoder {
+    pub fn fit(
+        params: &Tabular,
+        header: &StringRecord,
+        records: &[StringRecord],
+        fit_rows: &[usize],
+    ) -> Result<Self, Box<dyn Error>> {
+        // Classes come from every row so that splits never see an unknown label, columns only from fit_rows
+        let header: Vec<String> = header.iter().map(|h| h.trim().to_string()).collect();
+        let label_index = header
+            .iter()
+            .position(|h| *h == params.label_column)
+            .ok_or(format!(
+                "Label column '{}' not found in header",
+                params.label_column
+            ))?;
+        let classes = sorted_values(
+            records
+                .iter()
+                .map(|r| r.get(label_index).unwrap_or("").trim()),
+        );
+        if fit_rows.is_empty() {
+            return Err("Need at least one row to fit the tabular encoder".into());
+        }
+
+        let columns = header
+            .iter()
+            .enumerate()
+            .map(|(col, name)| {
+                if col == label_index || params.ignore.contains(name) {
+                    return None;
+                }
+                let values: Vec<&str> = fit_rows
+                    .iter()
+                    .map(|&row| records[row].get(col).unwrap_or("").trim())
+                    .filter(|v| !v.is_empty())
+                    .collect();
+                let numeric: Option<Vec<f64>> = values.iter().map(|v| v.parse().ok()).collect();
+                Some(match numeric {
+                    Some(numbers) if !params.categorical.contains(name) => ColumnEncoder::Numeric {
+                        name: name.clone(),
+                        thresholds: quantile_thresholds(numbers, params.levels),
+                    },
+                    _ => ColumnEncoder::Categorical {
+                        name: name.clone(),
+                        categories: top_categories(&values, params.max_categories),
+                    },
+                })
+            })
+            .collect();
+
+        Ok(TabularEncoder {
+            header,
+            columns,
+            label_index,
+            classes,
+        })
+    }
+
+    pub fn bit_width(&self) -> usize {
+        self.columns.iter().flatten().map(|c| c.bit_width()).sum()
+    }
+
+    pub fn label(&self, record: &StringRecord) -> Result<usize, Box<dyn Error>> {
+        let value = record.get(self.label_index).unwrap_or("").trim();
+        self.classes.iter().position(|c| c == value).ok_or_else(|| {
+            format!("Label '{}' was not seen when the encoder was fitted", value).into()
+        })
+    }
+
+    pub fn encode_row(&self, record: &StringRecord, bv: &mut BitVec<u8, Msb0>) {
+        for (col, encoder) in self.columns.iter().enumerate() {
+            if let Some(encoder) = encoder {
+                encoder.encode(record.get(col).unwrap_or(""), bv);
+            }
+        }
+    }
+
+    pub fn from_cfg(data: &Data, params: &Tabular) -> Result<Self, Box<dyn Error>> {
+        // Fits on the rows of the configured datasplit, which has to come from the train file
+        let split = &data.datasplit;
+        if split.source() != DataSource::Train {
+            return Err("The tabular encoder must be fitted on a split of the train file".into());
+        }
+        split.validate(data)?;
+        let (header, records) = read_records(&data.train_filepath)?;
+        let labels = sorted_labels(&header, &records, params)?;
+        let fit_rows = split.rows(data, Some(&labels));
+        if fit_rows.last().is_some_and(|&row| row >= records.len()) {
+            return Err(format!(
+                "Split {:?} needs more rows than the {} in {}",
+                split,
+                records.len(),
+                data.train_filepath
+            )
+            .into());
+        }
+        TabularEncoder::fit(params, &header, &records, &fit_rows)
+    }
+
+    pub fn sidecar_path(model_filename: &str) -> PathBuf {
+        PathBuf::from(format!("{}.encoder", model_filename))
+    }
+
+    pub fn write_sidecar(&self, model_filename: &str) -> Result<(), Box<dyn Error>> {
+        let encoded_bytes = bincode::serde::encode_to_vec(self, bincode::config::standard())?;
+        let mut file = File::create(Self::sidecar_path(model_filename))?;
+        file.write_all(&encoded_bytes)?;
+        Ok(())
+    }
+
+    pub fn read_sidecar(model_filename: &str) -> Result<Option<Self>, Box<dyn Error>> {
+        let path = Self::sidecar_path(model_filename);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut buffer = Vec::new();
+        File::open(path)?.read_to_end(&mut buffer)?;
+        let (encoder, _) = bincode::serde::decode_from_slice(&buffer, bincode::config::standard())?;
+        Ok(Some(encoder))
+    }
+}
+
+pub fn write_encoder_sidecar(
+    cfg: &Configuration,
+    model_filename: &str,
+) -> Result<(), Box<dyn Error>> {
+    // Nothing to write for image data, the pixel encodings are fully described by the settings
+    if let Some(encoder) = cfg.data.tabular.as_ref().and_then(|t| t.encoder.as_ref()) {
+        encoder.write_sidecar(model_filename)?;
+        println!(
+            "Tabular encoder written to {}",
+            TabularEncoder::sidecar_path(model_filename).display()
+        );
+    }
+    Ok(())
+}
+
+fn sorted_values<'a>(values: impl Iterator<Item = &'a str>) -> Vec<String> {
+    // Distinct values, numerically sorted when they are all numbers so "2" < "10"
+    let mut distinct: Vec<String> = values.map(str::to_string).collect();
+    distinct.sort();
+    distinct.dedup();
+    if distinct.iter().all(|v| v.parse::<f64>().is_ok()) {
+        distinct.sort_by(|a, b| {
+            a.parse::<f64>()
+                .unwrap()
+                .total_cmp(&b.parse::<f64>().unwrap())
+        });
+    }
+    distinct
+}
+
+fn sorted_labels(
+    header: &StringRecord,
+    records: &[StringRecord],
+    params: &Tabular,
+) -> Result<Vec<usize>, Box<dyn Error>> {
+    // Class indices of every row, only needed before an encoder exists (to stratify the fitting split)
+    let label_index = header
+        .iter()
+        .position(|h| h.trim() == params.label_column)
+        .ok_or(format!(
+            "Label column '{}' not found in header",
+            params.label_column
+        ))?;
+    let value = |r: &StringRecord| r.get(label_index).unwrap_or("").trim().to_string();
+    let classes = sorted_values(
+        records
+            .iter()
+            .map(|r| r.get(label_index).unwrap_or("").trim()),
+    );
+    Ok(records
+        .iter()
+        .map(|r| classes.iter().position(|c| *c == value(r)).unwrap())
+        .collect())
+}
+
+fn quantile_thresholds(mut numbers: Vec<f64>, levels: usize) -> Vec<f64> {
+    // levels - 1 thresholds, fewer if the column has ties across quantiles (a constant column gets none)
+    numbers.sort_by(f64::total_cmp);
+    let n = numbers.len();
+    let mut thresholds: Vec<f64> = (1..levels)
+        .filter_map(|level| {
+            let idx = level * n / levels;
+            (idx > 0 && idx < n).then(|| numbers[idx - 1])
+        })
+        .collect();
+    thresholds.dedup();
+    // A threshold at the column maximum could never be exceeded
+    thresholds.retain(|&t| Some(&t) != numbers.last());
+    thresholds
+}
+
+fn top_categories(values: &[&str], max_categories: usize) -> Vec<String> {
+    // The most frequent categories, ties broken by name so fitting is deterministic
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for &value in values {
+        *counts.entry(value).or_default() += 1;
+    }
+    let mut by_count: Vec<(&str, usize)> = counts.into_iter().collect();
+    by_count.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    by_count
+        .into_iter()
+        .take(max_categories)
+        .map(|(value, _)| value.to_string())
+        .collect()
+}
+
+pub fn read_records(filepath: &str) -> Result<(StringRecord, Vec<StringRecord>), Box<dyn Error>> {
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(File::open(filepath)?);
+    let header = rdr.headers()?.clone();
+    let records = rdr.records().collect::<Result<Vec<_>, _>>()?;
+    Ok((header, records))
+}
+
+pub fn load_tabular_split(
+    cfg: &Configuration,
+    split: &DataSplit,
+) -> Result<LoadedSplit, Box<dyn Error>> {
+    let encoder = cfg
+        .data
+        .tabular
+        .as_ref()
+        .and_then(|t| t.encoder.as_ref())
+        .ok_or("Tabular data needs a fitted encoder in the configuration")?;
+    split.validate(&cfg.data)?;
+    let (header, records) = read_records(source_filepath(&cfg.data, split.source()))?;
+    if header
+        .iter()
+        .map(str::trim)
+        .ne(encoder.header.iter().map(String::as_str))
+    {
+        return Err("The CSV header does not match the one the encoder was fitted on".into());
+    }
+    let source_labels = records
+        .iter()
+        .map(|r| encoder.label(r))
+        .collect::<Result<Vec<_>, _>>()?;
+    let rows = split.rows(&cfg.data, Some(&source_labels));
+    let mut databits = BitVec::with_capacity(rows.len() * cfg.derived.img_bitcount);
+    for &row in &rows {
+        let record = records
+            .get(row)
+            .ok_or(format!("Row {} is past the end of the file", row))?;
+        encoder.encode_row(record, &mut databits);
+    }
+    let labels = rows.iter().map(|&row| source_labels[row]).collect();
+    Ok((databits, labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Tabular {
+        Tabular {
+            label_column: "class".to_string(),
+            categorical: vec!["zip".to_string()],
+            ignore: vec!["id".to_string()],
+            levels: 4,
+            max_categories: 2,
+            encoder: None,
+        }
+    }
+
+    fn table() -> (StringRecord, Vec<StringRecord>) {
+        let header = StringRecord::from(vec!["id", "age", "colour", "zip", "class"]);
+        let rows = [
+            ["1", "20", "red", "100", "yes"],
+            ["2", "30", "blue", "200", "no"],
+            ["3", "40", "red", "100", "yes"],
+            ["4", "50", "green", "300", "no"],
+            ["5", "", "red", "100", "maybe"],
+        ];
+        (
+            header,
+            rows.iter()
+                .map(|r| StringRecord::from(r.to_vec()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn encoder_fits_numeric_and_categorical_columns() {
+        let (header, records) = table();
+        let encoder = TabularEncoder::fit(&params(), &header, &records, &[0, 1, 2, 3]).unwrap();
+        assert_eq!(encoder.classes, vec!["maybe", "no", "yes"]);
+        assert_eq!(
+            encoder.columns[1],
+            Some(ColumnEncoder::Numeric {
+                name: "age".to_string(),
+                thresholds: vec![20.0, 30.0, 40.0]
+            })
+        );
+        // colour is detected as categorical, zip is forced to be, both keep the 2 most frequent values
+        assert_eq!(
+            encoder.columns[2],
+            Some(ColumnEncoder::Categorical {
+                name: "colour".to_string(),
+                categories: vec!["red".to_string(), "blue".to_string()]
+            })
+        );
+        assert!(matches!(
+            encoder.columns[3],
+            Some(ColumnEncoder::Categorical { .. })
+        ));
+        assert_eq!(encoder.columns[0], None);
+        assert_eq!(encoder.bit_width(), 3 + 2 + 2);
+
+        let mut bv = BitVec::<u8, Msb0>::new();
+        encoder.encode_row(&records[2], &mut bv);
+        assert_eq!(bv, bitvec![u8, Msb0; 1,1,0, 1,0, 1,0]);
+        assert_eq!(encoder.label(&records[2]).unwrap(), 2);
+        // Missing numbers set no bits
+        bv.clear();
+        encoder.encode_row(&records[4], &mut bv);
+        assert_eq!(bv, bitvec![u8, Msb0; 0,0,0, 1,0, 1,0]);
+    }
+
+    #[test]
+    fn encoder_round_trips_through_sidecar() {
+        let (header, records) = table();
+        let encoder = TabularEncoder::fit(&params(), &header, &records, &[0, 1, 2, 3, 4]).unwrap();
+        let model_filename = std::env::temp_dir()
+            .join(format!("lutnets_tabular_test_{}.ltnet", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        encoder.write_sidecar(&model_filename).unwrap();
+        let loaded = TabularEncoder::read_sidecar(&model_filename)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded, encoder);
+        std::fs::remove_file(TabularEncoder::sidecar_path(&model_filename)).unwrap();
+    }
+}
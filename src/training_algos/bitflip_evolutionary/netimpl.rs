@@ -1,4 +1,4 @@
-use crate::{iterators::*, netcore::*, settings::*};
+use crate::{iterators::*, netcore::*, settings::*, training_algos::rewiring::Rewire};
 use bitvec::prelude::*;
 
 impl LUTNet {
@@ -7,18 +7,22 @@ impl LUTNet {
         cfg: &Configuration,
         bv: &mut BitVec<T, O>,
         node_idxs_to_corrupt: &Vec<usize>,
+        rewires: &[Rewire],
         mut pseudo_6bit_generator: impl Iterator<Item = u8>,
     ) -> Vec<Node>
     where
         T: BitStore,
         O: BitOrder,
     {
-        // fewer checks, a little more rodeo version of apply_gates. Adds corruption and returns the mutated nodes.
+        // fewer checks, a little more rodeo version of apply_gates. Adds corruption and rewires, and returns the mutated nodes.
         // let cfg = initialize_app_config();
         let mut nodes_to_iterate = self.nodes.clone();
         for node_idx in node_idxs_to_corrupt {
             nodes_to_iterate[*node_idx].lut ^= 1 << pseudo_6bit_generator.next().unwrap();
         }
+        for rewire in rewires {
+            rewire.apply(&mut nodes_to_iterate[rewire.node], self.lut_bank.is_none());
+        }
         for layer in 0..cfg.derived.num_layers {
             let gate_iterator = LayerGateIterator::new(cfg, layer);
             let results: Vec<(usize, bool)> = gate_iterator
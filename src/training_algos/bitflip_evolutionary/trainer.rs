@@ -1,68 +1,91 @@
-use crate::{netcore::*, processing::*, settings::*, utils::*};
+use crate::{
+    netcore::*, processing::*, sampler::*, settings::*, training_algos::TrainParams, utils::*,
+};
 use bitvec::prelude::*;
 use rand::{prelude::*, seq::index};
-use rand_xoshiro::Xoshiro256PlusPlus;
 use rayon::prelude::*;
 use std::{fs::File, io::Write, time::Instant};
 
 pub fn train(
     ltnet: &mut LUTNet,
     cfg: &Configuration,
-    databits: &BitVec<u8, Msb0>,
-    labels: &[usize],
-    corruption_ratio: f32,
-    lut_sampling_depth: usize,
-    epochs: usize,
+    data: &mut dyn BatchSource,
+    params: TrainParams,
     model_filename: &str,
 ) {
+    ltnet.decoder = Some(cfg.decoder.clone()); // Saved with the model, which then decodes with this head
     let start_time = Instant::now();
+    let TrainParams {
+        corruption_ratio,
+        heads,
+        epochs,
+        ..
+    } = params;
+    // With heads, every batch mutates a single head (or the shared trunk), taking turns
+    let mut targets = ltnet
+        .training_targets(heads)
+        .unwrap_or_else(|e| panic!("Can't train by heads: {}", e));
 
-    let mut loss_per_batch: Vec<usize> = Vec::with_capacity(cfg.derived.num_batches);
+    let mut loss_per_batch: Vec<usize> = Vec::with_capacity(data.num_batches());
     let mut dbv = bitvec![u8, Msb0; 0; cfg.derived.bitvec_size];
     let mut steps = 0;
-    for batch_num in 0..cfg.derived.num_batches {
-        let y = &labels[batch_num * cfg.data.batch_size..(batch_num + 1) * cfg.data.batch_size];
-        dbv[..cfg.derived.batch_bitcount].copy_from_bitslice(
-            &databits[batch_num * cfg.derived.batch_bitcount
-                ..(batch_num + 1) * cfg.derived.batch_bitcount],
-        );
+    data.start_epoch(0);
+    for batch_num in 0..data.num_batches() {
+        let y = data.load_batch(batch_num, &mut dbv);
         ltnet.apply_gates(cfg, &mut dbv);
-        loss_per_batch.push(get_loss(cfg, &dbv, y));
+        loss_per_batch.push(get_loss(cfg, &dbv, &y));
+    }
+    let corrupted: Vec<usize> = targets
+        .iter()
+        .map(|nodes| (nodes.len() as f32 * corruption_ratio).round() as usize)
+        .collect();
+    if heads == 0 {
+        print!(
+            "Initial losses per batch: {:?}\nCorrupting {} nodes at a time.\n",
+            loss_per_batch, corrupted[0]
+        );
+    } else {
+        print!(
+            "Initial losses per batch: {:?}\nCorrupting {:?} nodes at a time, one head after the other.\n",
+            loss_per_batch, corrupted
+        );
     }
-    print!(
-        "Initial losses per batch: {:?}\nCorrupting {} nodes at a time.\n",
-        loss_per_batch,
-        (cfg.derived.network_size as f32 * corruption_ratio).round() as usize
-    );
     for epoch in 0..epochs {
-        for batch_num in 0..cfg.derived.num_batches {
-            let y = &labels[batch_num * cfg.data.batch_size..(batch_num + 1) * cfg.data.batch_size];
-            dbv[..cfg.derived.batch_bitcount].copy_from_bitslice(
-                &databits[batch_num * cfg.derived.batch_bitcount
-                    ..(batch_num + 1) * cfg.derived.batch_bitcount],
-            );
-            // loss = loss_per_batch[batch_num];
+        data.start_epoch(epoch);
+        for (batch_num, batch_loss) in loss_per_batch.iter_mut().enumerate() {
+            let y = &data.load_batch(batch_num, &mut dbv);
+            if data.varies_per_epoch() {
+                // The batch has new rows or new augmentations this epoch, so the stored loss is stale
+                ltnet.apply_gates(cfg, &mut dbv);
+                *batch_loss = get_loss(cfg, &dbv, y);
+            }
             let (c_loss, mutated_nodes) = iterate_corruptions(
                 ltnet,
                 cfg,
                 &mut dbv,
                 y,
-                corruption_ratio,
-                lut_sampling_depth,
+                &targets[steps % targets.len()],
+                &params,
             )
             .unwrap();
             // println!("Min loss from corruptions: {}", c_loss);
-            if c_loss < loss_per_batch[batch_num] {
+            if c_loss < *batch_loss {
                 ltnet.nodes = mutated_nodes;
+                if heads > 0 && params.rewire_probability > 0.0 {
+                    // A rewired node of one head may now read a node of another, which then reaches both heads
+                    targets = ltnet
+                        .training_targets(heads)
+                        .unwrap_or_else(|e| panic!("Can't train by heads: {}", e));
+                }
                 println!(
                     "Epoch {}, Batch {}, Previous loss: {}, Improved Loss: {}, Time: {:?}",
                     epoch,
                     batch_num,
-                    loss_per_batch[batch_num],
+                    *batch_loss,
                     c_loss,
                     start_time.elapsed()
                 );
-                loss_per_batch[batch_num] = c_loss;
+                *batch_loss = c_loss;
                 // ltnet.apply_gates(&mut dbv);
                 // let predicted_labels = get_labels(&dbv);
                 // let accuracy = utils::calculate_accuracy(y, &predicted_labels);
@@ -79,7 +102,7 @@ pub fn train(
                     "Epoch {}, Batch {}, Previous loss: {}, Current Loss: {}, Time: {:?}",
                     epoch,
                     batch_num,
-                    loss_per_batch[batch_num],
+                    *batch_loss,
                     c_loss,
                     start_time.elapsed()
                 );
@@ -93,34 +116,41 @@ pub fn iterate_corruptions(
     cfg: &Configuration,
     dbv: &mut BitVec<u8, Msb0>,
     y: &[usize],
-    corruption_ratio: f32,
-    iterations: usize,
+    nodes: &[usize], // Nodes to pick from, frozen ones left out
+    params: &TrainParams,
 ) -> Option<(usize, Vec<Node>)> {
     assert!(
         cfg.network.lut_bank_size == 0,
         "Cannot run this algorithm on network with lut bank."
     );
-    let mut rng = Xoshiro256PlusPlus::from_rng(&mut rand::rng());
-    let num_to_corrupt = (cfg.derived.network_size as f32 * corruption_ratio).round() as usize;
+    let mut rng = new_rng();
+    let num_to_corrupt = (nodes.len() as f32 * params.corruption_ratio).round() as usize;
     // println!("Num of nodes to corrupt: {}", num_to_corrupt);
     // let node_idxs_to_corrupt: Vec<usize> = std::iter::repeat_with(|| (rng.next_u64() as usize) % cfg.derived.network_size)
     //     .take(num_to_corrupt)
     //     .collect();
-    let node_idxs_to_corrupt: Vec<usize> =
-        index::sample(&mut rng, cfg.derived.network_size, num_to_corrupt).into_vec();
+    let node_idxs_to_corrupt: Vec<usize> = index::sample(&mut rng, nodes.len(), num_to_corrupt)
+        .into_iter()
+        .map(|i| nodes[i])
+        .collect();
     // let mut oloss   = initial_loss;
-    (0..iterations)
+    let base_seed = rng.next_u64();
+    (0..params.lut_sampling_depth)
         .into_par_iter()
         .map_init(
-            || {
-                let pseudo_6bit_generator = pseudo_6bit_generator();
-                (pseudo_6bit_generator, dbv.clone())
-            },
-            |(pseudo_6bit_generator, local_dbv), _| {
+            || dbv.clone(),
+            |local_dbv, iteration| {
+                // Each iteration draws its own stream, so the best corruption doesn't depend on thread scheduling
+                let mut rng = stream_rng(base_seed, iteration as u64);
+                // Some of the picked nodes get a new input instead of a LUT mutation
+                let (lut_nodes, rewires) =
+                    ltnet.draw_rewires(&node_idxs_to_corrupt, params.rewire_probability, &mut rng);
+                let pseudo_6bit_generator = pseudo_6bit_generator(rng);
                 let mutated_nodes = ltnet.apply_gates_with_bitflips(
                     cfg,
                     local_dbv,
-                    &node_idxs_to_corrupt,
+                    &lut_nodes,
+                    &rewires,
                     pseudo_6bit_generator,
                 );
                 let loss = get_loss(cfg, local_dbv, y);
@@ -0,0 +1,176 @@
+use crate::{netcore::*, settings::Configuration};
+
+// Training one output head at a time. The output layer is split into equal contiguous blocks of bits, the heads
+// (see HeadsSpec in architectures/builder.rs for networks built that way). A node belongs to a head when every
+// path from it to the output ends in that head's bits, so mutating it can't change any other head's outputs.
+// Nodes reaching several heads form the shared trunk, and nodes reaching no output bit are dead.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reach {
+    Dead,
+    Head(usize),
+    Shared,
+}
+
+impl Reach {
+    fn join(self, other: Reach) -> Reach {
+        match (self, other) {
+            (Reach::Dead, r) | (r, Reach::Dead) => r,
+            (Reach::Head(a), Reach::Head(b)) if a == b => Reach::Head(a),
+            _ => Reach::Shared,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadNodes {
+    pub heads: Vec<Vec<usize>>, // Trainable nodes of every head, ascending
+    pub shared: Vec<usize>,     // Trainable nodes reaching more than one head
+}
+
+pub fn check_heads(cfg: &Configuration, heads: usize) -> Result<(), String> {
+    // Heads have to split the output bits evenly, 0 trains the whole network at once
+    match cfg.network.layer_sizes.last() {
+        _ if heads == 0 => Ok(()),
+        Some(bits) if bits.is_multiple_of(heads) => Ok(()),
+        bits => Err(format!(
+            "{} output bits can't be split into {} equal heads",
+            bits.unwrap_or(&0),
+            heads
+        )),
+    }
+}
+
+impl LUTNet {
+    pub fn head_nodes(&self, heads: usize) -> Result<HeadNodes, String> {
+        let output_start = self.layer_edges[self.layer_edges.len() - 2];
+        let output_bits = self.nodes.len() - output_start;
+        if heads == 0 || !output_bits.is_multiple_of(heads) {
+            return Err(format!(
+                "{} output bits can't be split into {} equal heads",
+                output_bits, heads
+            ));
+        }
+        let mut reach = vec![Reach::Dead; self.nodes.len()];
+        for (i, r) in reach[output_start..].iter_mut().enumerate() {
+            *r = Reach::Head(i / (output_bits / heads));
+        }
+        // Consumers come after their sources, so walking backwards settles every node before its sources
+        for node_idx in (0..self.nodes.len()).rev() {
+            for &source in &self.nodes[node_idx].indices {
+                if let Some(source_idx) = source.checked_sub(self.input_size_in_bits) {
+                    reach[source_idx] = reach[source_idx].join(reach[node_idx]);
+                }
+            }
+        }
+        let mut out = HeadNodes {
+            heads: vec![Vec::new(); heads],
+            shared: Vec::new(),
+        };
+        for node_idx in self.trainable_nodes() {
+            match reach[node_idx] {
+                Reach::Head(head) => out.heads[head].push(node_idx),
+                Reach::Shared => out.shared.push(node_idx),
+                Reach::Dead => {}
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn training_targets(&self, heads: usize) -> Result<Vec<Vec<usize>>, String> {
+        // Node sets the trainers take turns mutating: every trainable node at once without heads, otherwise each
+        // head and then the shared trunk, skipping empty ones
+        if heads == 0 {
+            return Ok(vec![self.trainable_nodes()]);
+        }
+        let HeadNodes { mut heads, shared } = self.head_nodes(heads)?;
+        heads.push(shared);
+        heads.retain(|nodes| !nodes.is_empty());
+        if heads.is_empty() {
+            return Err("No head has a trainable node".to_string());
+        }
+        Ok(heads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nodes_belong_to_the_only_head_they_reach() {
+        // 6 input bits. Node 0 feeds both heads, nodes 1 and 2 only head 0, node 3 only head 1, node 4 is dead.
+        // Outputs 5 and 6 are head 0, 7 and 8 head 1.
+        let node = |indices| Node { lut: 0, indices };
+        let ltnet = LUTNet::new(
+            vec![
+                node([0, 1, 2, 3, 4, 5]),
+                node([0, 0, 0, 0, 0, 0]),
+                node([1, 1, 1, 1, 1, 1]),
+                node([2, 2, 2, 2, 2, 2]),
+                node([3, 3, 3, 3, 3, 3]),
+                node([6, 7, 7, 7, 7, 7]),
+                node([8, 6, 8, 8, 8, 8]),
+                node([6, 9, 9, 9, 9, 9]),
+                node([9, 9, 9, 9, 9, 9]),
+            ],
+            6,
+            vec![0, 5, 9],
+            None,
+            vec![0, 3],
+        )
+        .with_frozen(vec![7]);
+        let split = ltnet.head_nodes(2).unwrap();
+        assert_eq!(split.heads, vec![vec![1, 2, 5, 6], vec![3, 8]]);
+        assert_eq!(split.shared, vec![0]);
+        assert!(ltnet.head_nodes(3).is_err());
+        assert_eq!(
+            ltnet.training_targets(2).unwrap(),
+            vec![vec![1, 2, 5, 6], vec![3, 8], vec![0]]
+        );
+        assert_eq!(ltnet.training_targets(0).unwrap().len(), 1);
+
+        // One head per output bit
+        let per_bit = ltnet.head_nodes(4).unwrap();
+        assert_eq!(per_bit.heads[3], vec![8]);
+        assert_eq!(per_bit.shared, vec![0, 3]);
+    }
+
+    #[test]
+    fn rewiring_across_heads_moves_the_source_to_the_trunk() {
+        // Output 4 is head 0 and reads node 0, output 5 is head 1 and reads nodes 1 and 2
+        let node = |indices| Node { lut: 0, indices };
+        let mut ltnet = LUTNet::new(
+            vec![
+                node([0, 1, 2, 3, 4, 5]),
+                node([0, 1, 2, 3, 4, 5]),
+                node([0, 1, 2, 3, 4, 5]),
+                node([0, 1, 2, 3, 4, 5]),
+                node([6, 6, 6, 6, 6, 6]),
+                node([7, 7, 7, 8, 8, 8]),
+            ],
+            6,
+            vec![0, 4, 6],
+            None,
+            vec![0, 3],
+        );
+        assert_eq!(
+            ltnet.training_targets(2).unwrap(),
+            vec![vec![0, 4], vec![1, 2, 5]]
+        );
+        // Node 5 of head 1 rewired to read node 0 of head 0, the way the trainers draw rewires
+        let mut rng = crate::utils::new_rng();
+        let rewire = loop {
+            let (_, rewires) = ltnet.draw_rewires(&[5], 1.0, &mut rng);
+            if rewires[0].source == 6 {
+                break rewires[0];
+            }
+        };
+        rewire.apply(&mut ltnet.nodes[5], true);
+        // Node 0 now changes both heads' outputs, so it is trained with the trunk
+        assert_eq!(
+            ltnet.training_targets(2).unwrap(),
+            vec![vec![4], vec![1, 2, 5], vec![0]]
+        );
+    }
+}
@@ -13,3 +13,16 @@ pub mod bitflip_evolutionary {
     pub mod netimpl;
     pub mod trainer;
 }
+
+pub mod heads;
+pub mod rewiring;
+
+// Hyperparameters of the evolutionary trainers (naive_evolutionary and bitflip_evolutionary)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrainParams {
+    pub corruption_ratio: f32,     // Share of the nodes mutated at a time
+    pub lut_sampling_depth: usize, // Mutations tried per batch, the best one is kept if it lowers the loss
+    pub rewire_probability: f32, // Chance a mutation moves one input of a node instead of changing its LUT
+    pub heads: usize, // Mutate one of this many output heads at a time, 0 for the whole network
+    pub epochs: usize,
+}
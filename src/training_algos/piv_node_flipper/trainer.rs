@@ -1,7 +1,7 @@
 #![allow(warnings)]
 use crate::{
-    architectures::cnn_iv0::netimpl::*, netcore::*, processing::*, settings::*,
-    training_algos::piv_node_flipper::utils::*, utils::*,
+    netcore::*, processing::*, sampler::*, settings::*, training_algos::piv_node_flipper::utils::*,
+    utils::*,
 };
 use bitvec::prelude::*;
 use bitvec::prelude::*;
@@ -19,28 +19,26 @@ use std::{error::Error, sync::OnceLock, time::Instant};
 pub fn train(
     ltnet: &mut LUTNet,
     cfg: &Configuration,
-    databits: &BitVec<u8, Msb0>,
-    labels: &[usize],
+    data: &mut dyn BatchSource,
     epochs: usize,
     write_freq: usize,
     model_filename: &str,
 ) {
+    ltnet.decoder = Some(cfg.decoder.clone()); // Saved with the model, which then decodes with this head
     let mut dbv = bitvec![u8, Msb0; 0; cfg.derived.bitvec_size];
     let mut loss = usize::MAX;
     let mut steps: usize = 0;
     for epoch in 0..epochs {
-        for batch_num in 0..cfg.derived.num_batches {
-            let y = &labels[batch_num * cfg.data.batch_size..(batch_num + 1) * cfg.data.batch_size];
-            dbv[..cfg.derived.batch_bitcount].copy_from_bitslice(
-                &databits[batch_num * cfg.derived.batch_bitcount
-                    ..(batch_num + 1) * cfg.derived.batch_bitcount],
-            );
+        data.start_epoch(epoch);
+        for batch_num in 0..data.num_batches() {
+            let y = &data.load_batch(batch_num, &mut dbv);
             let pivotal_nodes = ltnet.apply_gates_while_tracking_pivotal_nodes(cfg, &mut dbv);
             let mut last_layer_location_for_batch_in_bitvec = cfg.derived.bitvec_size
                 - cfg.data.batch_size * cfg.network.layer_sizes[cfg.derived.num_layers - 1];
             let loss_vec = get_loss_vec(cfg, &dbv, y);
-            let loss = &loss_vec.count_ones();
-            let node_to_flip = find_global_pivotal_node(cfg, &pivotal_nodes, &loss_vec).unwrap();
+            let loss = &get_loss(cfg, &dbv, y);
+            let node_to_flip =
+                find_global_pivotal_node(cfg, &pivotal_nodes, &loss_vec, &ltnet.frozen).unwrap();
             // let: usize = selected_bits_to_flip[1].unwrap();
             // ltnet.nodes[node_to_flip].lut = !ltnet.nodes[node_to_flip].lut;
             let mut lut_inputs_for_node_to_flip: Vec<u8> = Vec::with_capacity(cfg.data.batch_size);
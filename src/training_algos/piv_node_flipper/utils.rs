@@ -167,7 +167,9 @@ pub fn find_global_pivotal_node(
     cfg: &Configuration,
     pivotal_nodes: &[HashMap<usize, HashSet<usize>>],
     flags: &BitVec<u8, Msb0>,
+    frozen: &[usize],
 ) -> Option<usize> {
+    // Frozen nodes keep their fixed function, so they are never picked
     let mut global_node_scores: HashMap<usize, isize> = HashMap::new();
 
     for (input_map, key_flags) in pivotal_nodes.iter().zip(flags.chunks(8)) {
@@ -181,6 +183,9 @@ pub fn find_global_pivotal_node(
             let key_score = if is_good { 1 } else { -1 };
 
             for &number in number_set {
+                if frozen.binary_search(&number).is_ok() {
+                    continue;
+                }
                 *global_node_scores.entry(number).or_default() += key_score;
             }
         }
@@ -0,0 +1,115 @@
+use crate::netcore::*;
+use rand::prelude::*;
+use std::ops::Range;
+
+// Mutations of a node's connections instead of its LUT. A rewire moves one input of a node to another bit of the
+// layer before it, the only layer a node may read. A rewired node then has its inputs sorted ascending by
+// sort_inputs, which permutes the LUT along so the node computes the same function of the same bits. Only rewired
+// nodes are sorted, the others keep whatever order their layer wired them in. Networks with a lut bank skip the
+// sort, a permuted LUT is usually not in the bank.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rewire {
+    pub node: usize,
+    pub slot: usize,   // Which of the 6 inputs moves
+    pub source: usize, // The bit it reads from now on
+}
+
+impl Rewire {
+    pub fn apply(&self, node: &mut Node, keep_sorted: bool) {
+        node.indices[self.slot] = self.source;
+        if keep_sorted {
+            sort_inputs(node);
+        }
+    }
+}
+
+pub fn permute_lut(lut: u64, order: [usize; 6]) -> u64 {
+    // LUT for a node whose input i reads what its input order[i] read before, computing the same function.
+    // order must be a permutation of 0..6.
+    (0..64).fold(0, |permuted, new_address| {
+        let address = (0..6).fold(0, |a, i| a | (new_address >> i & 1) << order[i]);
+        permuted | (lut >> address & 1) << new_address
+    })
+}
+
+pub fn sort_inputs(node: &mut Node) {
+    // Ascending inputs with the same function
+    let mut order = [0, 1, 2, 3, 4, 5];
+    order.sort_by_key(|&i| node.indices[i]);
+    node.lut = permute_lut(node.lut, order);
+    node.indices = order.map(|i| node.indices[i]);
+}
+
+impl LUTNet {
+    pub fn source_range(&self, node_idx: usize) -> Range<usize> {
+        // Bits the node may read: the input for the first layer, the previous layer for the others
+        let layer = self.layer_edges[1..].partition_point(|&edge| edge <= node_idx);
+        if layer == 0 {
+            0..self.input_size_in_bits
+        } else {
+            self.input_size_in_bits + self.layer_edges[layer - 1]
+                ..self.input_size_in_bits + self.layer_edges[layer]
+        }
+    }
+
+    pub fn draw_rewires(
+        &self,
+        node_idxs: &[usize],
+        rewire_probability: f32,
+        rng: &mut impl Rng,
+    ) -> (Vec<usize>, Vec<Rewire>) {
+        // Splits the nodes picked for mutation into those that get a LUT mutation and those that get a rewire
+        let mut lut_nodes = Vec::with_capacity(node_idxs.len());
+        let mut rewires = Vec::new();
+        for &node in node_idxs {
+            if rewire_probability > 0.0 && rng.random::<f32>() < rewire_probability {
+                rewires.push(Rewire {
+                    node,
+                    slot: rng.random_range(0..6),
+                    source: rng.random_range(self.source_range(node)),
+                });
+            } else {
+                lut_nodes.push(node);
+            }
+        }
+        (lut_nodes, rewires)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::new_rng;
+
+    fn output(node: &Node, bits: &[bool]) -> bool {
+        let address = (0..6).fold(0, |a, i| a | (bits[node.indices[i]] as usize) << i);
+        node.lut >> address & 1 == 1
+    }
+
+    #[test]
+    fn reordered_inputs_keep_the_function() {
+        let mut rng = new_rng();
+        let node = Node {
+            lut: rng.next_u64(),
+            indices: [9, 3, 7, 0, 3, 5],
+        };
+        let mut sorted = node;
+        sort_inputs(&mut sorted);
+        assert_eq!(sorted.indices, [0, 3, 3, 5, 7, 9]);
+        for pattern in 0..1024 {
+            let bits: Vec<bool> = (0..10).map(|b| pattern >> b & 1 == 1).collect();
+            assert_eq!(output(&node, &bits), output(&sorted, &bits));
+        }
+        assert_eq!(permute_lut(0b10, [1, 0, 2, 3, 4, 5]), 0b100);
+
+        // Rewires stay inside the layer before the node
+        let ltnet = LUTNet::new(vec![sorted; 12], 10, vec![0, 8, 12], None, vec![0, 1]);
+        assert_eq!(ltnet.source_range(3), 0..10);
+        assert_eq!(ltnet.source_range(8), 10..18);
+        let (lut_nodes, rewires) = ltnet.draw_rewires(&[2, 9, 11], 1.0, &mut rng);
+        assert!(lut_nodes.is_empty());
+        assert!(rewires[1..].iter().all(|r| (10..18).contains(&r.source)));
+        assert_eq!(ltnet.draw_rewires(&[2, 9], 0.0, &mut rng).0, vec![2, 9]);
+    }
+}
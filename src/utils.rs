@@ -1,6 +1,34 @@
-use crate::{netcore::*, settings::*};
+use crate::{decoding::RankedPrediction, netcore::*, settings::*};
 use rand::prelude::*;
 use rand_xoshiro::Xoshiro256PlusPlus;
+use std::sync::{
+    OnceLock,
+    atomic::{AtomicU64, Ordering},
+};
+
+// Without a global seed every generator is seeded from OS entropy. With one (run_experiment sets it from the
+// manifest), generators are derived from it in the order they are created, so a whole run can be repeated.
+static GLOBAL_SEED: OnceLock<u64> = OnceLock::new();
+static RNG_STREAMS: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_global_seed(seed: u64) -> Result<(), String> {
+    // Must run before any generator is created
+    GLOBAL_SEED
+        .set(seed)
+        .map_err(|_| "The global seed was already set".to_string())
+}
+
+pub fn new_rng() -> Xoshiro256PlusPlus {
+    match GLOBAL_SEED.get() {
+        Some(&seed) => stream_rng(seed, RNG_STREAMS.fetch_add(1, Ordering::Relaxed)),
+        None => Xoshiro256PlusPlus::from_rng(&mut rand::rng()),
+    }
+}
+
+pub fn stream_rng(base: u64, stream: u64) -> Xoshiro256PlusPlus {
+    // Fixed stream of a base seed. Parallel loops use one per item, so thread scheduling doesn't change the draws.
+    Xoshiro256PlusPlus::seed_from_u64(base ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+}
 
 pub fn calculate_accuracy(y: &[usize], predicted_labels: &[usize]) -> f32 {
     assert_eq!(
@@ -19,6 +47,24 @@ pub fn calculate_accuracy(y: &[usize], predicted_labels: &[usize]) -> f32 {
     correct_predictions as f32 / y.len() as f32
 }
 
+pub fn calculate_top_k_accuracy(y: &[usize], predictions: &[RankedPrediction], k: usize) -> f32 {
+    // Share of samples whose true class is among the k nearest
+    assert_eq!(
+        y.len(),
+        predictions.len(),
+        "Input slices must have the same length."
+    );
+    if y.is_empty() {
+        return 0.0;
+    }
+    let hits = y
+        .iter()
+        .zip(predictions)
+        .filter(|&(&label, prediction)| prediction.rank_of(label).is_some_and(|rank| rank < k))
+        .count();
+    hits as f32 / y.len() as f32
+}
+
 pub fn get_ix(cfg: &Configuration, target: usize) -> Option<u8> {
     // Given target embedding, return the corresponding index (which is the label)
     cfg.network
@@ -57,8 +103,8 @@ pub fn get_node_indices_in_bitvec(
 
 pub fn create_pseudorandom_lut_generator(
     lut_bank_opt: &Option<Vec<u64>>,
+    mut rng_luts: Xoshiro256PlusPlus,
 ) -> Box<dyn Iterator<Item = u64> + Send> {
-    let mut rng_luts = Xoshiro256PlusPlus::from_rng(&mut rand::rng());
     if let Some(lut_bank) = lut_bank_opt {
         let lut_bank_size = lut_bank.len();
         let ltbank = lut_bank.clone();
@@ -88,24 +134,21 @@ pub fn create_pseudorandom_lut_generator(
     }
 }
 
-pub fn pseudo_4bit_generator() -> impl Iterator<Item = u8> {
-    let mut rng = Xoshiro256PlusPlus::from_rng(&mut rand::rng());
+pub fn pseudo_4bit_generator(mut rng: Xoshiro256PlusPlus) -> impl Iterator<Item = u8> {
     std::iter::repeat(()).flat_map(move |_| {
         let chunk = rng.next_u64();
         (0..16).map(move |i| (chunk >> (i * 4)) as u8 & 0xF)
     })
 }
 
-pub fn pseudo_6bit_generator() -> impl Iterator<Item = u8> {
-    let mut rng = Xoshiro256PlusPlus::from_rng(&mut rand::rng());
+pub fn pseudo_6bit_generator(mut rng: Xoshiro256PlusPlus) -> impl Iterator<Item = u8> {
     std::iter::repeat(()).flat_map(move |_| {
         let chunk = rng.next_u64();
         (0..10).map(move |i| (chunk >> (i * 6)) as u8 & 0x3F)
     })
 }
 
-pub fn pseudo_8bit_generator() -> impl Iterator<Item = u8> {
-    let mut rng = Xoshiro256PlusPlus::from_rng(&mut rand::rng());
+pub fn pseudo_8bit_generator(mut rng: Xoshiro256PlusPlus) -> impl Iterator<Item = u8> {
     std::iter::repeat(()).flat_map(move |_| {
         let chunk = rng.next_u64();
         (0..8).map(move |i| (chunk >> (i * 8)) as u8)
@@ -158,4 +201,20 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn top_k_accuracy_counts_true_class_among_nearest() {
+        use bitvec::prelude::*;
+        let targets = [0b00, 0b01, 0b11];
+        let outputs = bitvec![u8, Msb0; 0, 1, /**/ 1, 1, /**/ 1, 0];
+        let predictions: Vec<RankedPrediction> = outputs
+            .chunks_exact(2)
+            .map(|sample| Decoder::Nearest.rank(&targets, sample))
+            .collect();
+        // Rankings: [1, 0, 2], [2, 1, 0] and [0, 2, 1]
+        let y = [0, 0, 1];
+        assert_eq!(calculate_top_k_accuracy(&y, &predictions, 1), 0.0);
+        assert_eq!(calculate_top_k_accuracy(&y, &predictions, 2), 1.0 / 3.0);
+        assert_eq!(calculate_top_k_accuracy(&y, &predictions, 3), 1.0);
+    }
 }
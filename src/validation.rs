@@ -0,0 +1,283 @@
+use crate::settings::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+// Checks a configuration, and the architecture built on it, before any data is loaded or nodes are created.
+// Every problem found is collected, so one run shows all of them instead of the first panic in the forward pass.
+// Architectures given layer_sizes by hand also get the layer_sizes their layers actually produce suggested.
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub errors: Vec<String>, // The network can't be built or would give wrong results
+    pub warnings: Vec<String>, // Legal, but probably not what was meant
+    pub suggested_layer_sizes: Option<Vec<usize>>, // Set when layer_sizes don't match the span specs
+}
+
+impl ValidationReport {
+    pub fn error(&mut self, msg: impl Into<String>) {
+        self.errors.push(msg.into());
+    }
+
+    pub fn warn(&mut self, msg: impl Into<String>) {
+        self.warnings.push(msg.into());
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn ensure_valid(&self) -> Result<(), Box<dyn Error>> {
+        // Warnings are printed and the run goes on, errors stop it with the whole report
+        if self.is_ok() {
+            for warning in &self.warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            Ok(())
+        } else {
+            Err(self.to_string().into())
+        }
+    }
+
+    pub fn check_layer_sizes(&mut self, cfg: &Configuration, produced: Option<Vec<usize>>) {
+        // produced is None when a span spec was invalid, that error is already in the report
+        if let Some(produced) = produced
+            && produced != cfg.network.layer_sizes
+        {
+            self.error(format!(
+                "layer_sizes {:?} don't match the {} layers the span specs produce",
+                cfg.network.layer_sizes,
+                produced.len()
+            ));
+            self.suggested_layer_sizes = Some(produced);
+        }
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.errors.is_empty() && self.warnings.is_empty() {
+            return write!(f, "Configuration is valid");
+        }
+        writeln!(
+            f,
+            "Configuration check found {} error(s) and {} warning(s):",
+            self.errors.len(),
+            self.warnings.len()
+        )?;
+        for error in &self.errors {
+            writeln!(f, "  error: {}", error)?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "  warning: {}", warning)?;
+        }
+        if let Some(sizes) = &self.suggested_layer_sizes {
+            writeln!(f, "Suggested layer_sizes = {:?}", sizes)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn span_output_dims(
+    dims: (usize, usize, usize),
+    lens: (usize, usize, usize),
+    hops: (usize, usize, usize),
+) -> Result<(usize, usize, usize), String> {
+    // Dims of the layer a SpanGenerator produces, with the checks it would otherwise fail on with a panic or underflow
+    if lens.0 * lens.1 * lens.2 != 6 {
+        return Err(format!("span {:?} doesn't cover 6 inputs", lens));
+    }
+    window_output_dims(dims, lens, hops)
+}
+
+pub fn window_output_dims(
+    dims: (usize, usize, usize),
+    lens: (usize, usize, usize),
+    hops: (usize, usize, usize),
+) -> Result<(usize, usize, usize), String> {
+    // Number of window positions along each dim, for windows of any size placed the way SpanGenerator places spans
+    if lens.0 == 0 || lens.1 == 0 || lens.2 == 0 {
+        return Err(format!("window {:?} must not be empty", lens));
+    }
+    if hops.0 == 0 || hops.1 == 0 || hops.2 == 0 {
+        return Err(format!("hops {:?} must all be non-zero", hops));
+    }
+    if lens.0 > dims.0 || lens.1 > dims.1 || lens.2 > dims.2 {
+        return Err(format!("span {:?} doesn't fit in dims {:?}", lens, dims));
+    }
+    let out = |dim: usize, len: usize, hop: usize| (dim - len + 2 * hop - 1) / hop;
+    Ok((
+        out(dims.0, lens.0, hops.0),
+        out(dims.1, lens.1, hops.1),
+        out(dims.2, lens.2, hops.2),
+    ))
+}
+
+impl Configuration {
+    pub fn validate(&self) -> ValidationReport {
+        // Data and network settings only. Architectures add their own checks in LUTNetBuilder::validate.
+        let mut report = ValidationReport::default();
+        let (data, network) = (&self.data, &self.network);
+
+        if let Err(e) = data.encoding.validate() {
+            report.error(e);
+        }
+        if let Err(e) = data.datasplit.validate(data) {
+            report.error(e);
+        }
+        if let Some(Err(e)) = data.val_split.as_ref().map(|split| split.validate(data)) {
+            report.error(format!("val_split: {}", e));
+        }
+        if self.derived.img_bitcount == 0 {
+            report.error("Inputs have no bits, check dim2, dim3 and the encoding");
+        }
+
+        let rows = self.derived.rows;
+        if data.batch_size == 0 {
+            report.error("batch_size must be non-zero");
+        } else if rows > 0 && self.derived.num_batches == 0 {
+            report.error(format!(
+                "batch_size {} is larger than the {} rows of the split, so there are no batches",
+                data.batch_size, rows
+            ));
+        } else if !rows.is_multiple_of(data.batch_size)
+            && data.sampling.remainder == Remainder::Drop
+        {
+            report.warn(format!(
+                "batch_size {} doesn't divide the {} rows of the split, the last {} rows are never used. Set sampling.remainder = \"Pad\" to keep them.",
+                data.batch_size,
+                rows,
+                rows % data.batch_size
+            ));
+        }
+        if let Some(streaming) = &data.streaming {
+            if data.synthetic.is_some() || data.tabular.is_some() {
+                report.error("streaming only reads image CSV files");
+            }
+            if data.sampling.stratified {
+                report.error("streaming can't be combined with stratified sampling");
+            }
+            if streaming.prefetch_batches == 0 || streaming.shuffle_window == 0 {
+                report.warn("streaming prefetch_batches and shuffle_window are raised to 1");
+            }
+        }
+
+        if network.layer_sizes.is_empty() {
+            report.error("layer_sizes is empty");
+            return report;
+        }
+        if let Some(layer) = network.layer_sizes.iter().position(|&size| size == 0) {
+            report.error(format!("layer {} of layer_sizes has no nodes", layer));
+        }
+
+        let output_bitsize = self.derived.output_bitsize;
+        if output_bitsize > usize::BITS as usize {
+            report.error(format!(
+                "The output layer has {} bits, codewords are read into {} bits",
+                output_bitsize,
+                usize::BITS
+            ));
+        }
+        if let Some(Err(e)) = network
+            .output_code
+            .as_ref()
+            .map(|code| code.validate(output_bitsize))
+        {
+            report.error(e.to_string());
+        }
+        if network.output_embedding.len() < 2 {
+            report.error("output_embedding needs a codeword for at least 2 classes");
+        }
+        for (class, &codeword) in network.output_embedding.iter().enumerate() {
+            if output_bitsize < usize::BITS as usize && codeword >> output_bitsize != 0 {
+                report.error(format!(
+                    "Codeword {:#b} of class {} is wider than the {} output bits",
+                    codeword, class, output_bitsize
+                ));
+            }
+        }
+        let mut seen = HashMap::new();
+        for (class, &codeword) in network.output_embedding.iter().enumerate() {
+            if let Some(first) = seen.insert(codeword, class) {
+                report.error(format!(
+                    "Classes {} and {} share the codeword {:#b}",
+                    first, class, codeword
+                ));
+            }
+        }
+        if let Err(e) = self.decoder.validate(self) {
+            report.error(e);
+        }
+        if let Err(e) = self.loss.validate(self) {
+            report.error(e);
+        }
+
+        if let Some(Err(e)) = data
+            .synthetic
+            .as_ref()
+            .map(|synthetic| synthetic.task.validate(&network.output_embedding))
+        {
+            report.error(e);
+        }
+        if let Some(encoder) = data.tabular.as_ref().and_then(|t| t.encoder.as_ref())
+            && encoder.classes.len() > network.output_embedding.len()
+        {
+            report.error(format!(
+                "The label column has {} classes but output_embedding only has {} codewords",
+                encoder.classes.len(),
+                network.output_embedding.len()
+            ));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::architectures::{
+        LUTNetBuilder,
+        spec::settings::{SpecSettings, get_aligned_cfg},
+    };
+
+    #[test]
+    fn configuration_problems_are_all_reported() {
+        let mut cfg = initialize_app_config_with_network(None);
+        cfg.data.datasplit = DataSplit::Train(5000);
+        cfg.data.batch_size = 300;
+        cfg.data.sampling.remainder = Remainder::Drop;
+        cfg.network.layer_sizes = vec![100, 4];
+        cfg.network.output_embedding = vec![1, 2, 3, 2, 16];
+        cfg.derived = DerivedValues::new(&cfg.data, &cfg.network);
+
+        let report = cfg.validate();
+        assert_eq!(report.errors.len(), 2, "{}", report);
+        assert!(report.errors[0].contains("class 4"));
+        assert!(report.errors[1].contains("Classes 1 and 3"));
+        // 5000 rows in batches of 300 leave 200 rows out
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("last 200 rows"));
+        assert!(report.ensure_valid().is_err());
+    }
+
+    #[test]
+    fn span_specs_suggest_layer_sizes() {
+        let arch_settings =
+            SpecSettings::new("cnn_iv0", include_str!("../architectures/cnn_iv0.toml")).unwrap();
+        let mut cfg = get_aligned_cfg(&arch_settings).unwrap();
+        let expected = cfg.network.layer_sizes.clone();
+        assert!(arch_settings.validate(&cfg).is_ok());
+
+        cfg.network.layer_sizes[2] += 1;
+        cfg.derived = DerivedValues::new(&cfg.data, &cfg.network);
+        let report = arch_settings.validate(&cfg);
+        assert_eq!(report.errors.len(), 1, "{}", report);
+        assert_eq!(report.suggested_layer_sizes, Some(expected));
+
+        assert_eq!(
+            span_output_dims((3, 28, 28), (3, 2, 1), (1, 1, 1)),
+            Ok((1, 27, 28))
+        );
+        assert!(span_output_dims((2, 28, 28), (3, 2, 1), (1, 1, 1)).is_err());
+    }
+}